@@ -0,0 +1,569 @@
+//! # lua-ffi-derive
+//!
+//! Derive macros for `lua-ffi`. These are re-exported by `lua-ffi` when its
+//! `derive` feature is enabled, and should be used from there.
+//!
+//! `#[derive(LuaObject)]` implements `LuaObject` for a struct, and a
+//! `#[lua_methods]` impl block generates the Lua functions for it. Methods
+//! marked `#[lua(method)]` are called on the userdata in argument 1, methods
+//! marked `#[lua(function)]` are registered as static functions on the type's
+//! class table. Arguments are read with `FromLua` and the return value is
+//! pushed with `LuaReturn`. Functions marked `#[lua(constant)]` are called
+//! once to fill in constants of the class table, and a function marked
+//! `#[lua(metamethods)]` returns the type's `LuaMetaMethod`s.
+//!
+//! ```ignore
+//! #[derive(LuaObject)]
+//! struct Point2D {
+//!     #[lua(get, set)]
+//!     x: i32,
+//!     #[lua(get)]
+//!     y: i32,
+//! }
+//!
+//! #[lua_methods]
+//! impl Point2D {
+//!     #[lua(function)]
+//!     fn new(x: i32, y: i32) -> Point2D {
+//!         Point2D { x, y }
+//!     }
+//!
+//!     #[lua(method)]
+//!     fn add(&self, dx: i32) -> i32 {
+//!         self.x + self.y + dx
+//!     }
+//!
+//!     #[lua(method, name = "setX")]
+//!     fn set_x(&mut self, x: i32) {
+//!         self.x = x;
+//!     }
+//! }
+//! ```
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use syn::spanned::Spanned;
+use syn::{Attribute, Data, DeriveInput, Fields, FnArg, Ident, ImplItem, ImplItemFn, Index, ItemImpl, LitBool, LitInt, LitStr, Member, Pat, Type};
+
+/// Implements `LuaObject` for a struct or enum, using the methods generated
+/// by the type's `#[lua_methods]` impl block.
+///
+/// The metatable name defaults to the name of the type and can be changed
+/// with `#[lua(name = "...")]`. Struct fields marked `#[lua(get)]` and/or
+/// `#[lua(set)]` can be read and assigned from Lua as `obj.field`, and can
+/// be renamed the same way. `#[lua(user_values = N)]` gives every instance
+/// `N` user values, and `#[lua(global = false)]` stops the class table from
+/// being published as a global. A field marked `#[lua(parent)]` becomes the parent of
+/// the type, see `LuaObject::lua_parent`.
+#[proc_macro_derive(LuaObject, attributes(lua))]
+pub fn derive_lua_object(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+
+    expand_derive(input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+/// Generates Lua trampolines for the methods of an impl block marked with
+/// `#[lua(method)]` or `#[lua(function)]`, and lists them along with the
+/// `#[lua(constant)]`s in an implementation of `LuaMethods`.
+#[proc_macro_attribute]
+pub fn lua_methods(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        let err = syn::Error::new(Span::call_site(), "#[lua_methods] does not take arguments");
+        return err.to_compile_error().into();
+    }
+
+    let input = syn::parse_macro_input!(input as ItemImpl);
+
+    expand_methods(input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+fn expand_derive(input: DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(input.generics.span(), "LuaObject cannot be derived for generic types"));
+    }
+
+    let ident = &input.ident;
+    let mut name = ident.to_string();
+    let mut user_values = 0;
+    let mut global = true;
+
+    for attr in lua_attrs(&input.attrs) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else if meta.path.is_ident("user_values") {
+                user_values = meta.value()?.parse::<LitInt>()?.base10_parse::<i32>()?;
+                Ok(())
+            } else if meta.path.is_ident("global") {
+                global = meta.value()?.parse::<LitBool>()?.value;
+                Ok(())
+            } else {
+                Err(meta.error("unknown lua attribute, expected `name`, `user_values` or `global`"))
+            }
+        })?;
+    }
+
+    let name = c_str(&name);
+    let (fields, parent) = match input.data {
+        Data::Struct(ref data) => (expand_fields(ident, &data.fields)?, expand_parent(ident, &data.fields)?),
+        _ => (Vec::new(), None),
+    };
+    let parent = parent.map(|parent| quote! {
+        fn lua_parent() -> ::std::option::Option<::lua_ffi::LuaParent<#ident>> {
+            ::std::option::Option::Some(#parent)
+        }
+    });
+
+    Ok(quote! {
+        impl ::lua_ffi::LuaObject for #ident {
+            fn name() -> *const i8 {
+                #name
+            }
+
+            fn lua_fns() -> ::std::vec::Vec<::lua_ffi::ffi::luaL_Reg> {
+                <#ident as ::lua_ffi::LuaMethods>::lua_methods()
+            }
+
+            fn lua_statics() -> ::std::vec::Vec<::lua_ffi::ffi::luaL_Reg> {
+                <#ident as ::lua_ffi::LuaMethods>::lua_statics()
+            }
+
+            fn lua_constants() -> ::std::vec::Vec<::lua_ffi::LuaConstant> {
+                <#ident as ::lua_ffi::LuaMethods>::lua_constants()
+            }
+
+            fn lua_global() -> bool {
+                #global
+            }
+
+            fn lua_fields() -> ::std::vec::Vec<::lua_ffi::LuaField<#ident>> {
+                vec![#(#fields),*]
+            }
+
+            fn lua_metamethods() -> ::std::vec::Vec<::lua_ffi::LuaMetaMethod<#ident>> {
+                <#ident as ::lua_ffi::LuaMethods>::lua_metamethods()
+            }
+
+            fn user_values() -> ::lua_ffi::c_int {
+                #user_values
+            }
+
+            #parent
+        }
+    })
+}
+
+/// Builds a `LuaField` for every struct field marked `#[lua(get)]` or
+/// `#[lua(set)]`. Getters clone the field value.
+fn expand_fields(ident: &Ident, fields: &Fields) -> syn::Result<Vec<TokenStream2>> {
+    let mut exprs = Vec::new();
+
+    for field in fields {
+        let mut get = false;
+        let mut set = false;
+        let mut name = field.ident.as_ref().map(|ident| ident.to_string());
+
+        for attr in lua_attrs(&field.attrs) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("get") {
+                    get = true;
+                    Ok(())
+                } else if meta.path.is_ident("set") {
+                    set = true;
+                    Ok(())
+                } else if meta.path.is_ident("name") {
+                    name = Some(meta.value()?.parse::<LitStr>()?.value());
+                    Ok(())
+                } else if meta.path.is_ident("parent") {
+                    // Handled by `expand_parent`
+                    Ok(())
+                } else {
+                    Err(meta.error("unknown lua attribute, expected `get`, `set`, `name` or `parent`"))
+                }
+            })?;
+        }
+
+        if !get && !set {
+            continue;
+        }
+
+        let member = match field.ident {
+            Some(ref member) => member,
+            None => return Err(syn::Error::new(field.span(), "only named fields can be exposed to Lua")),
+        };
+        let name = name.unwrap();
+        let ty = &field.ty;
+        let mut expr = quote!(::lua_ffi::LuaField::new(#name));
+
+        if get {
+            expr = quote!(#expr.getter(|obj: &#ident| ::std::clone::Clone::clone(&obj.#member)));
+        }
+
+        if set {
+            expr = quote!(#expr.setter(|obj: &mut #ident, val: #ty| obj.#member = val));
+        }
+
+        exprs.push(expr);
+    }
+
+    Ok(exprs)
+}
+
+/// Builds the `LuaParent` for the struct field marked `#[lua(parent)]`, if
+/// there is one.
+fn expand_parent(ident: &Ident, fields: &Fields) -> syn::Result<Option<TokenStream2>> {
+    let mut parent = None;
+
+    for (n, field) in fields.iter().enumerate() {
+        let mut is_parent = false;
+
+        for attr in lua_attrs(&field.attrs) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("parent") {
+                    is_parent = true;
+                } else if meta.input.peek(syn::Token![=]) {
+                    meta.value()?.parse::<syn::Expr>()?;
+                }
+                Ok(())
+            })?;
+        }
+
+        if !is_parent {
+            continue;
+        }
+
+        if parent.is_some() {
+            return Err(syn::Error::new(field.span(), "only one field can be marked #[lua(parent)]"));
+        }
+
+        let member = match field.ident {
+            Some(ref member) => Member::Named(member.clone()),
+            None => Member::Unnamed(Index::from(n)),
+        };
+
+        parent = Some(quote! {
+            ::lua_ffi::LuaParent::new(|obj: &#ident| &obj.#member, |obj: &mut #ident| &mut obj.#member)
+        });
+    }
+
+    Ok(parent)
+}
+
+/// How a method in a `#[lua_methods]` block is exposed to Lua.
+enum Kind {
+    Method,
+    Function,
+    /// Called once on registration, the result is stored on the class table.
+    Constant,
+    /// Returns the type's `LuaMetaMethod`s, instead of being exposed itself.
+    MetaMethods,
+}
+
+struct Export {
+    kind: Kind,
+    name: String,
+}
+
+fn expand_methods(mut input: ItemImpl) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(input.generics.span(), "#[lua_methods] cannot be used on generic impl blocks"));
+    }
+
+    if let Some((_, ref path, _)) = input.trait_ {
+        return Err(syn::Error::new(path.span(), "#[lua_methods] cannot be used on trait impls"));
+    }
+
+    let self_ty = input.self_ty.clone();
+    let mut regs = Vec::new();
+    let mut statics = Vec::new();
+    let mut constants = Vec::new();
+    let mut metamethods = None;
+
+    for item in &mut input.items {
+        if let ImplItem::Fn(ref mut func) = *item {
+            match parse_export(func)? {
+                Some(Export { kind: Kind::MetaMethods, .. }) => {
+                    if metamethods.is_some() {
+                        return Err(syn::Error::new(func.sig.ident.span(), "only one function can be marked #[lua(metamethods)]"));
+                    }
+
+                    let ident = &func.sig.ident;
+                    metamethods = Some(quote! {
+                        fn lua_metamethods() -> ::std::vec::Vec<::lua_ffi::LuaMetaMethod<#self_ty>> {
+                            <#self_ty>::#ident()
+                        }
+                    });
+                }
+                Some(Export { kind: Kind::Constant, ref name }) => {
+                    if !func.sig.inputs.is_empty() {
+                        return Err(syn::Error::new(func.sig.inputs.span(), "#[lua(constant)] cannot take arguments"));
+                    }
+
+                    let ident = &func.sig.ident;
+                    constants.push(quote!(::lua_ffi::LuaConstant::new(#name, <#self_ty>::#ident())));
+                }
+                Some(export @ Export { kind: Kind::Function, .. }) => statics.push(expand_reg(&self_ty, func, export)?),
+                Some(export) => regs.push(expand_reg(&self_ty, func, export)?),
+                None => {}
+            }
+        }
+    }
+
+    Ok(quote! {
+        #input
+
+        impl ::lua_ffi::LuaMethods for #self_ty {
+            fn lua_methods() -> ::std::vec::Vec<::lua_ffi::ffi::luaL_Reg> {
+                vec![#(#regs),*]
+            }
+
+            fn lua_statics() -> ::std::vec::Vec<::lua_ffi::ffi::luaL_Reg> {
+                vec![#(#statics),*]
+            }
+
+            fn lua_constants() -> ::std::vec::Vec<::lua_ffi::LuaConstant> {
+                vec![#(#constants),*]
+            }
+
+            #metamethods
+        }
+    })
+}
+
+/// Reads and strips the `#[lua(...)]` attributes of a method.
+fn parse_export(func: &mut ImplItemFn) -> syn::Result<Option<Export>> {
+    let mut kind = None;
+    let mut name = func.sig.ident.to_string();
+
+    for attr in lua_attrs(&func.attrs) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("method") {
+                kind = Some(Kind::Method);
+                Ok(())
+            } else if meta.path.is_ident("function") {
+                kind = Some(Kind::Function);
+                Ok(())
+            } else if meta.path.is_ident("constant") {
+                kind = Some(Kind::Constant);
+                Ok(())
+            } else if meta.path.is_ident("metamethods") {
+                kind = Some(Kind::MetaMethods);
+                Ok(())
+            } else if meta.path.is_ident("name") {
+                name = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("unknown lua attribute, expected `method`, `function`, `constant`, `metamethods` or `name`"))
+            }
+        })?;
+    }
+
+    let had_attrs = func.attrs.iter().any(is_lua_attr);
+    func.attrs.retain(|attr| !is_lua_attr(attr));
+
+    match kind {
+        Some(kind) => Ok(Some(Export { kind, name })),
+        None if had_attrs => Err(syn::Error::new(func.sig.ident.span(), "expected #[lua(method)], #[lua(function)] or #[lua(constant)]")),
+        None => Ok(None),
+    }
+}
+
+fn expand_reg(self_ty: &Type, func: &ImplItemFn, export: Export) -> syn::Result<TokenStream2> {
+    let ident = &func.sig.ident;
+    let export_name = &export.name;
+    let mut inputs = func.sig.inputs.iter();
+
+    // The stack index of the first argument after `self`.
+    let (receiver, first_arg, this_mut) = match export.kind {
+        Kind::Method => {
+            let mutable = match inputs.next() {
+                Some(FnArg::Receiver(recv)) if recv.reference.is_some() => recv.mutability.is_some(),
+                Some(arg) => return Err(syn::Error::new(arg.span(), "#[lua(method)] must take `&self` or `&mut self`")),
+                None => return Err(syn::Error::new(func.sig.span(), "#[lua(method)] must take `&self` or `&mut self`")),
+            };
+
+            let this = Ident::new("this", Span::call_site());
+            (Some(borrow_arg(&this, self_ty, mutable, 1, export_name)), 2, mutable)
+        }
+        Kind::Function | Kind::Constant | Kind::MetaMethods => (None, 1, false),
+    };
+
+    let mut reads = Vec::new();
+    let mut args = Vec::new();
+    let mut idx: i32 = first_arg;
+
+    if receiver.is_some() {
+        args.push(deref_arg(&Ident::new("this", Span::call_site()), this_mut));
+    }
+
+    for (n, input) in inputs.enumerate() {
+        let ty = match *input {
+            FnArg::Typed(ref pat) => &*pat.ty,
+            FnArg::Receiver(ref recv) => {
+                return Err(syn::Error::new(recv.span(), "#[lua(function)] cannot take `self`, use #[lua(method)]"));
+            }
+        };
+
+        if let FnArg::Typed(ref pat) = *input {
+            if let Pat::Ident(ref pat) = *pat.pat {
+                if pat.by_ref.is_some() {
+                    return Err(syn::Error::new(pat.span(), "arguments cannot be bound by reference"));
+                }
+            }
+        }
+
+        let arg = Ident::new(&format!("arg{}", n), Span::call_site());
+
+        if is_state_ref(ty) {
+            // `&mut State` is passed through untouched, without using up a
+            // stack slot.
+            args.push(quote!(&mut state));
+            continue;
+        }
+
+        if let Type::Reference(ref r) = *ty {
+            // Other userdata are borrowed through their cell, so passing
+            // the receiver again fails instead of aliasing it.
+            let elem = if is_self(&r.elem) { self_ty } else { &*r.elem };
+            reads.push(borrow_arg(&arg, elem, r.mutability.is_some(), idx, export_name));
+            args.push(deref_arg(&arg, r.mutability.is_some()));
+            idx += 1;
+            continue;
+        }
+
+        let bad = bad_argument(idx, export_name);
+        reads.push(quote! {
+            let #arg = match <#ty as ::lua_ffi::FromLua>::from_lua(&mut state, #idx) {
+                Ok(val) => val,
+                Err(err) => #bad,
+            };
+        });
+        args.push(quote!(#arg));
+        idx += 1;
+    }
+
+    let name = c_str(export_name);
+
+    Ok(quote! {
+        {
+            #[allow(unused)]
+            unsafe extern "C" fn trampoline(l: *mut ::lua_ffi::ffi::lua_State) -> ::lua_ffi::c_int {
+                unsafe fn call(l: *mut ::lua_ffi::ffi::lua_State) -> ::std::result::Result<::lua_ffi::c_int, ::lua_ffi::LuaError> {
+                    let mut state = ::lua_ffi::State::from_ptr(l);
+                    #receiver
+                    #(#reads)*
+                    let ret = <#self_ty>::#ident(#(#args),*);
+
+                    ::lua_ffi::LuaResult::push_result(ret, &mut state)
+                }
+
+                match call(l) {
+                    Ok(n) => n,
+                    Err(err) => ::lua_ffi::error::raise_error(l, err),
+                }
+            }
+
+            ::lua_ffi::ffi::luaL_Reg {
+                name: #name,
+                func: Some(trampoline),
+            }
+        }
+    })
+}
+
+/// Reads the userdata at `idx` and borrows it into `var`. The handle is
+/// kept alongside so the borrow guard can outlive the read.
+fn borrow_arg(var: &Ident, ty: &Type, mutable: bool, idx: i32, export_name: &str) -> TokenStream2 {
+    let handle = Ident::new(&format!("{}_handle", var), Span::call_site());
+    let borrow = if mutable { quote!(borrow_mut) } else { quote!(borrow) };
+    let bad = bad_argument(idx, export_name);
+
+    quote! {
+        let #handle = match <::lua_ffi::LuaUserData<#ty> as ::lua_ffi::FromLua>::from_lua(&mut state, #idx) {
+            Ok(val) => val,
+            Err(err) => #bad,
+        };
+        #[allow(unused_mut)]
+        let mut #var = match #handle.#borrow() {
+            Ok(val) => val,
+            Err(err) => #bad,
+        };
+    }
+}
+
+/// Returns early from a trampoline with the error `err`, reported as a bad
+/// argument `idx` to `export_name`.
+fn bad_argument(idx: i32, export_name: &str) -> TokenStream2 {
+    quote! {
+        return Err(::lua_ffi::LuaError::BadArgument {
+            index: #idx,
+            function: #export_name.to_owned(),
+            message: err.to_string(),
+        })
+    }
+}
+
+fn deref_arg(var: &Ident, mutable: bool) -> TokenStream2 {
+    if mutable {
+        quote!(&mut *#var)
+    } else {
+        quote!(&*#var)
+    }
+}
+
+/// Tests if `ty` is `Self`, which cannot be named inside the generated
+/// trampolines.
+fn is_self(ty: &Type) -> bool {
+    match *ty {
+        Type::Path(ref p) => p.qself.is_none() && p.path.is_ident("Self"),
+        _ => false,
+    }
+}
+
+fn is_lua_attr(attr: &Attribute) -> bool {
+    attr.path().is_ident("lua")
+}
+
+fn lua_attrs(attrs: &[Attribute]) -> impl Iterator<Item = &Attribute> {
+    attrs.iter().filter(|attr| is_lua_attr(attr))
+}
+
+/// Tests if `ty` is a `&mut State`, which handlers can take to get
+/// direct access to the stack. The path must be `State` itself or lead to
+/// it through `lua_ffi`, so userdata types of the same name elsewhere are
+/// still read from the stack.
+fn is_state_ref(ty: &Type) -> bool {
+    if let Type::Reference(ref r) = *ty {
+        if r.mutability.is_some() {
+            if let Type::Path(ref p) = *r.elem {
+                if p.qself.is_some() || p.path.segments.iter().any(|seg| !seg.arguments.is_none()) {
+                    return false;
+                }
+
+                let names: Vec<String> = p.path.segments.iter().map(|seg| seg.ident.to_string()).collect();
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                return match names[..] {
+                    ["State"] => p.path.leading_colon.is_none(),
+                    ["lua_ffi", "State"] | ["lua_ffi", "state", "State"] => true,
+                    _ => false,
+                };
+            }
+        }
+    }
+
+    false
+}
+
+/// Expands to a pointer to a NUL-terminated copy of `s`, like `c_str!`.
+fn c_str(s: &str) -> TokenStream2 {
+    let lit = LitStr::new(&format!("{}\0", s), Span::call_site());
+
+    quote!(#lit.as_ptr() as *const i8)
+}
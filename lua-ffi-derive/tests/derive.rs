@@ -0,0 +1,376 @@
+extern crate lua_ffi;
+
+use lua_ffi::{lua_methods, LuaError, LuaMetaMethod, LuaObject, State, ThreadStatus};
+
+#[derive(LuaObject)]
+struct Point2D {
+    #[lua(get, set)]
+    x: i32,
+    #[lua(get, name = "yPos")]
+    y: i32,
+}
+
+#[lua_methods]
+impl Point2D {
+    #[lua(function)]
+    fn new(x: i32, y: i32) -> Point2D {
+        Point2D { x, y }
+    }
+
+    #[lua(constant, name = "ZERO")]
+    fn zero() -> Point2D {
+        Point2D { x: 0, y: 0 }
+    }
+
+    #[lua(method)]
+    fn add(&self, dx: i32) -> i32 {
+        self.x + self.y + dx
+    }
+
+    #[lua(method, name = "setX")]
+    fn set_x(&mut self, x: i32) {
+        self.x = x;
+    }
+
+    #[lua(method)]
+    fn describe(&self, prefix: String, state: &mut State) -> i64 {
+        state.push(format!("{}({}, {})", prefix, self.x, self.y));
+        state.set_global("description");
+
+        prefix.len() as i64
+    }
+
+    #[lua(method)]
+    fn merge(&mut self, other: &Point2D) {
+        self.x += other.x;
+        self.y += other.y;
+    }
+
+    #[allow(unused)]
+    fn not_exported(&self) -> i32 {
+        self.x
+    }
+
+    #[lua(metamethods)]
+    fn metamethods() -> Vec<LuaMetaMethod<Point2D>> {
+        vec![LuaMetaMethod::tostring(|p: &Point2D| format!("({}, {})", p.x, p.y))]
+    }
+}
+
+#[derive(LuaObject)]
+#[lua(name = "Counter")]
+struct RenamedCounter {
+    count: i64,
+}
+
+#[lua_methods]
+impl RenamedCounter {
+    #[lua(method)]
+    fn bump(&mut self) -> i64 {
+        self.count += 1;
+        self.count
+    }
+
+    #[lua(method)]
+    fn take(&mut self, n: i64) -> Result<i64, LuaError> {
+        if n > self.count {
+            return Err(LuaError::Conversion { path: String::new(), message: format!("only {} left", self.count) });
+        }
+
+        self.count -= n;
+        Ok(self.count)
+    }
+
+    #[lua(method)]
+    fn capacity(&self) -> u64 {
+        u64::MAX
+    }
+}
+
+#[test]
+fn call_methods() {
+    let mut state = State::new();
+    state.open_libs();
+    state.register_struct::<Point2D>();
+
+    let res = state.do_string(
+        r#"local p = Point2D.new(1, 2)
+        if p:add(3) ~= 6 then error() end
+        p:setX(10)
+        if p:add(0) ~= 12 then error() end
+        if tostring(p) ~= "(10, 2)" then error() end
+        if pcall(function() return p.not_exported end) then error() end"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+fn pass_state_through() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(Point2D { x: 1, y: 2 });
+    state.set_global("p");
+
+    let res = state.do_string(r#"if p:describe("P") ~= 1 then error() end"#);
+    assert_eq!(res, ThreadStatus::Ok);
+
+    state.get_global("description");
+    assert_eq!(state.to_str(-1), Some("P(1, 2)"));
+}
+
+#[test]
+fn bad_argument_message() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(Point2D { x: 1, y: 2 });
+    state.set_global("p");
+
+    state.do_string(r#"ok, err = pcall(p.add, p, "three")"#);
+    state.get_global("err");
+    assert_eq!(state.to_str(-1), Some("bad argument #2 to 'add' (number expected, got string)"));
+
+    state.do_string(r#"ok, err = pcall(Point2D.new, 1)"#);
+    state.get_global("err");
+    assert_eq!(state.to_str(-1), Some("bad argument #2 to 'new' (number expected, got no value)"));
+}
+
+#[test]
+fn renamed_type() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(RenamedCounter { count: 0 });
+    state.set_global("c");
+
+    let res = state.do_string("c:bump() if c:bump() ~= 2 then error() end");
+    assert_eq!(res, ThreadStatus::Ok);
+
+    state.get_global("Counter");
+    assert!(!state.is_userdata(-1));
+    assert_eq!(state.do_string("if Counter.bump == nil then error() end"), ThreadStatus::Ok);
+}
+
+#[test]
+fn result_methods() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(RenamedCounter { count: 3 });
+    state.set_global("c");
+
+    let res = state.do_string(
+        r#"assert(c:take(2) == 1)
+        local ok, err = pcall(c.take, c, 5)
+        assert(not ok and err == "only 1 left")
+        assert(c:bump() == 2)"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+fn unrepresentable_return() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(RenamedCounter { count: 0 });
+    state.set_global("c");
+
+    let res = state.do_string(
+        r#"local ok, err = pcall(c.capacity, c)
+        assert(not ok and err == "u64 18446744073709551615 does not fit in a Lua integer")
+        assert(c:bump() == 1)"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+fn derived_fields() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(Point2D { x: 1, y: 2 });
+    state.set_global("p");
+
+    let res = state.do_string(
+        "p.x = 5
+        if p.x ~= 5 or p.yPos ~= 2 then error() end
+        if p:add(0) ~= 7 then error() end"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+
+    state.do_string("ok, err = pcall(function() p.yPos = 3 end)");
+    state.get_global("err");
+    assert!(state.to_str(-1).unwrap().ends_with("field 'yPos' on Point2D is read-only"));
+}
+
+#[test]
+fn borrow_conflict() {
+    let mut state = State::new();
+    state.open_libs();
+    state.register_struct::<Point2D>();
+
+    let res = state.do_string(
+        r#"local a, b = Point2D.new(1, 2), Point2D.new(3, 4)
+        a:merge(b)
+        if tostring(a) ~= "(4, 6)" or tostring(b) ~= "(3, 4)" then error() end"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+
+    state.do_string(r#"p = Point2D.new(1, 2) ok, err = pcall(p.merge, p, p)"#);
+    state.get_global("err");
+    assert_eq!(state.to_str(-1), Some("bad argument #2 to 'merge' (Point2D is already mutably borrowed)"));
+    state.pop(1);
+
+    let res = state.do_string(r#"p:merge(Point2D.new(1, 1)) if p.x ~= 2 then error() end"#);
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[derive(LuaObject)]
+#[lua(user_values = 1)]
+struct WithUserValue;
+
+#[lua_methods]
+impl WithUserValue {}
+
+#[test]
+fn user_values() {
+    let mut state = State::new();
+    state.push(WithUserValue);
+    state.push("data");
+    assert!(state.set_user_value(-2, 1));
+    assert!(state.get_user_value(-1, 1));
+    assert_eq!(state.to_str(-1), Some("data"));
+}
+
+#[derive(LuaObject)]
+struct Creature {
+    #[lua(get)]
+    legs: i32,
+}
+
+#[lua_methods]
+impl Creature {
+    #[lua(method)]
+    fn walk(&self) -> i32 {
+        self.legs
+    }
+}
+
+#[derive(LuaObject)]
+struct Spider {
+    #[lua(parent)]
+    creature: Creature,
+    #[lua(get)]
+    venom: bool,
+}
+
+#[lua_methods]
+impl Spider {}
+
+#[test]
+fn derived_parent() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(Spider { creature: Creature { legs: 8 }, venom: true });
+    state.set_global("s");
+
+    let res = state.do_string("assert(s:walk() == 8 and s.legs == 8 and s.venom)");
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+fn statics_and_constants() {
+    let mut state = State::new();
+    state.open_libs();
+    state.register_struct::<Point2D>();
+
+    let res = state.do_string(
+        r#"local p = Point2D(1, 2)
+        assert(p.x == 1 and p.yPos == 2)
+        assert(Point2D.ZERO.x == 0 and Point2D.ZERO.yPos == 0)
+        assert(not pcall(function() return p.new end))
+        assert(not pcall(function() return p.ZERO end))
+        assert(Point2D.add(p, 1) == 4)"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[derive(LuaObject)]
+#[lua(global = false)]
+struct Hidden {
+    #[lua(get)]
+    id: i32,
+}
+
+#[lua_methods]
+impl Hidden {
+    #[lua(function)]
+    fn new(id: i32) -> Hidden {
+        Hidden { id }
+    }
+}
+
+#[test]
+fn without_global() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push_class::<Hidden>();
+    state.set_global("MakeHidden");
+
+    let res = state.do_string("assert(Hidden == nil) assert(MakeHidden(7).id == 7)");
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+mod game {
+    use lua_ffi::{lua_methods, LuaObject};
+
+    // A userdata type sharing its name with the Lua state
+    #[derive(LuaObject)]
+    pub struct State {
+        pub turn: i64,
+    }
+
+    #[lua_methods]
+    impl State {
+        #[lua(function)]
+        fn new(turn: i64) -> State {
+            State { turn }
+        }
+    }
+}
+
+#[derive(LuaObject)]
+struct Tracker {
+    #[lua(get)]
+    turn: i64,
+}
+
+#[lua_methods]
+impl Tracker {
+    #[lua(function)]
+    fn new() -> Tracker {
+        Tracker { turn: 0 }
+    }
+
+    #[lua(method)]
+    fn follow(&mut self, game: &mut game::State, state: &mut lua_ffi::State) {
+        self.turn = game.turn;
+        game.turn += 1;
+        state.push(true);
+        state.set_global("followed");
+    }
+}
+
+#[test]
+fn state_named_userdata() {
+    let mut state = State::new();
+    state.open_libs();
+    state.register_struct::<game::State>();
+    state.register_struct::<Tracker>();
+    state.pop(2);
+
+    let res = state.do_string(
+        "local game, tracker = State.new(5), Tracker.new()
+        tracker:follow(game)
+        assert(tracker.turn == 5 and followed)
+        tracker:follow(game)
+        assert(tracker.turn == 6)"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
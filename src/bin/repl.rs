@@ -0,0 +1,33 @@
+//! An interactive Lua prompt with the standard library loaded.
+//!
+//! Scripts given as arguments are run before the prompt, so bindings or
+//! test data can be set up from Lua.
+
+extern crate lua_ffi;
+
+use std::env;
+use std::io;
+use std::path::Path;
+use std::process;
+
+use lua_ffi::repl::Repl;
+use lua_ffi::State;
+
+fn main() {
+    let mut state = State::new();
+    state.open_libs();
+
+    for path in env::args().skip(1) {
+        if let Err(err) = state.do_file(Path::new(&path)) {
+            eprintln!("lua-ffi-repl: {}: {}", path, err);
+            process::exit(1);
+        }
+        state.settop(0);
+    }
+
+    let stdin = io::stdin();
+    if let Err(err) = Repl::new(state).run(stdin.lock(), io::stdout()) {
+        eprintln!("lua-ffi-repl: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,255 @@
+//! Runs a Lua script like the standalone `lua` interpreter, under the
+//! restrictions a host may put on scripts: which standard libraries are
+//! open, how much memory and how many instructions they may use, and
+//! whether precompiled chunks are accepted.
+//!
+//! The exit code tells why a script failed, so CI jobs can tell a crash
+//! from a script running away.
+
+extern crate lua_ffi;
+
+use std::io::{self, Read};
+use std::{env, fs, process};
+
+use lua_ffi::ffi::lua_rawseti;
+use lua_ffi::{LuaError, State};
+
+const USAGE: &str = "\
+usage: lua-ffi-run [options] script [--] [args]
+Runs script with args in the global table 'arg' and as '...'. A script of
+'-' is read from stdin.
+
+options:
+  --libs LIST              open only the standard libraries in LIST, separated
+                           by commas: base, coroutine, debug, io, math, os,
+                           package, string, table, utf8 (default: all)
+  --memory-limit BYTES     fail allocations past BYTES, which may end in K, M
+                           or G
+  --instruction-limit N    stop the script after N instructions
+  --no-binary              refuse precompiled chunks
+  -h, --help               print this help
+
+exit codes:
+  0  the script ran to completion
+  1  the script raised an error
+  2  the command line is invalid
+  3  the script has a syntax error, or is a refused binary chunk
+  4  the script ran out of memory
+  5  the script hit the instruction limit
+  6  the script could not be read
+";
+
+const EXIT_RUNTIME: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_SYNTAX: i32 = 3;
+const EXIT_MEMORY: i32 = 4;
+const EXIT_INSTRUCTIONS: i32 = 5;
+const EXIT_IO: i32 = 6;
+
+/// Opens a standard library on a state.
+type OpenFn = fn(&mut State);
+
+/// The standard libraries `--libs` may open.
+const LIBS: &[(&str, OpenFn)] = &[
+    ("base", State::open_base),
+    ("coroutine", State::open_coroutine),
+    ("debug", State::open_debug),
+    ("io", State::open_io),
+    ("math", State::open_math),
+    ("os", State::open_os),
+    ("package", State::open_package),
+    ("string", State::open_string),
+    ("table", State::open_table),
+    ("utf8", State::open_utf8),
+];
+
+struct Options {
+    /// The libraries to open, or `None` for all of them.
+    libs: Option<Vec<OpenFn>>,
+    memory_limit: Option<usize>,
+    instruction_limit: Option<u32>,
+    no_binary: bool,
+    /// The index of the script in the command line.
+    script: usize,
+    /// The arguments passed to the script.
+    args: Vec<String>,
+}
+
+fn main() {
+    let argv: Vec<String> = env::args().collect();
+    let opts = match parse(&argv) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return;
+        }
+        Err(msg) => {
+            eprint!("lua-ffi-run: {}\n{}", msg, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    process::exit(run(&argv, &opts));
+}
+
+/// Parses the command line, returning `None` if help was asked for.
+fn parse(argv: &[String]) -> Result<Option<Options>, String> {
+    let mut opts = Options {
+        libs: None,
+        memory_limit: None,
+        instruction_limit: None,
+        no_binary: false,
+        script: 0,
+        args: Vec::new(),
+    };
+
+    let mut i = 1;
+    while i < argv.len() {
+        let arg = argv[i].as_str();
+        match arg {
+            "-h" | "--help" => return Ok(None),
+            "--libs" => opts.libs = Some(parse_libs(value(argv, &mut i)?)?),
+            "--memory-limit" => opts.memory_limit = Some(parse_size(value(argv, &mut i)?)?),
+            "--instruction-limit" => {
+                let n = value(argv, &mut i)?;
+                match n.parse() {
+                    Ok(n) if n > 0 && n <= i32::MAX as u32 => opts.instruction_limit = Some(n),
+                    _ => return Err(format!("invalid instruction limit '{}'", n)),
+                }
+            }
+            "--no-binary" => opts.no_binary = true,
+            "--" => {
+                i += 1;
+                break;
+            }
+            "-" => break,
+            _ if arg.starts_with('-') => return Err(format!("unrecognized option '{}'", arg)),
+            _ => break,
+        }
+        i += 1;
+    }
+
+    if i >= argv.len() {
+        return Err("no script given".to_owned());
+    }
+    opts.script = i;
+
+    let mut rest = i + 1;
+    if argv.get(rest).map(String::as_str) == Some("--") {
+        rest += 1;
+    }
+    opts.args = argv[rest..].to_vec();
+
+    Ok(Some(opts))
+}
+
+/// Returns the value following the option at `i`, moving past it.
+fn value<'a>(argv: &'a [String], i: &mut usize) -> Result<&'a str, String> {
+    *i += 1;
+    argv.get(*i).map(String::as_str).ok_or_else(|| format!("'{}' needs a value", argv[*i - 1]))
+}
+
+fn parse_libs(list: &str) -> Result<Vec<OpenFn>, String> {
+    list.split(',').filter(|name| !name.is_empty()).map(|name| {
+        LIBS.iter()
+            .find(|&&(lib, _)| lib == name)
+            .map(|&(_, open)| open)
+            .ok_or_else(|| format!("unknown library '{}'", name))
+    }).collect()
+}
+
+fn parse_size(size: &str) -> Result<usize, String> {
+    let (digits, unit) = match size.as_bytes().last() {
+        Some(b'k') | Some(b'K') => (&size[..size.len() - 1], 1 << 10),
+        Some(b'm') | Some(b'M') => (&size[..size.len() - 1], 1 << 20),
+        Some(b'g') | Some(b'G') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+
+    digits.parse::<usize>().ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("invalid memory limit '{}'", size))
+}
+
+fn run(argv: &[String], opts: &Options) -> i32 {
+    let path = argv[opts.script].as_str();
+    let code = match read_script(path) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("lua-ffi-run: cannot read {}: {}", path, err);
+            return EXIT_IO;
+        }
+    };
+
+    let mut state = State::new();
+    match opts.libs {
+        Some(ref libs) => libs.iter().for_each(|open| open(&mut state)),
+        None => state.open_libs(),
+    }
+    if opts.no_binary {
+        state.refuse_binary_chunks();
+    }
+    set_arg(&mut state, argv, opts);
+
+    if opts.memory_limit.is_some() {
+        state.set_memory_limit(opts.memory_limit);
+    }
+    state.set_instruction_limit(opts.instruction_limit);
+
+    let name = if path == "-" { "=stdin".to_owned() } else { format!("@{}", path) };
+    let res = state.load_chunk(&code, &name).and_then(|()| {
+        for arg in &opts.args {
+            state.push(arg.as_str());
+        }
+        state.pcall_with_traceback(opts.args.len() as i32, 0)
+    });
+
+    match res {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("lua-ffi-run: {}", err);
+            match err {
+                LuaError::Syntax(_) => EXIT_SYNTAX,
+                LuaError::OutOfMemory => EXIT_MEMORY,
+                LuaError::InstructionLimit { .. } => EXIT_INSTRUCTIONS,
+                _ => EXIT_RUNTIME,
+            }
+        }
+    }
+}
+
+fn read_script(path: &str) -> io::Result<Vec<u8>> {
+    let mut code = if path == "-" {
+        let mut code = Vec::new();
+        io::stdin().read_to_end(&mut code)?;
+        code
+    } else {
+        fs::read(path)?
+    };
+
+    // Like `lua`, skip a first line starting with `#`, such as a shebang,
+    // keeping its newline so line numbers stay right.
+    if code.starts_with(b"#") {
+        let end = code.iter().position(|&b| b == b'\n').unwrap_or(code.len());
+        code.drain(..end);
+    }
+
+    Ok(code)
+}
+
+/// Sets the global `arg` like `lua` does: the script at index 0, its
+/// arguments from 1, and the interpreter and its options at negative
+/// indices.
+fn set_arg(state: &mut State, argv: &[String], opts: &Options) {
+    state.new_table();
+    let before = argv[..=opts.script].iter().enumerate().map(|(i, arg)| (i as i64 - opts.script as i64, arg));
+    let after = opts.args.iter().enumerate().map(|(i, arg)| (i as i64 + 1, arg));
+
+    for (n, arg) in before.chain(after) {
+        state.push(arg.as_str());
+        unsafe {
+            lua_rawseti(state.as_ptr(), -2, n);
+        }
+    }
+    state.set_global("arg");
+}
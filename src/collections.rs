@@ -0,0 +1,334 @@
+//! `LuaValue`, `FromLua` and `LuaReturn` implementations for standard
+//! collections.
+//!
+//! `Vec<T>` and slices are pushed as sequences, tables with the keys
+//! `1..=n`, and maps as tables keyed by their keys. A `HashSet<T>` becomes a
+//! table mapping each member to `true`. Tuples and fixed-size arrays are not
+//! tables: they return several values from a function and read several
+//! consecutive values from the stack.
+//!
+//! Reading is strict. A sequence with a hole or with any key outside
+//! `1..=n` is an error rather than being truncated, and an element that
+//! fails to convert is reported with its position, such as
+//! `[3]: number expected, got string`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hash};
+
+use libc::c_int;
+
+use super::error::{within, LuaError, Segment};
+use super::ffi;
+use super::types::{unwrap_push, FromLua, LuaReturn, LuaValue};
+use super::State;
+
+fn conversion(message: String) -> LuaError {
+    LuaError::Conversion {
+        path: String::new(),
+        message,
+    }
+}
+
+fn annotate(err: LuaError, seg: &Option<Segment>) -> LuaError {
+    match *seg {
+        Some(ref seg) => within(err, seg),
+        None => err,
+    }
+}
+
+/// Describes the table key at `idx` for error messages.
+fn describe_key(state: &mut State, idx: c_int) -> String {
+    match Segment::of_key(state.as_ptr(), idx) {
+        Some(Segment::Field(name)) => format!("'{}'", name),
+        Some(Segment::Index(i)) => i.to_string(),
+        None => format!("of type {}", state.typename_of(idx)),
+    }
+}
+
+/// Calls `f` with the stack indices of the key and value of every pair in
+/// the table at `idx`. The key is a copy, since reading a number key as a
+/// string would convert it in place and break the traversal. The stack is
+/// restored afterwards, even when `f` fails, and it fails with
+/// `LuaError::StackOverflow` if there is no room to traverse the table.
+fn for_each_pair<F>(state: &mut State, idx: c_int, mut f: F) -> Result<(), LuaError>
+    where F: FnMut(&mut State, c_int, c_int) -> Result<(), LuaError> {
+    let l = state.as_ptr();
+    unsafe {
+        if ffi::lua_type(l, idx) != ffi::LUA_TTABLE {
+            return Err(state.type_mismatch(idx, "table"));
+        }
+
+        reserve(l)?;
+        let table = ffi::lua_absindex(l, idx);
+        let top = ffi::lua_gettop(l);
+        ffi::lua_pushnil(l);
+        while ffi::lua_next(l, table) != 0 {
+            ffi::lua_pushvalue(l, -2);
+            let key = ffi::lua_gettop(l);
+            if let Err(err) = f(state, key, key - 1) {
+                ffi::lua_settop(l, top);
+                return Err(err);
+            }
+            ffi::lua_settop(l, key - 2);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a key and its value, locating errors in the value at the key.
+fn read_pair<K, V>(state: &mut State, key: c_int, value: c_int) -> Result<(K, V), LuaError>
+    where K: FromLua, V: FromLua {
+    let seg = Segment::of_key(state.as_ptr(), key);
+    let k = K::from_lua(state, key)
+        .map_err(|err| annotate(conversion(format!("invalid key ({})", err)), &seg))?;
+    let v = V::from_lua(state, value).map_err(|err| annotate(err, &seg))?;
+
+    Ok((k, v))
+}
+
+/// Makes room for a table and one key and value inside it, plus the slots
+/// the value may use itself, when pushing or reading one level of nested
+/// tables. Fails with `LuaError::StackOverflow` if tables are nested too
+/// deeply for the stack.
+fn reserve(l: *mut ffi::lua_State) -> Result<(), LuaError> {
+    if unsafe { ffi::lua_checkstack(l, 3 + ffi::LUA_MINSTACK) } == 0 {
+        Err(LuaError::StackOverflow)
+    } else {
+        Ok(())
+    }
+}
+
+/// Pushes a table holding `items`. If an item cannot be pushed, the table
+/// is popped and the error returned, located at the item.
+fn push_sequence<I>(l: *mut ffi::lua_State, len: usize, items: I) -> Result<(), LuaError>
+    where I: IntoIterator, I::Item: LuaValue {
+    reserve(l)?;
+    unsafe { ffi::lua_createtable(l, len as c_int, 0) };
+    for (i, item) in items.into_iter().enumerate() {
+        let i = i as ffi::lua_Integer + 1;
+        if let Err(err) = item.try_push_val(l) {
+            unsafe { ffi::lua_settop(l, -2) };
+            return Err(within(err, &Segment::Index(i)));
+        }
+        unsafe { ffi::lua_rawseti(l, -2, i) };
+    }
+
+    Ok(())
+}
+
+/// Pushes a table holding `pairs`. If a key or value cannot be pushed,
+/// or a key is `nil` or NaN, which Lua cannot use as table keys, the table
+/// is popped and the error returned.
+fn push_pairs<I, K, V>(l: *mut ffi::lua_State, len: usize, pairs: I) -> Result<(), LuaError>
+    where I: IntoIterator<Item = (K, V)>, K: LuaValue, V: LuaValue {
+    reserve(l)?;
+    unsafe { ffi::lua_createtable(l, 0, len as c_int) };
+    let table = unsafe { ffi::lua_gettop(l) };
+    for (k, v) in pairs {
+        if let Err(err) = k.try_push_val(l) {
+            unsafe { ffi::lua_settop(l, table - 1) };
+            return Err(conversion(format!("invalid key ({})", err)));
+        }
+        if unsafe { ffi::lua_isnil(l, -1) || ffi::lua_rawequal(l, -1, -1) == 0 } {
+            unsafe { ffi::lua_settop(l, table - 1) };
+            return Err(conversion("table key cannot be nil or NaN".to_owned()));
+        }
+        if let Err(err) = v.try_push_val(l) {
+            let seg = Segment::of_key(l, -1);
+            unsafe { ffi::lua_settop(l, table - 1) };
+            return Err(annotate(err, &seg));
+        }
+        unsafe { ffi::lua_rawset(l, -3) };
+    }
+
+    Ok(())
+}
+
+impl<T> LuaValue for Vec<T> where T: LuaValue {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        unwrap_push(self.try_push_val(l));
+    }
+
+    fn try_push_val(self, l: *mut ffi::lua_State) -> Result<(), LuaError> {
+        push_sequence(l, self.len(), self)
+    }
+}
+
+impl<T> LuaValue for &[T] where T: LuaValue + Clone {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        unwrap_push(self.try_push_val(l));
+    }
+
+    fn try_push_val(self, l: *mut ffi::lua_State) -> Result<(), LuaError> {
+        push_sequence(l, self.len(), self.iter().cloned())
+    }
+}
+
+impl<T> FromLua for Vec<T> where T: FromLua {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        let mut len = 0;
+        for_each_pair(state, idx, |state, key, _| {
+            match Segment::of_key(state.as_ptr(), key) {
+                Some(Segment::Index(i)) if i >= 1 => {
+                    len += 1;
+                    Ok(())
+                }
+                _ => Err(conversion(format!("expected a sequence, found key {}", describe_key(state, key)))),
+            }
+        })?;
+
+        let l = state.as_ptr();
+        reserve(l)?;
+        let table = unsafe { ffi::lua_absindex(l, idx) };
+        let mut vec = Vec::with_capacity(len);
+        for i in 1..=len {
+            unsafe { ffi::lua_rawgeti(l, table, i as ffi::lua_Integer) };
+            let res = if unsafe { ffi::lua_isnil(l, -1) } {
+                Err(conversion(format!("sequence has a hole at index {}", i)))
+            } else {
+                T::from_lua(state, -1).map_err(|err| within(err, &Segment::Index(i as i64)))
+            };
+            state.pop(1);
+            vec.push(res?);
+        }
+
+        Ok(vec)
+    }
+}
+
+impl<K, V, S> LuaValue for HashMap<K, V, S> where K: LuaValue, V: LuaValue {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        unwrap_push(self.try_push_val(l));
+    }
+
+    fn try_push_val(self, l: *mut ffi::lua_State) -> Result<(), LuaError> {
+        push_pairs(l, self.len(), self)
+    }
+}
+
+impl<K, V, S> FromLua for HashMap<K, V, S> where K: FromLua + Eq + Hash, V: FromLua, S: BuildHasher + Default {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        let mut map = HashMap::with_hasher(S::default());
+        for_each_pair(state, idx, |state, key, value| {
+            let (k, v) = read_pair(state, key, value)?;
+            map.insert(k, v);
+            Ok(())
+        })?;
+
+        Ok(map)
+    }
+}
+
+impl<K, V> LuaValue for BTreeMap<K, V> where K: LuaValue, V: LuaValue {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        unwrap_push(self.try_push_val(l));
+    }
+
+    fn try_push_val(self, l: *mut ffi::lua_State) -> Result<(), LuaError> {
+        push_pairs(l, self.len(), self)
+    }
+}
+
+impl<K, V> FromLua for BTreeMap<K, V> where K: FromLua + Ord, V: FromLua {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        let mut map = BTreeMap::new();
+        for_each_pair(state, idx, |state, key, value| {
+            let (k, v) = read_pair(state, key, value)?;
+            map.insert(k, v);
+            Ok(())
+        })?;
+
+        Ok(map)
+    }
+}
+
+impl<T, S> LuaValue for HashSet<T, S> where T: LuaValue {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        unwrap_push(self.try_push_val(l));
+    }
+
+    fn try_push_val(self, l: *mut ffi::lua_State) -> Result<(), LuaError> {
+        let len = self.len();
+        push_pairs(l, len, self.into_iter().map(|member| (member, true)))
+    }
+}
+
+/// Every key of the table is a member, and must map to `true`.
+impl<T, S> FromLua for HashSet<T, S> where T: FromLua + Eq + Hash, S: BuildHasher + Default {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        let mut set = HashSet::with_hasher(S::default());
+        for_each_pair(state, idx, |state, key, value| {
+            if state.to_bool(value) != Some(true) {
+                let got = if state.is_bool(value) { "false" } else { state.typename_of(value) };
+                let err = conversion(format!("true expected, got {}", got));
+                return Err(annotate(err, &Segment::of_key(state.as_ptr(), key)));
+            }
+
+            let (member, _) = read_pair::<T, bool>(state, key, value)?;
+            set.insert(member);
+            Ok(())
+        })?;
+
+        Ok(set)
+    }
+}
+
+/// Returns every element as a separate value.
+impl<T, const N: usize> LuaReturn for [T; N] where T: LuaValue {
+    fn push_return(self, state: &mut State) -> Result<c_int, LuaError> {
+        for item in self {
+            state.try_push(item)?;
+        }
+
+        Ok(N as c_int)
+    }
+}
+
+/// Reads `N` consecutive values starting at `idx`.
+impl<T, const N: usize> FromLua for [T; N] where T: FromLua {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        let idx = unsafe { ffi::lua_absindex(state.as_ptr(), idx) };
+        let mut items = Vec::with_capacity(N);
+        for i in 0..N {
+            items.push(T::from_lua(state, idx + i as c_int)?);
+        }
+
+        match items.try_into() {
+            Ok(array) => Ok(array),
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident $n:tt),+) => {
+        /// Returns every element as a separate value.
+        impl<$($name),+> LuaReturn for ($($name,)+) where $($name: LuaValue),+ {
+            fn push_return(self, state: &mut State) -> Result<c_int, LuaError> {
+                $(state.try_push(self.$n)?;)+
+
+                Ok([$($n),+].len() as c_int)
+            }
+        }
+
+        /// Reads consecutive values starting at `idx`, one per element.
+        impl<$($name),+> FromLua for ($($name,)+) where $($name: FromLua),+ {
+            fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+                let idx = unsafe { ffi::lua_absindex(state.as_ptr(), idx) };
+
+                Ok(($($name::from_lua(state, idx + $n)?,)+))
+            }
+        }
+    }
+}
+
+impl_tuple!(A 0);
+impl_tuple!(A 0, B 1);
+impl_tuple!(A 0, B 1, C 2);
+impl_tuple!(A 0, B 1, C 2, D 3);
+impl_tuple!(A 0, B 1, C 2, D 3, E 4);
+impl_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
@@ -0,0 +1,387 @@
+use std::error::Error;
+use std::fmt;
+use std::os::raw::c_schar;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{ptr, slice};
+
+use libc::c_int;
+
+use super::ffi;
+use super::value::LuaType;
+
+/// Errors produced while moving values between Rust and Lua.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum LuaError {
+    /// The value on the stack did not have the type a Rust conversion
+    /// expected. `expected` and `got` are Lua type names, such as `number`
+    /// or `string`.
+    TypeMismatch {
+        expected: String,
+        got: String,
+    },
+    /// A userdata value could not be borrowed because a conflicting
+    /// borrow of it is still live.
+    AlreadyBorrowed {
+        type_name: String,
+        mutably: bool,
+    },
+    /// A userdata value borrowed from Rust by `State::scope` was used
+    /// after the scope ended.
+    Expired {
+        type_name: String,
+    },
+    /// A handle to a userdata value was used after the `State` holding it
+    /// was closed.
+    Closed {
+        type_name: String,
+    },
+    /// A value could not be converted without losing information, such as
+    /// a float with a fractional part read as an integer, or a value nested
+    /// inside a table could not be converted, or the table did not have the
+    /// expected shape, such as a sequence with a hole.
+    /// `path` locates the offending value inside the converted one, such as
+    /// `enemies[3].hp`, and is empty when the error concerns the value as a
+    /// whole.
+    Conversion {
+        path: String,
+        message: String,
+    },
+    /// An argument passed to a Rust function called by Lua was invalid.
+    /// `index` is the stack index of the argument, `function` the name Lua
+    /// called the function by, or `?` if it has none, and `message`
+    /// describes the problem, such as `number expected, got string`.
+    /// An `index` of 0 stands for the `self` of a method call such as
+    /// `obj:f(x)`, reported like `luaL_argerror` does.
+    BadArgument {
+        index: c_int,
+        function: String,
+        message: String,
+    },
+    /// A Lua value raised as an error, such as a table with a `code`
+    /// field, created by
+    /// [`State::error_value`](../state/struct.State.html#method.error_value).
+    Value(ErrorValue),
+    /// A chunk could not be loaded because its source is invalid.
+    Syntax(SyntaxError),
+    /// A script file could not be opened or read. The message names the
+    /// file and the reason, such as `cannot open init.lua`.
+    File(String),
+    /// Lua code raised an error during a protected call. `message`
+    /// describes the error value, after the message handler set with
+    /// [`State::set_default_message_handler`](../state/struct.State.html#method.set_default_message_handler)
+    /// rewrote it, and `traceback` is the formatted stack of the call at
+    /// the point of the error, when it was recorded.
+    Runtime {
+        message: String,
+        traceback: Option<String>,
+    },
+    /// The stack could not grow to hold more values. Lua caps a stack at
+    /// `LUAI_MAXSTACK` slots.
+    StackOverflow,
+    /// Lua could not allocate memory, or would have gone over the limit set
+    /// with
+    /// [`State::set_memory_limit`](../state/struct.State.html#method.set_memory_limit).
+    OutOfMemory,
+    /// Lua code ran past the limit set with
+    /// [`State::set_instruction_limit`](../state/struct.State.html#method.set_instruction_limit).
+    /// `traceback` is the formatted stack of the call where it was stopped.
+    InstructionLimit {
+        traceback: Option<String>,
+    },
+}
+
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LuaError::TypeMismatch { ref expected, ref got } => {
+                write!(f, "{} expected, got {}", expected, got)
+            }
+            LuaError::AlreadyBorrowed { ref type_name, mutably: true } => {
+                write!(f, "{} is already mutably borrowed", type_name)
+            }
+            LuaError::AlreadyBorrowed { ref type_name, mutably: false } => {
+                write!(f, "{} is already borrowed", type_name)
+            }
+            LuaError::Expired { ref type_name } => {
+                write!(f, "{} is no longer accessible, its scope has ended", type_name)
+            }
+            LuaError::Closed { ref type_name } => {
+                write!(f, "{} is no longer accessible, its state was closed", type_name)
+            }
+            LuaError::Conversion { ref path, ref message } if path.is_empty() => {
+                write!(f, "{}", message)
+            }
+            LuaError::Conversion { ref path, ref message } => {
+                write!(f, "{}: {}", path, message)
+            }
+            LuaError::BadArgument { index: 0, ref function, ref message } => {
+                write!(f, "calling '{}' on bad self ({})", function, message)
+            }
+            LuaError::BadArgument { index, ref function, ref message } => {
+                write!(f, "bad argument #{} to '{}' ({})", index, function, message)
+            }
+            LuaError::Value(ref val) => val.fmt(f),
+            LuaError::Syntax(ref err) => err.fmt(f),
+            LuaError::File(ref message) => f.write_str(message),
+            LuaError::Runtime { ref message, traceback: Some(ref traceback) } => {
+                write!(f, "{}\n{}", message, traceback)
+            }
+            LuaError::Runtime { ref message, traceback: None } => f.write_str(message),
+            LuaError::StackOverflow => f.write_str("stack overflow"),
+            LuaError::OutOfMemory => f.write_str("not enough memory"),
+            LuaError::InstructionLimit { traceback: Some(ref traceback) } => {
+                write!(f, "instruction limit exceeded\n{}", traceback)
+            }
+            LuaError::InstructionLimit { traceback: None } => f.write_str("instruction limit exceeded"),
+        }
+    }
+}
+
+impl Error for LuaError {}
+
+/// Registry field holding the value of the latest `ErrorValue` of a state.
+const ERROR_VALUE_FIELD: &[u8] = b"lua_ffi.error_value\x00";
+/// Registry field holding the id of that `ErrorValue`.
+const ERROR_VALUE_ID_FIELD: &[u8] = b"lua_ffi.error_value_id\x00";
+
+/// Numbers the `ErrorValue`s of every state, so one is never mistaken for
+/// another.
+static NEXT_ERROR_VALUE: AtomicU64 = AtomicU64::new(1);
+
+/// A Lua value raised as an error, held by `LuaError::Value`.
+///
+/// The value itself stays in the state it was created in, so the error can
+/// be sent to other threads and outlive the state. A state only keeps its
+/// latest error value: raising an older one, or one of another state,
+/// raises its description instead. Strings need no keeping and are always
+/// raised as they are.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ErrorValue {
+    /// The id the value is kept under, or 0 for a string.
+    id: u64,
+    type_of: LuaType,
+    text: Option<String>,
+}
+
+impl ErrorValue {
+    /// Keeps the value at `idx` as the latest error value of the state.
+    /// Needs 2 free stack slots.
+    pub(crate) unsafe fn capture(l: *mut ffi::lua_State, idx: c_int) -> ErrorValue {
+        let type_of = LuaType::from_raw(ffi::lua_type(l, idx));
+        let id = if type_of == LuaType::String {
+            0
+        } else {
+            let id = NEXT_ERROR_VALUE.fetch_add(1, Ordering::Relaxed);
+            ffi::lua_pushvalue(l, idx);
+            ffi::lua_setfield(l, ffi::LUA_REGISTRYINDEX, ERROR_VALUE_FIELD.as_ptr() as *const c_schar);
+            ffi::lua_pushinteger(l, id as ffi::lua_Integer);
+            ffi::lua_setfield(l, ffi::LUA_REGISTRYINDEX, ERROR_VALUE_ID_FIELD.as_ptr() as *const c_schar);
+            id
+        };
+
+        // Converting a number to text changes the slot, so a copy is used
+        ffi::lua_pushvalue(l, idx);
+        let text = match type_of {
+            LuaType::String | LuaType::Number => {
+                let mut len = 0;
+                let ptr = ffi::lua_tolstring(l, -1, &mut len);
+                Some(String::from_utf8_lossy(slice::from_raw_parts(ptr as *const u8, len)).into_owned())
+            }
+            _ => None,
+        };
+        ffi::lua_settop(l, -2);
+
+        ErrorValue { id, type_of, text }
+    }
+
+    /// Pushes the value if the state still keeps it, or its description.
+    /// Needs 1 free stack slot.
+    unsafe fn push(&self, l: *mut ffi::lua_State) {
+        if self.id != 0 {
+            ffi::lua_getfield(l, ffi::LUA_REGISTRYINDEX, ERROR_VALUE_ID_FIELD.as_ptr() as *const c_schar);
+            let kept = ffi::lua_tointegerx(l, -1, ptr::null_mut()) as u64 == self.id;
+            ffi::lua_settop(l, -2);
+
+            if kept {
+                ffi::lua_getfield(l, ffi::LUA_REGISTRYINDEX, ERROR_VALUE_FIELD.as_ptr() as *const c_schar);
+                return;
+            }
+        }
+
+        let text = self.to_string();
+        ffi::lua_pushlstring(l, text.as_ptr() as *const c_schar, text.len());
+    }
+
+    /// Returns the type of the value.
+    pub fn type_of(&self) -> LuaType {
+        self.type_of
+    }
+}
+
+/// Strings and numbers are shown as text, other values by their type.
+impl fmt::Display for ErrorValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.text {
+            Some(ref text) => f.write_str(text),
+            None => write!(f, "(error object is a {} value)", self.type_of),
+        }
+    }
+}
+
+/// A syntax error reported by Lua while loading a chunk, split into the
+/// parts of its message.
+///
+/// # Examples
+///
+/// ```
+/// use lua_ffi::{LuaError, State};
+///
+/// let mut state = State::new();
+/// match state.load_chunk(b"local x = \n\n  = 3", "=editor") {
+///     Err(LuaError::Syntax(err)) => {
+///         assert_eq!(err.chunk, "editor");
+///         assert_eq!(err.line, 3);
+///         assert_eq!(err.message, "unexpected symbol near '='");
+///         assert!(!err.incomplete_input);
+///     }
+///     _ => panic!("the chunk should not load"),
+/// }
+/// ```
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SyntaxError {
+    /// The chunk as Lua displays it: the file name of an `@file` chunk,
+    /// the name of a `=name` chunk, or `[string "..."]` for any other.
+    pub chunk: String,
+    /// The line of the error, counting from 1, or 0 if Lua did not give
+    /// one.
+    pub line: u32,
+    /// The error without its position, such as `'=' expected near 'x'`.
+    pub message: String,
+    /// Whether the source ended early, so that appending more input might
+    /// make it valid, such as an unclosed `function` or string.
+    pub incomplete_input: bool,
+}
+
+impl SyntaxError {
+    /// Parses a syntax error message in the `chunk:line: message` form Lua
+    /// reports. A message without a position is kept whole.
+    pub fn parse(msg: &str) -> SyntaxError {
+        // A `[string "..."]` chunk may itself contain something resembling
+        // a position, so it is skipped as a whole.
+        let start = if msg.starts_with("[string \"") {
+            msg.find("\"]:").map_or(0, |i| i + 2)
+        } else {
+            0
+        };
+
+        let position = msg[start..].match_indices(':').find_map(|(i, _)| {
+            let colon = start + i;
+            let rest = &msg[colon + 1..];
+            let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            if digits > 0 && rest[digits..].starts_with(": ") {
+                rest[..digits].parse().ok().map(|line| (colon, line, colon + 1 + digits + 2))
+            } else {
+                None
+            }
+        });
+
+        let (chunk, line, message) = match position {
+            Some((colon, line, text)) => (msg[..colon].to_owned(), line, msg[text..].to_owned()),
+            None => (String::new(), 0, msg.to_owned()),
+        };
+
+        SyntaxError {
+            incomplete_input: message.ends_with("<eof>"),
+            chunk,
+            line,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.chunk.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.chunk, self.line, self.message)
+        }
+    }
+}
+
+/// A step in the path to a value nested inside the one being converted.
+#[derive(Clone)]
+pub(crate) enum Segment {
+    Index(i64),
+    Field(String),
+}
+
+impl Segment {
+    /// Describes the table key at `idx` as a path segment, if it is a
+    /// string or an integer.
+    pub(crate) fn of_key(l: *mut ffi::lua_State, idx: c_int) -> Option<Segment> {
+        unsafe {
+            match ffi::lua_type(l, idx) {
+                ffi::LUA_TSTRING => {
+                    let mut len = 0;
+                    let ptr = ffi::lua_tolstring(l, idx, &mut len);
+                    let bytes = slice::from_raw_parts(ptr as *const u8, len);
+                    Some(Segment::Field(String::from_utf8_lossy(bytes).into_owned()))
+                }
+                ffi::LUA_TNUMBER if ffi::lua_isinteger(l, idx) != 0 => {
+                    Some(Segment::Index(ffi::lua_tointegerx(l, idx, ptr::null_mut())))
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Prefixes the path of a conversion error with the segment it happened
+/// under, so that errors read `enemies[3].hp` once they reach the top.
+/// Other errors become conversion errors located at `seg`.
+pub(crate) fn within(err: LuaError, seg: &Segment) -> LuaError {
+    let (path, message) = match err {
+        LuaError::Conversion { path, message } => (path, message),
+        err => (String::new(), err.to_string()),
+    };
+
+    let sep = if path.is_empty() || path.starts_with('[') { "" } else { "." };
+    let path = match *seg {
+        Segment::Index(i) => format!("[{}]{}{}", i, sep, path),
+        Segment::Field(ref name) => format!("{}{}{}", name, sep, path),
+    };
+
+    LuaError::Conversion { path, message }
+}
+
+/// Raises `err` as a Lua error from inside a function called by Lua. A
+/// `LuaError::Value` raises the value itself, any other error its message.
+///
+/// As with [`raise`](fn.raise.html), callers must make sure nothing else on
+/// the Rust side still needs to be dropped.
+#[doc(hidden)]
+pub unsafe fn raise_error(l: *mut ffi::lua_State, err: LuaError) -> c_int {
+    match err {
+        LuaError::Value(val) => {
+            ffi::lua_checkstack(l, 1);
+            val.push(l);
+            drop(val);
+            ffi::lua_error(l)
+        }
+        err => raise(l, err.to_string()),
+    }
+}
+
+/// Raises `msg` as a Lua error from inside a function called by Lua.
+///
+/// The message is copied onto the Lua stack and freed before `lua_error`
+/// is called, so no Rust allocation is leaked by the jump. Callers must
+/// make sure nothing else on the Rust side still needs to be dropped.
+#[doc(hidden)]
+pub unsafe fn raise(l: *mut ffi::lua_State, msg: String) -> c_int {
+    ffi::lua_pushlstring(l, msg.as_ptr() as *const c_schar, msg.len());
+    drop(msg);
+
+    ffi::lua_error(l)
+}
@@ -2,7 +2,7 @@
 use libc::{c_int, c_schar, size_t, c_void};
 use super::lua::*;
 use std::ptr;
-use std::ptr::{null, null_mut};
+use std::ptr::null;
 
 pub const LUA_ERRFILE: c_int = LUA_ERRERR + 1;
 pub const LUAL_BUFFERSIZE: size_t = 8192;
@@ -14,7 +14,7 @@ pub struct luaL_Reg {
 }
 
 extern "C" {
-    pub fn luaL_getmetafield(L: *mut lua_State, obj: c_int, e: *const c_schar);
+    pub fn luaL_getmetafield(L: *mut lua_State, obj: c_int, e: *const c_schar) -> c_int;
     pub fn luaL_callmeta(L: *mut lua_State, obj: c_int, e: *const c_schar);
     pub fn luaL_typerror(L: *mut lua_State, narg: c_int, tname: *const c_schar);
     pub fn luaL_argerror(L: *mut lua_State, numArg: c_int, extramsg: *const c_schar);
@@ -32,6 +32,7 @@ extern "C" {
 
     pub fn luaL_newmetatable(L: *mut lua_State, tname: *const c_schar) -> c_int;
     pub fn luaL_checkudata(L: *mut lua_State, ud: c_int, tname: *const c_schar) -> *mut c_void;
+    pub fn luaL_testudata(L: *mut lua_State, ud: c_int, tname: *const c_schar) -> *mut c_void;
 
     pub fn luaL_where(L: *mut lua_State, lvl: c_int);
     pub fn luaL_error(L: *mut lua_State, fmt: *const c_schar, ...) -> c_int;
@@ -47,6 +48,7 @@ extern "C" {
 
     pub fn luaL_setfuncs(L: *mut lua_State, l: *const luaL_Reg, nup: c_int);
     pub fn luaL_gsub(L: *mut lua_State, s: *const c_schar, p: *const c_schar, r: *const c_schar) -> *const c_schar;
+    pub fn luaL_requiref(L: *mut lua_State, modname: *const c_schar, openf: lua_CFunction, glb: c_int);
     pub fn luaL_findtable(L: *mut lua_State, idx: c_int, fname: *const c_schar, szhint: size_t) -> *const c_schar;
 
     pub fn luaL_fileresult(L: *mut lua_State, stat: c_int, fname: *const c_schar) -> c_int;
@@ -84,7 +86,7 @@ pub unsafe fn luaL_optint(L: *mut lua_State, narg: c_int, d: c_int) -> c_int {
 }
 
 #[inline(always)]
-pub unsafe fn luaL_typename(L: *mut lua_State, i: c_int) -> c_int {
+pub unsafe fn luaL_typename(L: *mut lua_State, i: c_int) -> *const c_schar {
     lua_typename(L, lua_type(L, i))
 }
 
@@ -151,5 +153,5 @@ pub unsafe fn lua_unref(L: *mut lua_State, r: c_int) {
 
 #[inline(always)]
 pub unsafe fn lua_getref(L: *mut lua_State, r: c_int) {
-    lua_rawgeti(L, LUA_REGISTRYINDEX, r);
+    lua_rawgeti(L, LUA_REGISTRYINDEX, r as lua_Integer);
 }
@@ -2,18 +2,17 @@
 use libc::{c_int, c_uchar, c_schar, c_double, c_void, size_t, ptrdiff_t};
 use super::lauxlib::luaL_newstate;
 use std::ptr;
-use std::os::raw::c_uint;
 
 pub const LUA_VERSION: &'static [c_uchar] = b"Lua 5.3\x00";
 pub const LUA_RELEASE: &'static [c_uchar] = b"Lua 5.3.6\x00";
 pub const LUA_VERSION_NUM: c_int = 503;
 
-pub const LUA_SIGNATURE: &'static [c_uchar] = b"\033Lua\x00";
+pub const LUA_SIGNATURE: &'static [c_uchar] = b"\x1bLua\x00";
 
 pub const LUA_MULTIRET: c_int = -1;
 
 pub const LUAI_MAXSTACK: c_int = 1000000;
-pub const LUA_REGISTRYINDEX: c_int = -10000;
+pub const LUA_REGISTRYINDEX: c_int = -LUAI_MAXSTACK - 1000;
 
 #[inline(always)]
 pub fn lua_upvalueindex(i: i32) -> c_int {
@@ -51,15 +50,22 @@ pub const LUA_TTHREAD: c_int = 8;
 
 pub const LUA_MINSTACK: c_int = 20;
 
+pub const LUA_RIDX_MAINTHREAD: lua_Integer = 1;
+pub const LUA_RIDX_GLOBALS: lua_Integer = 2;
+
 // These are constant in LuaJIT
 pub type lua_Number = c_double;
-pub type lua_Integer = ptrdiff_t;
+pub type lua_Integer = i64;
 pub type lua_Unsigned = usize;
 pub type lua_KContext = ptrdiff_t;
 pub type lua_KFunction = Option<unsafe extern "C" fn(s: *mut lua_State, status: i32, ctx: lua_KContext) -> c_int>;
 
 pub const LUA_IDSIZE: size_t = 60;
 
+pub const LUA_OPEQ: c_int = 0;
+pub const LUA_OPLT: c_int = 1;
+pub const LUA_OPLE: c_int = 2;
+
 pub const LUA_GCSTOP: c_int = 0;
 pub const LUA_GCRESTART: c_int = 1;
 pub const LUA_GCCOLLECT: c_int = 2;
@@ -77,24 +83,26 @@ extern "C" {
 
     pub fn lua_gettop(L: *mut lua_State) -> c_int;
     pub fn lua_settop(L: *mut lua_State, idx: c_int);
+    pub fn lua_absindex(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn lua_pushvalue(L: *mut lua_State, idx: c_int);
-    pub fn lua_remove(L: *mut lua_State, idx: c_int);
-    pub fn lua_insert(L: *mut lua_State, idx: c_int);
-    pub fn lua_replace(L: *mut lua_State, idx: c_int);
+    pub fn lua_rotate(L: *mut lua_State, idx: c_int, n: c_int);
+    pub fn lua_copy(L: *mut lua_State, fromidx: c_int, toidx: c_int);
     pub fn lua_checkstack(L: *mut lua_State, sz: c_int) -> c_int;
 
     pub fn lua_xmove(from: *mut lua_State, to: *mut lua_State, n: c_int);
 
     pub fn lua_isnumber(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_isinteger(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn lua_isstring(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn lua_iscfunction(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn lua_isuserdata(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn lua_type(L: *mut lua_State, idx: c_int) -> c_int;
-    pub fn lua_typename(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_typename(L: *mut lua_State, tp: c_int) -> *const c_schar;
 
     pub fn lua_equal(L: *mut lua_State, idx1: c_int, idx2: c_int) -> c_int;
     pub fn lua_rawequal(L: *mut lua_State, idx1: c_int, idx2: c_int) -> c_int;
     pub fn lua_lessthan(L: *mut lua_State, idx1: c_int, idx2: c_int) -> c_int;
+    pub fn lua_compare(L: *mut lua_State, idx1: c_int, idx2: c_int, op: c_int) -> c_int;
 
     pub fn lua_tonumberx(L: *mut lua_State, idx: c_int, is_num: *mut c_int) -> lua_Number;
     pub fn lua_tointegerx(L: *mut lua_State, idx: c_int, is_num: *mut c_int) -> lua_Integer;
@@ -123,18 +131,20 @@ extern "C" {
     pub fn lua_gettable(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn lua_getfield(L: *mut lua_State, idx: c_int, k: *const c_schar) -> c_int;
     pub fn lua_rawget(L: *mut lua_State, idx: c_int) -> c_int;
-    pub fn lua_rawgeti(L: *mut lua_State, idx: c_int, n: c_int) -> c_int;
+    pub fn lua_rawgeti(L: *mut lua_State, idx: c_int, n: lua_Integer) -> c_int;
     pub fn lua_createtable(L: *mut lua_State, narr: c_int, nrec: c_int);
     pub fn lua_newuserdata(L: *mut lua_State, sz: size_t) -> *mut c_void;
     pub fn lua_getmetatable(L: *mut lua_State, objindex: c_int) -> c_int;
+    pub fn lua_getuservalue(L: *mut lua_State, idx: c_int) -> c_int;
 
     // Set functions
     pub fn lua_settable(L: *mut lua_State, idx: c_int);
     pub fn lua_setglobal(L: *mut lua_State, name: *const c_schar);
     pub fn lua_setfield(L: *mut lua_State, idx: c_int, k: *const c_schar);
     pub fn lua_rawset(L: *mut lua_State, idx: c_int);
-    pub fn lua_rawseti(L: *mut lua_State, idx: c_int, n: c_int);
+    pub fn lua_rawseti(L: *mut lua_State, idx: c_int, n: lua_Integer);
     pub fn lua_setmetatable(L: *mut lua_State, objindex: c_int) -> c_int;
+    pub fn lua_setuservalue(L: *mut lua_State, idx: c_int);
     pub fn lua_setfenv(L: *mut lua_State, idx: c_int) -> c_int;
 
     pub fn lua_callk(L: *mut lua_State, nargs: c_int, nresults: c_int, ctx: lua_KContext, k: lua_KFunction);
@@ -162,6 +172,23 @@ pub unsafe fn lua_pop(state: *mut lua_State, n: c_int) {
     lua_settop(state, -n - 1);
 }
 
+#[inline(always)]
+pub unsafe fn lua_insert(state: *mut lua_State, idx: c_int) {
+    lua_rotate(state, idx, 1);
+}
+
+#[inline(always)]
+pub unsafe fn lua_remove(state: *mut lua_State, idx: c_int) {
+    lua_rotate(state, idx, -1);
+    lua_pop(state, 1);
+}
+
+#[inline(always)]
+pub unsafe fn lua_replace(state: *mut lua_State, idx: c_int) {
+    lua_copy(state, -1, idx);
+    lua_pop(state, 1);
+}
+
 #[inline(always)]
 pub unsafe fn lua_newtable(state: *mut lua_State) {
     lua_createtable(state, 0, 0);
@@ -227,7 +254,7 @@ pub unsafe fn lua_isnoneornil(state: *mut lua_State, n: c_int) -> bool {
 pub unsafe fn lua_pushliteral(state: *mut lua_State, s: &'static str) {
     use std::ffi::CString;
     let c_str = CString::new(s).unwrap();
-    lua_pushlstring(state, c_str.as_ptr() as *const c_schar, s.as_bytes().len());
+    lua_pushlstring(state, c_str.as_ptr() as *const c_schar, s.len());
 }
 
 #[inline(always)]
@@ -250,6 +277,78 @@ pub unsafe fn lua_getgccount(state: *mut lua_State) -> c_int {
     lua_gc(state, LUA_GCCOUNT, 0)
 }
 
+// Lua 5.4 gives userdata any number of user values. On 5.3 they are kept
+// in a table set as the single user value, with the count under `n`.
+
+/// Creates a full userdata with `nuvalue` user values, all `nil`.
+pub unsafe fn lua_newuserdatauv(state: *mut lua_State, sz: size_t, nuvalue: c_int) -> *mut c_void {
+    let udata = lua_newuserdata(state, sz);
+
+    if nuvalue > 0 {
+        lua_createtable(state, nuvalue, 1);
+        lua_pushinteger(state, nuvalue as lua_Integer);
+        lua_setfield(state, -2, b"n\x00".as_ptr() as *const c_schar);
+        lua_setuservalue(state, -2);
+    }
+
+    udata
+}
+
+/// Pushes the `n`th user value of the userdata at `idx` and returns its
+/// type. Pushes `nil` and returns `LUA_TNONE` if there is no such value.
+pub unsafe fn lua_getiuservalue(state: *mut lua_State, idx: c_int, n: c_int) -> c_int {
+    let idx = lua_absindex(state, idx);
+
+    if !uservalue_slot(state, idx, n) {
+        lua_pushnil(state);
+        return LUA_TNONE;
+    }
+
+    let ty = lua_rawgeti(state, -1, n as lua_Integer);
+    lua_remove(state, -2);
+    ty
+}
+
+/// Pops a value and sets it as the `n`th user value of the userdata at
+/// `idx`. Returns 0 if the userdata has no such value.
+pub unsafe fn lua_setiuservalue(state: *mut lua_State, idx: c_int, n: c_int) -> c_int {
+    let idx = lua_absindex(state, idx);
+
+    if !uservalue_slot(state, idx, n) {
+        lua_pop(state, 1);
+        return 0;
+    }
+
+    lua_insert(state, -2);
+    lua_rawseti(state, -2, n as lua_Integer);
+    lua_pop(state, 1);
+    1
+}
+
+// Pushes the user value table of the userdata at `idx` if it has an `n`th
+// slot, and pushes nothing otherwise.
+unsafe fn uservalue_slot(state: *mut lua_State, idx: c_int, n: c_int) -> bool {
+    if lua_type(state, idx) != LUA_TUSERDATA {
+        return false;
+    }
+
+    if lua_getuservalue(state, idx) != LUA_TTABLE {
+        lua_pop(state, 1);
+        return false;
+    }
+
+    lua_getfield(state, -1, b"n\x00".as_ptr() as *const c_schar);
+    let len = lua_tointegerx(state, -1, ptr::null_mut());
+    lua_pop(state, 1);
+
+    if n < 1 || n as lua_Integer > len {
+        lua_pop(state, 1);
+        return false;
+    }
+
+    true
+}
+
 pub type lua_Hook = Option<extern "C" fn(L: *mut lua_State, ar: *mut lua_Debug)>;
 
 pub const LUA_HOOKCALL: c_int = 0;
@@ -258,10 +357,10 @@ pub const LUA_HOOKLINE: c_int = 2;
 pub const LUA_HOOKCOUNT: c_int = 3;
 pub const LUA_HOOKTAILRET: c_int = 4;
 
-pub const LUA_MASKCALL: c_int = (1 << LUA_HOOKCALL);
-pub const LUA_MASKRET: c_int = (1 << LUA_HOOKRET);
-pub const LUA_MASKLINE: c_int = (1 << LUA_HOOKLINE);
-pub const LUA_MASKCOUNT: c_int = (1 << LUA_HOOKCOUNT);
+pub const LUA_MASKCALL: c_int = 1 << LUA_HOOKCALL;
+pub const LUA_MASKRET: c_int = 1 << LUA_HOOKRET;
+pub const LUA_MASKLINE: c_int = 1 << LUA_HOOKLINE;
+pub const LUA_MASKCOUNT: c_int = 1 << LUA_HOOKCOUNT;
 
 extern "C" {
     pub fn lua_setlevel(from: *mut lua_State, to: *mut lua_State);
@@ -288,9 +387,12 @@ pub struct lua_Debug {
     pub what: *const c_schar,
     pub source: *const c_schar,
     pub currentline: c_int,
-    pub nups: c_int,
     pub linedefined: c_int,
     pub lastlinedefined: c_int,
+    pub nups: c_uchar,
+    pub nparams: c_uchar,
+    pub isvararg: c_schar,
+    pub istailcall: c_schar,
     pub short_src: [c_schar; LUA_IDSIZE],
-    i_ci: c_int,
+    i_ci: *mut c_void,
 }
@@ -1,3 +1,5 @@
+#![allow(non_snake_case, clippy::missing_safety_doc, clippy::redundant_static_lifetimes)]
+
 pub mod lua;
 pub mod lualib;
 pub mod lauxlib;
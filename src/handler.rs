@@ -0,0 +1,148 @@
+//! The message handler used by protected calls, which records a traceback
+//! and lets the host rewrite error values before Lua unwinds the stack.
+
+use std::any::Any;
+use std::ffi::CStr;
+use std::os::raw::c_schar;
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+use libc::c_int;
+
+use super::ffi::*;
+use super::types::{FromLua, LuaValue};
+use super::userdata;
+use super::value::Value;
+use super::State;
+
+/// Rewrites an error value before a protected call returns it.
+pub(crate) type HandlerFn = Box<dyn Fn(&mut State, Value) -> Value>;
+
+/// Registry field holding the message handler closure, if one was set.
+const HANDLER_FIELD: &[u8] = b"lua_ffi.message_handler\x00";
+/// Registry field holding the traceback of the error being handled, set by
+/// the handler and taken right after the protected call returns.
+const TRACEBACK_FIELD: &[u8] = b"lua_ffi.traceback\x00";
+
+/// Installs `f` as the message handler of every protected call made
+/// through `State`.
+pub(crate) fn set(state: &mut State, f: HandlerFn) {
+    userdata::push_gc(state, f);
+
+    unsafe {
+        let l = state.as_ptr();
+        lua_pushcclosure(l, Some(handle_message), 1);
+        lua_setfield(l, LUA_REGISTRYINDEX, HANDLER_FIELD.as_ptr() as *const c_schar);
+    }
+}
+
+/// Pushes the message handler, the host's if one was set.
+fn push(l: *mut lua_State) {
+    unsafe {
+        if lua_getfield(l, LUA_REGISTRYINDEX, HANDLER_FIELD.as_ptr() as *const c_schar) == LUA_TNIL {
+            lua_settop(l, -2);
+            lua_pushcfunction(l, Some(handle_message));
+        }
+    }
+}
+
+/// Calls the function below the `nargs` arguments with the message
+/// handler, leaving the results or the handled error value. Returns the
+/// status along with the traceback of the error, if the handler ran.
+///
+/// The traceback of an enclosing call still being handled, such as when
+/// the host's handler makes protected calls itself, is kept for it. The
+/// stack must have room for one more value.
+pub(crate) fn pcall(l: *mut lua_State, nargs: c_int, nres: c_int) -> (c_int, Option<String>) {
+    unsafe {
+        let outer = take_traceback(l);
+
+        let func = lua_gettop(l) - nargs;
+        push(l);
+        lua_insert(l, func);
+        let status = lua_pcallk(l, nargs, nres, func, 0, None);
+        lua_remove(l, func);
+
+        let traceback = if status == LUA_OK { None } else { take_traceback(l) };
+        if let Some(outer) = outer {
+            lua_checkstack(l, 1);
+            lua_pushlstring(l, outer.as_ptr() as *const c_schar, outer.len());
+            lua_setfield(l, LUA_REGISTRYINDEX, TRACEBACK_FIELD.as_ptr() as *const c_schar);
+        }
+
+        (status, traceback)
+    }
+}
+
+/// Returns the recorded traceback, clearing it.
+unsafe fn take_traceback(l: *mut lua_State) -> Option<String> {
+    lua_checkstack(l, 1);
+    lua_getfield(l, LUA_REGISTRYINDEX, TRACEBACK_FIELD.as_ptr() as *const c_schar);
+    let traceback = to_string(l, -1);
+    lua_settop(l, -2);
+
+    if traceback.is_some() {
+        lua_pushnil(l);
+        lua_setfield(l, LUA_REGISTRYINDEX, TRACEBACK_FIELD.as_ptr() as *const c_schar);
+    }
+
+    traceback
+}
+
+/// Describes the error value at `idx`: strings and numbers as they are,
+/// anything else by its type, as the standalone interpreter does.
+pub(crate) fn describe(l: *mut lua_State, idx: c_int) -> String {
+    unsafe {
+        to_string(l, idx).unwrap_or_else(|| {
+            let name = lua_typename(l, lua_type(l, idx));
+            let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+            format!("(error object is a {} value)", name)
+        })
+    }
+}
+
+unsafe fn to_string(l: *mut lua_State, idx: c_int) -> Option<String> {
+    match lua_type(l, idx) {
+        LUA_TSTRING | LUA_TNUMBER => {
+            let mut len = 0;
+            let ptr = lua_tolstring(l, idx, &mut len);
+            let bytes = slice::from_raw_parts(ptr as *const u8, len);
+            Some(String::from_utf8_lossy(bytes).into_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Describes the payload of a panic, which is usually a string.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => payload.downcast_ref::<String>().map_or("Box<dyn Any>", String::as_str),
+    }
+}
+
+unsafe extern "C" fn handle_message(l: *mut lua_State) -> c_int {
+    lua_settop(l, 1);
+    luaL_traceback(l, l, ptr::null(), 1);
+
+    if lua_type(l, lua_upvalueindex(1)) == LUA_TUSERDATA {
+        let handled = {
+            let mut state = State::from_ptr(l);
+            let f = &*(lua_touserdata(l, lua_upvalueindex(1)) as *const HandlerFn);
+            let val = Value::from_lua(&mut state, 1).unwrap_or_default();
+            // Unwinding out of a function called by Lua is not allowed, so a
+            // panicking handler replaces the error with a message saying so.
+            panic::catch_unwind(AssertUnwindSafe(|| f(&mut state, val))).unwrap_or_else(|payload| {
+                Value::from(format!("message handler panicked: {}", panic_message(&*payload)))
+            })
+        };
+        handled.push_val(l);
+        lua_replace(l, 1);
+    }
+
+    // Recorded last, as the host's handler may make protected calls of its
+    // own.
+    lua_setfield(l, LUA_REGISTRYINDEX, TRACEBACK_FIELD.as_ptr() as *const c_schar);
+
+    1
+}
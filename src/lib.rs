@@ -8,9 +8,9 @@
 //! 
 //! ```
 //! #[macro_use]
-//! extern crate luajit;
+//! extern crate lua_ffi;
 //!
-//! use luajit::{c_int, State};
+//! use lua_ffi::{c_int, State};
 //!
 //! fn return_42(state: &mut State) -> c_int {
 //!     state.push(42);
@@ -29,27 +29,64 @@
 //! }
 //! ```
 
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 extern crate libc;
 
+#[cfg(feature = "derive")]
+extern crate lua_ffi_derive;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+mod collections;
+pub mod error;
 pub mod ffi;
+mod handler;
+pub mod metamethod;
+mod pretty;
+pub mod reload;
+#[cfg(feature = "repl")]
+pub mod repl;
+mod sandbox;
+mod scope;
+#[cfg(feature = "serde")]
+mod serialize;
+mod stack;
 pub mod state;
+pub mod testing;
 pub mod types;
+mod userdata;
+pub mod value;
 
+pub use error::{ErrorValue, LuaError, SyntaxError};
+pub use metamethod::{LuaMetaMethod, Operand};
+pub use scope::Scope;
+pub use stack::StackGuard;
 pub use state::{State, ThreadStatus};
-pub use types::{LuaFunction, LuaObject};
+pub use types::{FromLua, LightUserData, LuaConstant, LuaField, LuaFnResult, LuaFunction, LuaMethods, LuaNumber, LuaObject, LuaParent, LuaResult, LuaReturn, LuaString};
+pub use userdata::LuaUserData;
+pub use value::{LuaRef, LuaType, Value};
+
+#[cfg(feature = "derive")]
+pub use lua_ffi_derive::{lua_methods, LuaObject};
 
 pub use libc::c_int;
 
 /// This macro is used to wrap a rust function in an `extern "C"` trampoline
 /// to automatically pass a [`State`](state/struct.State.html) struct as the first
 /// argument instead of a `lua_State` raw pointer
+///
+/// The function returns the number of values it pushed, either as a plain
+/// `c_int` or as `Result<c_int, LuaError>`. An `Err` is raised as a Lua
+/// error after the function has returned, so its Rust values are dropped.
 /// 
 /// # Examples
 /// 
 /// ```
-/// #[macro_use] extern crate luajit;
+/// #[macro_use] extern crate lua_ffi;
 ///
-/// use luajit::{State, c_int, ThreadStatus};
+/// use lua_ffi::{State, c_int, ThreadStatus};
 ///
 /// fn return_42(state: &mut State) -> c_int {
 ///     state.push(42);
@@ -76,7 +113,12 @@ macro_rules! lua_fn {
         {
             #[allow(unused)]
             unsafe extern "C" fn trampoline(l: *mut $crate::ffi::lua_State) -> $crate::c_int {
-                $method(&mut $crate::State::from_ptr(l))
+                let res = $crate::LuaFnResult::into_result($method(&mut $crate::State::from_ptr(l)));
+
+                match res {
+                    Ok(n) => n,
+                    Err(err) => $crate::error::raise_error(l, err),
+                }
             };
 
             Some(trampoline as $crate::LuaFunction)
@@ -101,7 +143,8 @@ macro_rules! lua_func {
 /// This macro can be used to automatically generate a `luaL_Reg`
 /// struct for the provided method, with name `name`. It automatically
 /// reads an instances of struct `$st` from userdata and provides it as
-/// an argument.
+/// an argument. Like with `lua_fn!`, the method may return a `c_int` or a
+/// `Result<c_int, LuaError>`.
 #[macro_export]
 macro_rules! lua_method {
     ($name:expr, $st:ty, $method:path) => {
@@ -109,9 +152,27 @@ macro_rules! lua_method {
             #[allow(unused)]
             unsafe extern "C" fn trampoline(l: *mut $crate::ffi::lua_State) -> $crate::c_int {
                 let mut state = $crate::State::from_ptr(l);
-                let st = &mut *state.check_userdata::<$st>(1).unwrap();
 
-                $method(st, &mut state)
+                let res = match state.check_userdata::<$st>(1) {
+                    Ok(_) => {
+                        let ud = state.to::<$crate::LuaUserData<$st>>(1).unwrap();
+                        let res = match ud.borrow_mut() {
+                            Ok(mut st) => $crate::LuaFnResult::into_result($method(&mut *st, &mut state)),
+                            Err(err) => Err($crate::LuaError::BadArgument {
+                                index: 1,
+                                function: $name.to_owned(),
+                                message: err.to_string(),
+                            }),
+                        };
+                        res
+                    }
+                    Err(err) => Err(err),
+                };
+
+                match res {
+                    Ok(n) => n,
+                    Err(err) => $crate::error::raise_error(l, err),
+                }
             };
 
             $crate::ffi::lauxlib::luaL_Reg {
@@ -0,0 +1,339 @@
+//! Typed metamethods for [`LuaObject`](../types/trait.LuaObject.html) types.
+//!
+//! Metamethods returned by `LuaObject::lua_metamethods` are stored in the
+//! metatable of the type when it is registered. Binary operators receive
+//! their operands as [`Operand`](enum.Operand.html)s, so a handler for
+//! `__mul` on a vector type sees `v * 2` and `2 * v` with the operands in
+//! the order they were written.
+
+use std::cell::{Ref, RefMut};
+use std::marker::PhantomData;
+use std::os::raw::c_void;
+
+use libc::c_int;
+
+use super::error::{self, ErrorValue, LuaError};
+use super::ffi::*;
+use super::types::{FromLua, LuaObject, LuaReturn};
+use super::userdata::{self, LuaUserData};
+use super::State;
+
+/// One operand of a binary metamethod on `T`.
+pub enum Operand<'a, T: 'a, V> {
+    /// An instance of `T`, borrowed for the duration of the call.
+    Object(Ref<'a, T>),
+    /// Any other value, converted with `FromLua`.
+    Value(V),
+}
+
+impl<'a, T, V> Operand<'a, T, V> {
+    /// Returns the instance of `T`, if this operand is one.
+    pub fn object(&self) -> Option<&T> {
+        match *self {
+            Operand::Object(ref obj) => Some(obj),
+            Operand::Value(_) => None,
+        }
+    }
+
+    /// Returns the converted value, if this operand is not an instance
+    /// of `T`.
+    pub fn value(self) -> Option<V> {
+        match self {
+            Operand::Object(_) => None,
+            Operand::Value(val) => Some(val),
+        }
+    }
+}
+
+/// Runs a metamethod against the arguments on the stack, returning the
+/// number of results or the error to raise.
+pub(crate) type MetaFn = Box<dyn Fn(&mut State) -> Result<c_int, LuaError>>;
+
+/// The body of a metamethod run by `protected`.
+type Body<'a> = dyn FnMut(&mut State) -> Result<c_int, LuaError> + 'a;
+
+/// A metamethod for userdata of type `T`, registered in its metatable
+/// under [`name`](#method.name).
+///
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate lua_ffi;
+/// use lua_ffi::{LuaObject, LuaMetaMethod, Operand, State, ThreadStatus};
+/// use lua_ffi::ffi::luaL_Reg;
+///
+/// #[derive(Clone, Copy)]
+/// struct Vec2(f64, f64);
+///
+/// impl LuaObject for Vec2 {
+///     fn name() -> *const i8 {
+///         c_str!("Vec2")
+///     }
+///
+///     fn lua_fns() -> Vec<luaL_Reg> {
+///         vec!()
+///     }
+///
+///     fn lua_metamethods() -> Vec<LuaMetaMethod<Vec2>> {
+///         vec!(
+///             LuaMetaMethod::mul(|a: Operand<Vec2, f64>, b| match (a, b) {
+///                 (Operand::Object(v), Operand::Value(k)) |
+///                 (Operand::Value(k), Operand::Object(v)) => Vec2(v.0 * k, v.1 * k),
+///                 (Operand::Object(a), Operand::Object(b)) => Vec2(a.0 * b.0, a.1 * b.1),
+///                 (Operand::Value(_), Operand::Value(_)) => unreachable!(),
+///             }),
+///             LuaMetaMethod::tostring(|v: &Vec2| format!("Vec2({}, {})", v.0, v.1)),
+///         )
+///     }
+/// }
+///
+/// fn main() {
+///     let mut state = State::new();
+///     state.open_libs();
+///     state.push(Vec2(1.0, 2.0));
+///     state.set_global("v");
+///
+///     let res = state.do_string(r#"assert(tostring(2 * v) == "Vec2(2, 4)")"#);
+///     assert_eq!(res, ThreadStatus::Ok);
+/// }
+/// ```
+pub struct LuaMetaMethod<T> {
+    pub(crate) name: &'static str,
+    pub(crate) func: MetaFn,
+    marker: PhantomData<fn(&T)>,
+}
+
+macro_rules! binary_metamethods {
+    ($($(#[$attr:meta])* $func:ident => $name:expr;)*) => {
+        $(
+            $(#[$attr])*
+            pub fn $func<V, R, F>(f: F) -> LuaMetaMethod<T>
+                where V: FromLua, R: LuaReturn, F: Fn(Operand<T, V>, Operand<T, V>) -> R + 'static {
+                LuaMetaMethod::binary($name, f)
+            }
+        )*
+    }
+}
+
+macro_rules! unary_metamethods {
+    ($($(#[$attr:meta])* $func:ident => $name:expr;)*) => {
+        $(
+            $(#[$attr])*
+            pub fn $func<R, F>(f: F) -> LuaMetaMethod<T>
+                where R: LuaReturn, F: Fn(&T) -> R + 'static {
+                LuaMetaMethod::new($name, move |state| {
+                    let res = f(&*self_ref::<T>(state, $name)?);
+                    res.push_return(state).map_err(|err| err.to_string())
+                })
+            }
+        )*
+    }
+}
+
+impl<T> LuaMetaMethod<T> where T: LuaObject {
+    /// Creates a metamethod named `name` from a function reading its
+    /// arguments directly from the stack. Returning `Err` raises the
+    /// message as a Lua error.
+    pub fn new<F>(name: &'static str, f: F) -> LuaMetaMethod<T>
+        where F: Fn(&mut State) -> Result<c_int, String> + 'static {
+        LuaMetaMethod::raw(name, move |state| match f(state) {
+            Ok(n) => Ok(n),
+            Err(msg) => Err(state.error_value(msg)),
+        })
+    }
+
+    fn raw<F>(name: &'static str, f: F) -> LuaMetaMethod<T>
+        where F: Fn(&mut State) -> Result<c_int, LuaError> + 'static {
+        LuaMetaMethod {
+            name,
+            func: Box::new(f),
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the name of the metatable field this metamethod is stored
+    /// in, such as `__add`.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn binary<V, R, F>(name: &'static str, f: F) -> LuaMetaMethod<T>
+        where V: FromLua, R: LuaReturn, F: Fn(Operand<T, V>, Operand<T, V>) -> R + 'static {
+        LuaMetaMethod::new(name, move |state| {
+            let a = operand::<T, V>(state, 1, name)?;
+            let b = operand::<T, V>(state, 2, name)?;
+            f(a, b).push_return(state).map_err(|err| err.to_string())
+        })
+    }
+
+    binary_metamethods! {
+        /// `a + b`
+        add => "__add";
+        /// `a - b`
+        sub => "__sub";
+        /// `a * b`
+        mul => "__mul";
+        /// `a / b`
+        div => "__div";
+        /// `a % b`
+        modulo => "__mod";
+        /// `a ^ b`
+        pow => "__pow";
+        /// `a // b`
+        idiv => "__idiv";
+        /// `a & b`
+        band => "__band";
+        /// `a | b`
+        bor => "__bor";
+        /// `a ~ b`
+        bxor => "__bxor";
+        /// `a << b`
+        shl => "__shl";
+        /// `a >> b`
+        shr => "__shr";
+        /// `a .. b`
+        concat => "__concat";
+        /// `a < b`, and `b > a`
+        lt => "__lt";
+        /// `a <= b`, and `b >= a`
+        le => "__le";
+    }
+
+    unary_metamethods! {
+        /// `-a`
+        unm => "__unm";
+        /// `~a`
+        bnot => "__bnot";
+        /// `#a`
+        len => "__len";
+    }
+
+    /// `a == b`. Lua only calls this when both operands are userdata, if
+    /// either of them is not a `T` the comparison is false.
+    pub fn eq<F>(f: F) -> LuaMetaMethod<T> where F: Fn(&T, &T) -> bool + 'static {
+        LuaMetaMethod::new("__eq", move |state| {
+            let res = match (state.to::<LuaUserData<T>>(1), state.to::<LuaUserData<T>>(2)) {
+                (Ok(a), Ok(b)) => {
+                    let a = a.borrow().map_err(|err| arg_error(1, "__eq", err))?;
+                    let b = b.borrow().map_err(|err| arg_error(2, "__eq", err))?;
+                    f(&a, &b)
+                }
+                _ => false,
+            };
+            state.try_push(res).map(|_| 1).map_err(|err| err.to_string())
+        })
+    }
+
+    /// `tostring(a)`, also used by `print`.
+    pub fn tostring<F>(f: F) -> LuaMetaMethod<T> where F: Fn(&T) -> String + 'static {
+        LuaMetaMethod::new("__tostring", move |state| {
+            let text = f(&*self_ref::<T>(state, "__tostring")?);
+            state.try_push(text).map(|_| 1).map_err(|err| err.to_string())
+        })
+    }
+
+    /// `a(...)`. The call arguments start at stack index 2.
+    pub fn call<R, F>(f: F) -> LuaMetaMethod<T>
+        where R: LuaReturn, F: Fn(&mut T, &mut State) -> R + 'static {
+        LuaMetaMethod::raw("__call", move |state| {
+            let mut obj = match self_mut::<T>(state, "__call") {
+                Ok(obj) => obj,
+                Err(msg) => return Err(state.error_value(msg)),
+            };
+            protected(state, &mut |state| f(&mut obj, state).push_return(state))
+        })
+    }
+
+    /// `pairs(a)`, which should return an iterator function, a state
+    /// and an initial value.
+    pub fn pairs<R, F>(f: F) -> LuaMetaMethod<T>
+        where R: LuaReturn, F: Fn(&mut T, &mut State) -> R + 'static {
+        LuaMetaMethod::raw("__pairs", move |state| {
+            let mut obj = match self_mut::<T>(state, "__pairs") {
+                Ok(obj) => obj,
+                Err(msg) => return Err(state.error_value(msg)),
+            };
+            protected(state, &mut |state| f(&mut obj, state).push_return(state))
+        })
+    }
+
+    /// Called when a to-be-closed variable holding `a` goes out of scope.
+    /// Only Lua 5.4 and later call `__close`, on Lua 5.3 it is stored in
+    /// the metatable but never used.
+    pub fn close<F>(f: F) -> LuaMetaMethod<T> where F: Fn(&mut T) + 'static {
+        LuaMetaMethod::new("__close", move |state| {
+            let mut obj = self_mut::<T>(state, "__close")?;
+            f(&mut obj);
+            Ok(0)
+        })
+    }
+}
+
+/// Runs `body` on the arguments of the metamethod in a protected call,
+/// returning its results or the value it raised. Metamethods holding a
+/// borrow across code that can raise, such as calls back into Lua, use it
+/// so the borrow is released before the error is raised again.
+fn protected(state: &mut State, mut body: &mut Body) -> Result<c_int, LuaError> {
+    let l = state.as_ptr();
+
+    unsafe {
+        let nargs = lua_gettop(l);
+        if lua_checkstack(l, 2) == 0 {
+            return Err(LuaError::StackOverflow);
+        }
+
+        lua_pushlightuserdata(l, &mut body as *mut &mut Body as *mut c_void);
+        lua_pushcclosure(l, Some(run_protected), 1);
+        lua_insert(l, 1);
+
+        match lua_pcallk(l, nargs, LUA_MULTIRET, 0, 0, None) {
+            LUA_OK => Ok(lua_gettop(l)),
+            _ => {
+                let err = ErrorValue::capture(l, -1);
+                lua_settop(l, 0);
+                Err(LuaError::Value(err))
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn run_protected(l: *mut lua_State) -> c_int {
+    let res = {
+        let body = &mut **(lua_touserdata(l, lua_upvalueindex(1)) as *mut &mut Body);
+        body(&mut State::from_ptr(l))
+    };
+
+    match res {
+        Ok(n) => n,
+        Err(err) => error::raise_error(l, err),
+    }
+}
+
+/// Borrows the `T` a metamethod was called on.
+fn self_ref<'a, T>(state: &mut State, name: &str) -> Result<Ref<'a, T>, String> where T: LuaObject {
+    let obj = state.to::<LuaUserData<T>>(1).map_err(|err| arg_error(1, name, err))?;
+    unsafe { obj.borrow_unbound() }.map_err(|err| arg_error(1, name, err))
+}
+
+/// Mutably borrows the `T` a metamethod was called on.
+fn self_mut<'a, T>(state: &mut State, name: &str) -> Result<RefMut<'a, T>, String> where T: LuaObject {
+    let obj = state.to::<LuaUserData<T>>(1).map_err(|err| arg_error(1, name, err))?;
+    unsafe { obj.borrow_mut_unbound() }.map_err(|err| arg_error(1, name, err))
+}
+
+fn arg_error(idx: c_int, name: &str, err: LuaError) -> String {
+    format!("bad argument #{} to '{}' ({})", idx, name, err)
+}
+
+fn operand<'a, T, V>(state: &mut State, idx: c_int, name: &str) -> Result<Operand<'a, T, V>, String>
+    where T: LuaObject, V: FromLua {
+    if let Ok(obj) = state.to::<LuaUserData<T>>(idx) {
+        return unsafe { obj.borrow_unbound() }
+            .map(Operand::Object)
+            .map_err(|err| arg_error(idx, name, err));
+    }
+
+    V::from_lua(state, idx).map(Operand::Value).map_err(|err| {
+        format!("bad operand #{} to '{}' on {} ({})", idx, name, userdata::type_name::<T>(), err)
+    })
+}
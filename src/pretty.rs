@@ -0,0 +1,210 @@
+//! Formats Lua values for people to read, as printed by the REPL and by
+//! failed test assertions.
+
+use std::os::raw::c_schar;
+use std::str;
+
+use libc::{c_int, c_void};
+
+use super::ffi::*;
+use super::value::LuaType;
+use super::State;
+
+/// Tables nested deeper than this are printed as `{...}`.
+const MAX_DEPTH: usize = 32;
+
+/// Formats the value at `idx` for display. Tables are printed with their
+/// contents, as `{ 1, 2, name = "value" }`, and a table containing itself
+/// prints `<cycle>` where it recurs. Values with a `__tostring` metamethod
+/// are printed with it, and other userdata by the `__name` of their
+/// metatable.
+pub fn pretty(state: &mut State, idx: c_int) -> String {
+    let idx = state.absindex(idx);
+    let mut out = String::new();
+    write_value(state, idx, &mut Vec::new(), &mut out);
+
+    out
+}
+
+fn write_value(state: &mut State, idx: c_int, path: &mut Vec<*const c_void>, out: &mut String) {
+    match state.type_of(idx) {
+        LuaType::String => write_quoted(state.to_bytes(idx).unwrap_or_default(), out),
+        LuaType::Table if !has_metafield(state, idx, b"__tostring\x00") => write_table(state, idx, path, out),
+        _ => out.push_str(&tostring(state, idx)),
+    }
+}
+
+fn write_table(state: &mut State, idx: c_int, path: &mut Vec<*const c_void>, out: &mut String) {
+    let l = state.as_ptr();
+    let ptr = unsafe { lua_topointer(l, idx) };
+    if path.contains(&ptr) {
+        out.push_str("<cycle>");
+        return;
+    }
+    if path.len() >= MAX_DEPTH {
+        out.push_str("{...}");
+        return;
+    }
+    path.push(ptr);
+
+    let mut items = Vec::new();
+    let mut len = 0;
+    loop {
+        state.checkstack(1);
+        if unsafe { lua_rawgeti(l, idx, len + 1) } == LUA_TNIL {
+            state.pop(1);
+            break;
+        }
+
+        let mut item = String::new();
+        let top = state.gettop();
+        write_value(state, top, path, &mut item);
+        items.push(item);
+        state.pop(1);
+        len += 1;
+    }
+
+    let mut fields = Vec::new();
+    state.push_nil();
+    while unsafe { lua_next(l, idx) } != 0 {
+        let (key, value) = (state.gettop() - 1, state.gettop());
+        let in_sequence = state.is_integer(key) && state.to_long(key).is_some_and(|i| (1..=len).contains(&i));
+        if !in_sequence {
+            let mut field = String::new();
+            write_key(state, key, path, &mut field);
+            field.push_str(" = ");
+            write_value(state, value, path, &mut field);
+            fields.push(field);
+        }
+        state.pop(1);
+    }
+    fields.sort();
+
+    path.pop();
+    items.extend(fields);
+    if items.is_empty() {
+        out.push_str("{}");
+    } else {
+        out.push_str("{ ");
+        out.push_str(&items.join(", "));
+        out.push_str(" }");
+    }
+}
+
+fn write_key(state: &mut State, idx: c_int, path: &mut Vec<*const c_void>, out: &mut String) {
+    // Reading a number key as a string would convert it in place and break
+    // the traversal.
+    if state.type_of(idx) == LuaType::String {
+        let name = state.to_str(idx).unwrap_or_default();
+        if is_name(name) {
+            out.push_str(name);
+            return;
+        }
+    }
+
+    out.push('[');
+    write_value(state, idx, path, out);
+    out.push(']');
+}
+
+/// Tests if `s` can be written as a field name, without brackets.
+fn is_name(s: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+        "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+    ];
+
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c == '_' || c.is_ascii_alphanumeric()) && !KEYWORDS.contains(&s)
+}
+
+fn write_quoted(bytes: &[u8], out: &mut String) {
+    out.push('"');
+    match str::from_utf8(bytes) {
+        Ok(s) => {
+            for c in s.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    '\t' => out.push_str("\\t"),
+                    c if c.is_control() => out.push_str(&format!("\\{}", c as u32)),
+                    c => out.push(c),
+                }
+            }
+        }
+        Err(_) => {
+            for &b in bytes {
+                match b {
+                    b'"' => out.push_str("\\\""),
+                    b'\\' => out.push_str("\\\\"),
+                    0x20..=0x7e => out.push(b as char),
+                    b => out.push_str(&format!("\\{}", b)),
+                }
+            }
+        }
+    }
+    out.push('"');
+}
+
+fn has_metafield(state: &mut State, idx: c_int, name: &[u8]) -> bool {
+    unsafe {
+        let l = state.as_ptr();
+        if luaL_getmetafield(l, idx, name.as_ptr() as *const c_schar) == LUA_TNIL {
+            false
+        } else {
+            lua_settop(l, -2);
+            true
+        }
+    }
+}
+
+/// Converts the value at `idx` to a string like Lua's `tostring`, but
+/// catching errors raised by `__tostring`.
+fn tostring(state: &mut State, idx: c_int) -> String {
+    let l = state.as_ptr();
+    state.checkstack(2);
+
+    unsafe {
+        if luaL_getmetafield(l, idx, b"__tostring\x00".as_ptr() as *const c_schar) != LUA_TNIL {
+            lua_pushvalue(l, idx);
+            let res = match lua_pcallk(l, 1, 1, 0, 0, None) {
+                LUA_OK if lua_type(l, -1) == LUA_TSTRING => state.to_str(-1).unwrap_or_default().to_owned(),
+                LUA_OK => "<__tostring must return a string>".to_owned(),
+                _ => format!("<__tostring failed: {}>", state.to_str(-1).unwrap_or_default()),
+            };
+            lua_settop(l, -2);
+
+            return res;
+        }
+
+        match state.type_of(idx) {
+            LuaType::Nil | LuaType::None => "nil".to_owned(),
+            LuaType::Boolean => state.to_bool(idx).unwrap_or_default().to_string(),
+            LuaType::Number => {
+                lua_pushvalue(l, idx);
+                let res = state.to_str(-1).unwrap_or_default().to_owned();
+                lua_settop(l, -2);
+
+                res
+            }
+            ty => {
+                let name = if luaL_getmetafield(l, idx, b"__name\x00".as_ptr() as *const c_schar) != LUA_TNIL {
+                    let name = state.to_str(-1).map(str::to_owned);
+                    lua_settop(l, -2);
+                    name
+                } else {
+                    None
+                };
+
+                format!("{}: {:p}", name.unwrap_or_else(|| ty.name().to_owned()), lua_topointer(l, idx))
+            }
+        }
+    }
+}
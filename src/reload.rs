@@ -0,0 +1,319 @@
+//! Reloads script files when they change on disk, so scripts can be edited
+//! while the host runs.
+//!
+//! Once a [`Reloader`](struct.Reloader.html) was created, files loaded with
+//! [`State::load_file`](../state/struct.State.html#method.load_file) or
+//! [`State::do_file`](../state/struct.State.html#method.do_file) are
+//! remembered with their modification time, and polling the reloader from
+//! the host's main loop runs them again once they change:
+//!
+//! ```no_run
+//! extern crate lua_ffi;
+//!
+//! use std::path::Path;
+//!
+//! use lua_ffi::reload::Reloader;
+//! use lua_ffi::State;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     let reloader = Reloader::new().preserve("Game");
+//!     state.do_file(Path::new("scripts/game.lua")).unwrap();
+//!
+//!     loop {
+//!         for reload in reloader.poll(&mut state) {
+//!             if let Err(err) = reload.result {
+//!                 eprintln!("cannot reload {}: {}", reload.path.display(), err);
+//!             }
+//!         }
+//!         // Update and draw a frame...
+//!     }
+//! }
+//! ```
+//!
+//! A file is compiled before anything of it runs, so a version with a
+//! syntax error leaves the previous one running. Files are polled by their
+//! modification time, which needs no file system notifications. A file is
+//! reloaded through the path it was first loaded with, so a relative path
+//! must still lead to it.
+
+use std::fs;
+use std::os::raw::c_schar;
+use std::path::{Path, PathBuf};
+use std::{ptr, slice};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::UNIX_EPOCH;
+
+use libc::c_int;
+
+use super::error::LuaError;
+use super::ffi::*;
+use super::value::LuaType;
+use super::State;
+
+/// Registry field holding the modification time of every loaded file, by
+/// canonical path.
+const FILES_FIELD: &[u8] = b"lua_ffi.files\x00";
+
+/// Registry field holding the path every file was loaded with, by
+/// canonical path.
+const NAMES_FIELD: &[u8] = b"lua_ffi.file_names\x00";
+
+/// Set by the first [`Reloader`](struct.Reloader.html), files loaded before
+/// are not remembered.
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// Returns the canonical path the file at `path` is remembered by, or
+/// `None` if no reloader was created or the file does not exist.
+pub(crate) fn watched(path: &Path) -> Option<PathBuf> {
+    if !WATCHING.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    path.canonicalize().ok()
+}
+
+/// Remembers that the file at `canonical` was loaded as `path`, and
+/// `modified`, its modification time read before it was loaded, so a
+/// change made while it loads is noticed.
+pub(crate) fn record(l: *mut lua_State, canonical: &Path, path: &Path, modified: Option<i64>) {
+    let modified = modified.unwrap_or(-1);
+    let canonical = canonical.to_string_lossy();
+    let path = path.to_string_lossy();
+
+    unsafe {
+        lua_checkstack(l, 3);
+        push_table(l, FILES_FIELD);
+        lua_pushlstring(l, canonical.as_ptr() as *const c_schar, canonical.len());
+        lua_pushinteger(l, modified);
+        lua_rawset(l, -3);
+        lua_settop(l, -2);
+
+        push_table(l, NAMES_FIELD);
+        lua_pushlstring(l, canonical.as_ptr() as *const c_schar, canonical.len());
+        lua_pushlstring(l, path.as_ptr() as *const c_schar, path.len());
+        lua_rawset(l, -3);
+        lua_settop(l, -2);
+    }
+}
+
+/// Returns the path the file at `canonical` was loaded with.
+fn loaded_as(l: *mut lua_State, canonical: &Path) -> Option<PathBuf> {
+    let canonical = canonical.to_string_lossy();
+
+    unsafe {
+        lua_checkstack(l, 2);
+        push_table(l, NAMES_FIELD);
+        lua_pushlstring(l, canonical.as_ptr() as *const c_schar, canonical.len());
+        let path = if lua_rawget(l, -2) == LUA_TSTRING {
+            let mut len = 0;
+            let ptr = lua_tolstring(l, -1, &mut len);
+            Some(PathBuf::from(String::from_utf8_lossy(slice::from_raw_parts(ptr as *const u8, len)).into_owned()))
+        } else {
+            None
+        };
+        lua_settop(l, -3);
+
+        path
+    }
+}
+
+/// Returns the modification time of the file at `path`, in nanoseconds.
+pub(crate) fn modified(path: &Path) -> Option<i64> {
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok()?;
+    modified.duration_since(UNIX_EPOCH).ok().map(|time| time.as_nanos() as i64)
+}
+
+/// Pushes the registry table in `field`, creating it the first time.
+unsafe fn push_table(l: *mut lua_State, field: &[u8]) {
+    if lua_getfield(l, LUA_REGISTRYINDEX, field.as_ptr() as *const c_schar) != LUA_TTABLE {
+        lua_settop(l, -2);
+        lua_createtable(l, 0, 0);
+        lua_pushvalue(l, -1);
+        lua_setfield(l, LUA_REGISTRYINDEX, field.as_ptr() as *const c_schar);
+    }
+}
+
+/// Returns the loaded files modified since they were loaded.
+fn changed_files(l: *mut lua_State) -> Vec<PathBuf> {
+    let mut changed = Vec::new();
+
+    unsafe {
+        lua_checkstack(l, 3);
+        push_table(l, FILES_FIELD);
+        lua_pushnil(l);
+        while lua_next(l, -2) != 0 {
+            if lua_type(l, -2) == LUA_TSTRING {
+                let mut len = 0;
+                let ptr = lua_tolstring(l, -2, &mut len);
+                let path = PathBuf::from(String::from_utf8_lossy(slice::from_raw_parts(ptr as *const u8, len)).into_owned());
+
+                let loaded = lua_tointegerx(l, -1, ptr::null_mut());
+                if modified(&path).is_some_and(|modified| modified != loaded) {
+                    changed.push(path);
+                }
+            }
+            lua_settop(l, -2);
+        }
+        lua_settop(l, -2);
+    }
+
+    changed.sort();
+    changed
+}
+
+/// Runs script files again when they change.
+///
+/// Only files loaded after the first reloader was created are watched.
+///
+/// The tables in the globals named with [`preserve`](#method.preserve)
+/// survive a reload: when the new version of a file assigns a new table to
+/// one of them, its functions and the fields the old table lacks are copied
+/// into the old table, which is put back. Data already in the old table is
+/// kept, and everything holding on to the table sees the new functions.
+///
+/// A file may define a global function `on_reload`, which is called after
+/// the new version of the file ran and the tables were preserved. The
+/// global is cleared while the file runs, so one file's callback does not
+/// run when another file is reloaded, and restored afterwards unless the
+/// file defined a new one.
+pub struct Reloader {
+    preserved: Vec<String>,
+}
+
+/// A file run again by [`Reloader::poll`](struct.Reloader.html#method.poll).
+#[derive(Debug)]
+pub struct Reload {
+    /// The canonical path of the file.
+    pub path: PathBuf,
+    /// Whether the new version of the file loaded and ran.
+    pub result: Result<(), LuaError>,
+}
+
+impl Reloader {
+    /// Creates a reloader preserving no tables, and starts remembering the
+    /// files loaded from now on.
+    pub fn new() -> Reloader {
+        WATCHING.store(true, Ordering::Relaxed);
+        Reloader {
+            preserved: Vec::new(),
+        }
+    }
+
+    /// Preserves the table in the global `name` when files are reloaded.
+    pub fn preserve(mut self, name: &str) -> Reloader {
+        self.preserved.push(name.to_owned());
+        self
+    }
+
+    /// Reloads every file modified since it was last loaded, returning the
+    /// outcome for each of them. A file which fails to reload is not tried
+    /// again until it changes again.
+    pub fn poll(&self, state: &mut State) -> Vec<Reload> {
+        changed_files(state.as_ptr()).into_iter().map(|path| {
+            let result = self.reload(state, &path);
+            Reload { path, result }
+        }).collect()
+    }
+
+    /// Loads and runs the file at `path` again, whether it changed or not.
+    /// Its results are discarded.
+    ///
+    /// A version that does not compile is not run, and the error is
+    /// returned as a `LuaError::Syntax`. A version that raises an error may
+    /// have run in part. A file loaded before is opened through the path it
+    /// was loaded with, so errors keep naming it the same way.
+    pub fn reload(&self, state: &mut State, path: &Path) -> Result<(), LuaError> {
+        let top = state.gettop();
+        let canonical = path.canonicalize().ok();
+        let loaded = canonical.as_ref().and_then(|path| modified(path));
+        let name = canonical.as_ref()
+            .and_then(|canonical| loaded_as(state.as_ptr(), canonical))
+            .unwrap_or_else(|| path.to_owned());
+
+        if let Err(err) = state.load_file(&name) {
+            if let Some(canonical) = canonical {
+                record(state.as_ptr(), &canonical, &name, loaded);
+            }
+
+            return Err(err);
+        }
+
+        let chunk = state.gettop();
+        for name in &self.preserved {
+            state.get_global(name);
+        }
+        state.get_global("on_reload");
+        state.push_nil();
+        state.set_global("on_reload");
+
+        state.push_value(chunk);
+        let res = state.pcall_with_traceback(0, 0);
+
+        for (i, name) in self.preserved.iter().enumerate() {
+            keep(state, name, chunk + 1 + i as c_int);
+        }
+
+        let previous = chunk + 1 + self.preserved.len() as c_int;
+        state.get_global("on_reload");
+        if state.type_of(-1) == LuaType::Nil {
+            state.push_value(previous);
+            state.set_global("on_reload");
+        }
+
+        let res = match res {
+            Ok(()) if state.type_of(-1) == LuaType::Function => state.pcall_with_traceback(0, 0),
+            res => res,
+        };
+        state.settop(top);
+
+        res
+    }
+}
+
+impl Default for Reloader {
+    fn default() -> Reloader {
+        Reloader::new()
+    }
+}
+
+/// Puts the table at `old` back into the global `name` if the new version
+/// of a file replaced it, after merging the new table into it.
+fn keep(state: &mut State, name: &str, old: c_int) {
+    if state.type_of(old) != LuaType::Table {
+        return;
+    }
+
+    state.get_global(name);
+    let l = state.as_ptr();
+    unsafe {
+        if lua_type(l, -1) == LUA_TTABLE && lua_rawequal(l, old, -1) == 0 {
+            let new = lua_gettop(l);
+            merge(l, new, old);
+            lua_pushvalue(l, old);
+            state.set_global(name);
+        }
+    }
+    state.pop(1);
+}
+
+/// Copies the functions of the table at `from` into the one at `into`, and
+/// the other fields `into` does not have.
+unsafe fn merge(l: *mut lua_State, from: c_int, into: c_int) {
+    lua_checkstack(l, 4);
+    lua_pushnil(l);
+    while lua_next(l, from) != 0 {
+        let is_function = lua_type(l, -1) == LUA_TFUNCTION;
+        lua_pushvalue(l, -2);
+        let exists = lua_rawget(l, into) != LUA_TNIL;
+        lua_settop(l, -2);
+
+        if is_function || !exists {
+            lua_pushvalue(l, -2);
+            lua_pushvalue(l, -2);
+            lua_rawset(l, into);
+        }
+        lua_settop(l, -2);
+    }
+}
@@ -0,0 +1,174 @@
+//! An interactive prompt running Lua code on a [`State`](../state/struct.State.html),
+//! behind the `repl` feature.
+//!
+//! The `lua-ffi-repl` binary runs a prompt on a state with the standard
+//! library. To poke at a state with your own bindings loaded, build a
+//! [`Repl`](struct.Repl.html) on it from your own binary:
+//!
+//! ```no_run
+//! extern crate lua_ffi;
+//!
+//! use std::io;
+//!
+//! use lua_ffi::repl::Repl;
+//! use lua_ffi::{c_int, State};
+//! use lua_ffi::ffi::lua_State;
+//!
+//! unsafe extern "C" fn open_game(l: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(l);
+//!     state.new_table();
+//!     state.push(60);
+//!     state.set_field(-2, "fps");
+//!
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!
+//!     let mut repl = Repl::new(state);
+//!     repl.preload("game", open_game);
+//!
+//!     let stdin = io::stdin();
+//!     repl.run(stdin.lock(), io::stdout()).unwrap();
+//! }
+//! ```
+
+use std::io::{self, BufRead, Write};
+use std::mem;
+
+use libc::c_int;
+
+use super::error::LuaError;
+use super::ffi::*;
+use super::types::LuaFunction;
+use super::State;
+
+pub use super::pretty::pretty;
+
+/// The chunk name of lines typed at the prompt.
+const CHUNK: &str = "=stdin";
+
+/// The outcome of a line of input.
+#[derive(Debug)]
+pub enum Eval {
+    /// The input so far is an incomplete chunk, such as an unclosed
+    /// `function`; the next lines are appended to it.
+    Incomplete,
+    /// The chunk ran and returned these values, pretty-printed.
+    Values(Vec<String>),
+    /// The chunk could not be loaded, or raised an error.
+    Error(LuaError),
+}
+
+/// A read-eval-print loop on a `State`.
+///
+/// Each line is first tried as an expression, so `1 + 1` prints `2`.
+/// Otherwise it is run as a statement, and lines are accumulated for as
+/// long as they form an incomplete chunk.
+pub struct Repl {
+    state: State,
+    pending: String,
+}
+
+impl Repl {
+    /// Creates a prompt on `state`, which may already have bindings and
+    /// libraries loaded.
+    pub fn new(state: State) -> Repl {
+        Repl {
+            state,
+            pending: String::new(),
+        }
+    }
+
+    /// Returns the state the prompt runs code on.
+    pub fn state(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    /// Registers `open` as the loader of the module `name`, which the
+    /// prompt then loads with `require(name)`. Requires the package
+    /// library.
+    pub fn preload(&mut self, name: &str, open: LuaFunction) {
+        let l = self.state.as_ptr();
+        self.state.get_global("package");
+        self.state.get_field(-1, "preload");
+
+        unsafe {
+            if lua_type(l, -1) != LUA_TTABLE {
+                lua_settop(l, -3);
+                panic!("cannot preload '{}' without the package library", name);
+            }
+        }
+
+        self.state.push(open);
+        self.state.set_field(-2, name);
+        self.state.pop(2);
+    }
+
+    /// Returns whether previous lines are waiting for the rest of an
+    /// incomplete chunk.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Runs a line of input.
+    pub fn eval(&mut self, line: &str) -> Eval {
+        let top = self.state.gettop();
+
+        if self.pending.is_empty() {
+            let expr = format!("return {}", line);
+            if self.state.load_chunk(expr.as_bytes(), CHUNK).is_ok() {
+                return self.call(top);
+            }
+        } else {
+            self.pending.push('\n');
+        }
+        self.pending.push_str(line);
+
+        let code = mem::take(&mut self.pending);
+        match self.state.load_chunk(code.as_bytes(), CHUNK) {
+            Ok(()) => self.call(top),
+            Err(LuaError::Syntax(ref err)) if err.incomplete_input => {
+                self.pending = code;
+                Eval::Incomplete
+            }
+            Err(err) => Eval::Error(err),
+        }
+    }
+
+    /// Prompts for lines on `input` until it ends, writing results and
+    /// errors to `output`.
+    pub fn run<R, W>(&mut self, input: R, mut output: W) -> io::Result<()> where R: BufRead, W: Write {
+        let mut lines = input.lines();
+
+        loop {
+            output.write_all(if self.is_pending() { b">> " } else { b"> " })?;
+            output.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return writeln!(output),
+            };
+
+            match self.eval(&line) {
+                Eval::Incomplete => {}
+                Eval::Values(ref values) if values.is_empty() => {}
+                Eval::Values(values) => writeln!(output, "{}", values.join("\t"))?,
+                Eval::Error(err) => writeln!(output, "{}", err)?,
+            }
+        }
+    }
+
+    fn call(&mut self, top: c_int) -> Eval {
+        match self.state.pcall_with_traceback(0, LUA_MULTIRET) {
+            Ok(()) => {
+                let values = (top + 1..=self.state.gettop()).map(|i| pretty(&mut self.state, i)).collect();
+                self.state.settop(top);
+                Eval::Values(values)
+            }
+            Err(err) => Eval::Error(err),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Restrictions on the Lua code a state runs: a cap on the memory it may
+//! allocate, a budget of instructions, and refusing precompiled chunks.
+
+use std::os::raw::c_schar;
+use std::ptr;
+
+use libc::{c_int, c_void, size_t};
+
+use super::ffi::*;
+
+/// Registry field holding the `Allocator` of a state with a memory limit.
+const ALLOCATOR_FIELD: &[u8] = b"lua_ffi.allocator\x00";
+/// Registry field set once the instruction limit has been hit.
+const LIMIT_HIT_FIELD: &[u8] = b"lua_ffi.instruction_limit_hit\x00";
+/// Registry field set once binary chunks are refused.
+const TEXT_ONLY_FIELD: &[u8] = b"lua_ffi.text_only\x00";
+
+/// The error raised by Lua code running past its instruction limit.
+const INSTRUCTION_LIMIT_MESSAGE: &str = "instruction limit exceeded";
+
+/// Wraps the allocator of a state, counting the bytes in use and failing
+/// allocations that would take them over `limit`.
+struct Allocator {
+    inner: lua_Alloc,
+    inner_ud: *mut c_void,
+    used: usize,
+    limit: usize,
+}
+
+/// Caps the memory of the state at `limit` bytes, installing the counting
+/// allocator the first time.
+pub(crate) fn set_memory_limit(l: *mut lua_State, limit: Option<usize>) {
+    let limit = limit.unwrap_or(usize::MAX);
+
+    unsafe {
+        let alloc = allocator(l);
+        if !alloc.is_null() {
+            (*alloc).limit = limit;
+            return;
+        }
+
+        let mut inner_ud = ptr::null_mut();
+        let inner = lua_getallocf(l, &mut inner_ud);
+        let used = memory_used(l);
+        let alloc = Box::into_raw(Box::new(Allocator { inner, inner_ud, used, limit }));
+
+        lua_pushlightuserdata(l, alloc as *mut c_void);
+        lua_setfield(l, LUA_REGISTRYINDEX, ALLOCATOR_FIELD.as_ptr() as *const c_schar);
+        lua_setallocf(l, Some(limited_alloc), alloc as *mut c_void);
+    }
+}
+
+/// Returns the bytes the state has allocated.
+pub(crate) fn memory_used(l: *mut lua_State) -> usize {
+    unsafe {
+        lua_gc(l, LUA_GCCOUNT, 0) as usize * 1024 + lua_gc(l, LUA_GCCOUNTB, 0) as usize
+    }
+}
+
+/// Closes the state, then frees its counting allocator if it has one.
+pub(crate) unsafe fn close(l: *mut lua_State) {
+    // The stack may be full, and its values are about to be freed anyway.
+    lua_settop(l, 0);
+    let alloc = allocator(l);
+    lua_close(l);
+
+    if !alloc.is_null() {
+        drop(Box::from_raw(alloc));
+    }
+}
+
+unsafe fn allocator(l: *mut lua_State) -> *mut Allocator {
+    lua_getfield(l, LUA_REGISTRYINDEX, ALLOCATOR_FIELD.as_ptr() as *const c_schar);
+    let alloc = lua_touserdata(l, -1) as *mut Allocator;
+    lua_settop(l, -2);
+
+    alloc
+}
+
+unsafe extern "C" fn limited_alloc(ud: *mut c_void, ptr: *mut c_void, osize: size_t, nsize: size_t) -> *mut c_void {
+    let alloc = &mut *(ud as *mut Allocator);
+    // Without a block, `osize` encodes the type of the new object rather
+    // than a size.
+    let old = if ptr.is_null() { 0 } else { osize };
+    let used = alloc.used.saturating_sub(old);
+    if nsize > old && used.saturating_add(nsize) > alloc.limit {
+        return ptr::null_mut();
+    }
+
+    let res = match alloc.inner {
+        Some(inner) => inner(alloc.inner_ud, ptr, osize, nsize),
+        None => return ptr::null_mut(),
+    };
+    if !res.is_null() || nsize == 0 {
+        alloc.used = used + nsize;
+    }
+
+    res
+}
+
+/// Makes Lua code on the state raise an error after running `limit` more
+/// instructions, or lifts the limit.
+pub(crate) fn set_instruction_limit(l: *mut lua_State, limit: Option<c_int>) {
+    unsafe {
+        lua_pushnil(l);
+        lua_setfield(l, LUA_REGISTRYINDEX, LIMIT_HIT_FIELD.as_ptr() as *const c_schar);
+
+        match limit {
+            Some(limit) => lua_sethook(l, Some(count_hook), LUA_MASKCOUNT, limit),
+            None => lua_sethook(l, None, 0, 0),
+        };
+    }
+}
+
+/// Returns whether Lua code hit the instruction limit since the last call.
+pub(crate) fn take_limit_hit(l: *mut lua_State) -> bool {
+    unsafe {
+        lua_getfield(l, LUA_REGISTRYINDEX, LIMIT_HIT_FIELD.as_ptr() as *const c_schar);
+        let hit = lua_toboolean(l, -1) != 0;
+        lua_settop(l, -2);
+
+        if hit {
+            lua_pushnil(l);
+            lua_setfield(l, LUA_REGISTRYINDEX, LIMIT_HIT_FIELD.as_ptr() as *const c_schar);
+        }
+
+        hit
+    }
+}
+
+extern "C" fn count_hook(l: *mut lua_State, _ar: *mut lua_Debug) {
+    unsafe {
+        lua_pushboolean(l, 1);
+        lua_setfield(l, LUA_REGISTRYINDEX, LIMIT_HIT_FIELD.as_ptr() as *const c_schar);
+
+        // From now on every instruction raises the error again, so code
+        // catching it with `pcall` cannot carry on.
+        lua_sethook(l, Some(count_hook), LUA_MASKCOUNT, 1);
+
+        lua_pushlstring(l, INSTRUCTION_LIMIT_MESSAGE.as_ptr() as *const c_schar, INSTRUCTION_LIMIT_MESSAGE.len());
+        lua_error(l);
+    }
+}
+
+/// Makes the state refuse binary chunks, in the chunks loaded from Rust
+/// and in the `load`, `loadfile` and `dofile` functions of the basic
+/// library if it is open.
+pub(crate) fn refuse_binary_chunks(l: *mut lua_State) {
+    unsafe {
+        lua_checkstack(l, 3);
+        lua_pushboolean(l, 1);
+        lua_setfield(l, LUA_REGISTRYINDEX, TEXT_ONLY_FIELD.as_ptr() as *const c_schar);
+
+        lua_rawgeti(l, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS);
+        wrap_loader(l, b"load\x00", 3);
+        wrap_loader(l, b"loadfile\x00", 2);
+        if lua_getfield(l, -1, b"dofile\x00".as_ptr() as *const c_schar) == LUA_TFUNCTION {
+            lua_pushcfunction(l, Some(text_dofile));
+            lua_setfield(l, -3, b"dofile\x00".as_ptr() as *const c_schar);
+        }
+        lua_settop(l, -3);
+    }
+}
+
+/// Returns the mode to load chunks with, as taken by `luaL_loadbufferx`.
+pub(crate) fn load_mode(l: *mut lua_State) -> *const c_schar {
+    unsafe {
+        lua_getfield(l, LUA_REGISTRYINDEX, TEXT_ONLY_FIELD.as_ptr() as *const c_schar);
+        let text_only = lua_toboolean(l, -1) != 0;
+        lua_settop(l, -2);
+
+        if text_only {
+            b"t\x00".as_ptr() as *const c_schar
+        } else {
+            ptr::null()
+        }
+    }
+}
+
+/// Replaces the function `name` of the table on top of the stack by one
+/// calling it with `"t"` as its argument `mode_arg`.
+unsafe fn wrap_loader(l: *mut lua_State, name: &[u8], mode_arg: lua_Integer) {
+    if lua_getfield(l, -1, name.as_ptr() as *const c_schar) == LUA_TFUNCTION {
+        lua_pushinteger(l, mode_arg);
+        lua_pushcclosure(l, Some(text_loader), 2);
+        lua_setfield(l, -2, name.as_ptr() as *const c_schar);
+    } else {
+        lua_settop(l, -2);
+    }
+}
+
+unsafe extern "C" fn text_loader(l: *mut lua_State) -> c_int {
+    let mode_arg = lua_tointegerx(l, lua_upvalueindex(2), ptr::null_mut()) as c_int;
+    let nargs = lua_gettop(l).max(mode_arg);
+    luaL_checkstack(l, nargs + 2, ptr::null());
+
+    lua_settop(l, nargs);
+    lua_pushstring(l, b"t\x00".as_ptr() as *const c_schar);
+    lua_replace(l, mode_arg);
+    lua_pushvalue(l, lua_upvalueindex(1));
+    lua_insert(l, 1);
+    lua_callk(l, nargs, LUA_MULTIRET, 0, None);
+
+    lua_gettop(l)
+}
+
+unsafe extern "C" fn text_dofile(l: *mut lua_State) -> c_int {
+    let name = luaL_optlstring(l, 1, ptr::null(), ptr::null_mut());
+    lua_settop(l, 1);
+    if luaL_loadfilex(l, name, b"t\x00".as_ptr() as *const c_schar) != LUA_OK {
+        return lua_error(l);
+    }
+    lua_callk(l, 0, LUA_MULTIRET, 0, None);
+
+    lua_gettop(l) - 1
+}
@@ -0,0 +1,80 @@
+//! Userdata borrowing Rust values for a limited scope.
+
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+use libc::c_int;
+
+use super::ffi::*;
+use super::types::LuaObject;
+use super::userdata::{self, Slot};
+use super::State;
+
+/// Invalidates the userdata on the top of the stack.
+type Expire = unsafe fn(*mut lua_State);
+
+/// Pushes references to Rust values as userdata, for the duration of a
+/// call to [`State::scope`](state/struct.State.html#method.scope).
+///
+/// A `Scope` dereferences to the [`State`](state/struct.State.html) it was
+/// created from, so the rest of the stack API is available on it.
+pub struct Scope<'scope> {
+    state: State,
+    // Registry references keeping the borrowing userdata alive, and the
+    // function expiring each of them
+    borrowed: Vec<(c_int, Expire)>,
+    marker: PhantomData<&'scope mut ()>,
+}
+
+impl<'scope> Scope<'scope> {
+    pub(crate) fn new(l: *mut lua_State) -> Scope<'scope> {
+        Scope {
+            state: State::from_ptr(l),
+            borrowed: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Pushes a userdata referring to `val` instead of owning it. It has
+    /// the same metatable as a pushed `T`, so fields, methods and
+    /// metamethods work as usual, and it is never dropped by Lua.
+    pub fn push_ref<T>(&mut self, val: &'scope mut T) where T: LuaObject {
+        self.state.push_slot(Slot::Borrowed(val as *mut T));
+
+        unsafe {
+            let l = self.state.as_ptr();
+            lua_pushvalue(l, -1);
+            let r = luaL_ref(l, LUA_REGISTRYINDEX);
+            self.borrowed.push((r, userdata::expire::<T>));
+        }
+    }
+}
+
+impl<'scope> Deref for Scope<'scope> {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        &self.state
+    }
+}
+
+impl<'scope> DerefMut for Scope<'scope> {
+    fn deref_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+}
+
+impl<'scope> Drop for Scope<'scope> {
+    fn drop(&mut self) {
+        let l = self.state.as_ptr();
+
+        for (r, expire) in self.borrowed.drain(..) {
+            unsafe {
+                lua_rawgeti(l, LUA_REGISTRYINDEX, r as lua_Integer);
+                expire(l);
+                lua_pop(l, 1);
+                luaL_unref(l, LUA_REGISTRYINDEX, r);
+            }
+        }
+    }
+}
@@ -0,0 +1,846 @@
+//! Conversions between serde data types and Lua values, used by
+//! `State::to_lua` and `State::from_lua` when the `serde` feature is enabled.
+//!
+//! Structs and maps become tables keyed by field name or map key, sequences
+//! and tuples become arrays starting at 1, `None` and `()` become `nil`, and
+//! enums are externally tagged: a unit variant is the string of its name
+//! and any other variant is a table holding its content under its name,
+//! such as `{ Attack = { damage = 3 } }`.
+
+use std::ffi::CStr;
+use std::fmt::Display;
+use std::os::raw::{c_char, c_schar};
+use std::{ptr, slice, str};
+
+use libc::c_int;
+use serde::{de, ser};
+use serde::de::IntoDeserializer;
+
+use super::error::{within, LuaError, Segment};
+use super::ffi::*;
+
+// Stack slots used by one level of nesting: a table, a key and a value.
+const LEVEL_SLOTS: c_int = 3;
+
+/// How deep tables may be nested in a value read from Lua. Reading a table
+/// that contains itself stops here rather than overflowing the Rust stack.
+const MAX_DEPTH: usize = 128;
+
+/// Pushes `val` onto the stack, leaving the stack untouched on error.
+pub(crate) fn to_lua<T>(l: *mut lua_State, val: &T) -> Result<(), LuaError> where T: ser::Serialize + ?Sized {
+    let top = unsafe { lua_gettop(l) };
+    let res = val.serialize(Serializer { l });
+    if res.is_err() {
+        unsafe { lua_settop(l, top) };
+    }
+
+    res
+}
+
+/// Reads the value at `idx` without removing it from the stack.
+pub(crate) fn from_lua<T>(l: *mut lua_State, idx: c_int) -> Result<T, LuaError> where T: de::DeserializeOwned {
+    unsafe {
+        let idx = lua_absindex(l, idx);
+        let top = lua_gettop(l);
+        let res = T::deserialize(Deserializer { l, idx, depth: 0 });
+        lua_settop(l, top);
+
+        res
+    }
+}
+
+fn conversion<T: Display>(msg: T) -> LuaError {
+    LuaError::Conversion {
+        path: String::new(),
+        message: msg.to_string(),
+    }
+}
+
+impl ser::Error for LuaError {
+    fn custom<T: Display>(msg: T) -> LuaError {
+        conversion(msg)
+    }
+}
+
+impl de::Error for LuaError {
+    fn custom<T: Display>(msg: T) -> LuaError {
+        conversion(msg)
+    }
+}
+
+fn reserve(l: *mut lua_State) -> Result<(), LuaError> {
+    if unsafe { lua_checkstack(l, LEVEL_SLOTS) } == 0 {
+        Err(conversion("stack overflow, value is nested too deeply"))
+    } else {
+        Ok(())
+    }
+}
+
+fn push_str(l: *mut lua_State, s: &str) {
+    unsafe { lua_pushlstring(l, s.as_ptr() as *const c_schar, s.len()) };
+}
+
+fn typename(l: *mut lua_State, idx: c_int) -> &'static str {
+    unsafe {
+        let name = CStr::from_ptr(luaL_typename(l, idx) as *const c_char);
+        name.to_str().unwrap_or("?")
+    }
+}
+
+/// Returns the bytes of the string at `idx`, which must be a string rather
+/// than a number so that `lua_tolstring` leaves it untouched.
+unsafe fn string_bytes<'a>(l: *mut lua_State, idx: c_int) -> &'a [u8] {
+    let mut len = 0;
+    let ptr = lua_tolstring(l, idx, &mut len);
+    slice::from_raw_parts(ptr as *const u8, len)
+}
+
+struct Serializer {
+    l: *mut lua_State,
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = ();
+    type Error = LuaError;
+    type SerializeSeq = Table;
+    type SerializeTuple = Table;
+    type SerializeTupleStruct = Table;
+    type SerializeTupleVariant = Table;
+    type SerializeMap = Table;
+    type SerializeStruct = Table;
+    type SerializeStructVariant = Table;
+
+    fn serialize_bool(self, v: bool) -> Result<(), LuaError> {
+        unsafe { lua_pushboolean(self.l, v as c_int) };
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), LuaError> {
+        unsafe { lua_pushinteger(self.l, v) };
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), LuaError> {
+        if v > i64::MAX as u64 {
+            return Err(conversion(format!("integer {} does not fit in a Lua integer", v)));
+        }
+
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), LuaError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), LuaError> {
+        unsafe { lua_pushnumber(self.l, v) };
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), LuaError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), LuaError> {
+        push_str(self.l, v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), LuaError> {
+        unsafe { lua_pushlstring(self.l, v.as_ptr() as *const c_schar, v.len()) };
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), LuaError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: ?Sized + ser::Serialize>(self, value: &T) -> Result<(), LuaError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), LuaError> {
+        unsafe { lua_pushnil(self.l) };
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), LuaError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), LuaError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + ser::Serialize>(self, _name: &'static str, value: &T) -> Result<(), LuaError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + ser::Serialize>(self, _name: &'static str, _index: u32,
+                                                             variant: &'static str, value: &T) -> Result<(), LuaError> {
+        reserve(self.l)?;
+        unsafe { lua_createtable(self.l, 0, 1) };
+        push_str(self.l, variant);
+        value.serialize(Serializer { l: self.l })
+            .map_err(|e| within(e, &Segment::Field(variant.to_owned())))?;
+        unsafe { lua_rawset(self.l, -3) };
+
+        Ok(())
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Table, LuaError> {
+        Table::new(self.l, len.unwrap_or(0), 0, None)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Table, LuaError> {
+        Table::new(self.l, len, 0, None)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Table, LuaError> {
+        Table::new(self.l, len, 0, None)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                               len: usize) -> Result<Table, LuaError> {
+        Table::new(self.l, len, 0, Some(variant))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Table, LuaError> {
+        Table::new(self.l, 0, len.unwrap_or(0), None)
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Table, LuaError> {
+        Table::new(self.l, 0, len, None)
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                                len: usize) -> Result<Table, LuaError> {
+        Table::new(self.l, 0, len, Some(variant))
+    }
+}
+
+/// A table under construction on top of the stack. For enum variants the
+/// table sits above an outer table and the variant name, and is stored
+/// into the outer one when it is finished.
+struct Table {
+    l: *mut lua_State,
+    len: i64,
+    variant: Option<&'static str>,
+    key: Option<Segment>,
+}
+
+impl Table {
+    fn new(l: *mut lua_State, narr: usize, nrec: usize, variant: Option<&'static str>) -> Result<Table, LuaError> {
+        reserve(l)?;
+        if let Some(name) = variant {
+            reserve(l)?;
+            unsafe { lua_createtable(l, 0, 1) };
+            push_str(l, name);
+        }
+        unsafe { lua_createtable(l, narr as c_int, nrec as c_int) };
+
+        Ok(Table { l, len: 0, variant, key: None })
+    }
+
+    fn annotate(&self, err: LuaError, seg: Option<&Segment>) -> LuaError {
+        let err = match seg {
+            Some(seg) => within(err, seg),
+            None => err,
+        };
+
+        match self.variant {
+            Some(name) => within(err, &Segment::Field(name.to_owned())),
+            None => err,
+        }
+    }
+
+    fn element<T: ?Sized + ser::Serialize>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.len += 1;
+        value.serialize(Serializer { l: self.l })
+            .map_err(|e| self.annotate(e, Some(&Segment::Index(self.len))))?;
+        unsafe { lua_rawseti(self.l, -2, self.len) };
+
+        Ok(())
+    }
+
+    fn field<T: ?Sized + ser::Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), LuaError> {
+        push_str(self.l, key);
+        value.serialize(Serializer { l: self.l })
+            .map_err(|e| self.annotate(e, Some(&Segment::Field(key.to_owned()))))?;
+        unsafe { lua_rawset(self.l, -3) };
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), LuaError> {
+        if self.variant.is_some() {
+            unsafe { lua_rawset(self.l, -3) };
+        }
+
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for Table {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_element<T: ?Sized + ser::Serialize>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for Table {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_element<T: ?Sized + ser::Serialize>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for Table {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_field<T: ?Sized + ser::Serialize>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for Table {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_field<T: ?Sized + ser::Serialize>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for Table {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_key<T: ?Sized + ser::Serialize>(&mut self, key: &T) -> Result<(), LuaError> {
+        key.serialize(Serializer { l: self.l }).map_err(|e| self.annotate(e, None))?;
+
+        unsafe {
+            let invalid = match lua_type(self.l, -1) {
+                LUA_TNIL => true,
+                LUA_TNUMBER => lua_tonumberx(self.l, -1, ptr::null_mut()).is_nan(),
+                _ => false,
+            };
+            if invalid {
+                return Err(self.annotate(conversion("map key cannot be nil or NaN"), None));
+            }
+        }
+        self.key = Segment::of_key(self.l, -1);
+
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + ser::Serialize>(&mut self, value: &T) -> Result<(), LuaError> {
+        let key = self.key.take();
+        value.serialize(Serializer { l: self.l }).map_err(|e| self.annotate(e, key.as_ref()))?;
+        unsafe { lua_rawset(self.l, -3) };
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for Table {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_field<T: ?Sized + ser::Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), LuaError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for Table {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_field<T: ?Sized + ser::Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), LuaError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+/// Reads the value at the absolute index `idx`, found inside `depth`
+/// tables. Values nested inside it are pushed above it while they are read
+/// and popped afterwards.
+struct Deserializer {
+    l: *mut lua_State,
+    idx: c_int,
+    depth: usize,
+}
+
+impl Deserializer {
+    fn ty(&self) -> c_int {
+        unsafe { lua_type(self.l, self.idx) }
+    }
+
+    fn mismatch(&self, expected: &str) -> LuaError {
+        conversion(format!("expected {}, got {}", expected, typename(self.l, self.idx)))
+    }
+
+    fn integer(&self) -> Result<i64, LuaError> {
+        if self.ty() == LUA_TNUMBER {
+            let mut isnum = 0;
+            let n = unsafe { lua_tointegerx(self.l, self.idx, &mut isnum) };
+            if isnum != 0 {
+                return Ok(n);
+            }
+        }
+
+        Err(self.mismatch("integer"))
+    }
+
+    fn number(&self) -> Result<f64, LuaError> {
+        if self.ty() == LUA_TNUMBER {
+            Ok(unsafe { lua_tonumberx(self.l, self.idx, ptr::null_mut()) })
+        } else {
+            Err(self.mismatch("number"))
+        }
+    }
+
+    fn bytes(&self) -> Result<&[u8], LuaError> {
+        if self.ty() == LUA_TSTRING {
+            Ok(unsafe { string_bytes(self.l, self.idx) })
+        } else {
+            Err(self.mismatch("string"))
+        }
+    }
+
+    fn string(&self) -> Result<&str, LuaError> {
+        str::from_utf8(self.bytes()?).map_err(|_| conversion("string is not valid UTF-8"))
+    }
+
+    fn table(&self) -> Result<(), LuaError> {
+        if self.ty() != LUA_TTABLE {
+            return Err(self.mismatch("table"));
+        }
+
+        if self.depth >= MAX_DEPTH {
+            Err(conversion(format!("tables are nested more than {} levels deep", MAX_DEPTH)))
+        } else {
+            reserve(self.l)
+        }
+    }
+
+    /// Returns whether the table at `idx` holds nothing but the keys
+    /// `1..=n` for a non-zero `n`.
+    fn is_sequence(&self) -> bool {
+        unsafe {
+            let len = lua_rawlen(self.l, self.idx);
+            if len == 0 {
+                return false;
+            }
+
+            let mut count = 0;
+            lua_pushnil(self.l);
+            while lua_next(self.l, self.idx) != 0 {
+                count += 1;
+                lua_settop(self.l, -2);
+            }
+
+            count == len
+        }
+    }
+
+    /// Runs `f` and then drops anything it left above the current top.
+    fn restoring<T, F>(&self, f: F) -> Result<T, LuaError> where F: FnOnce() -> Result<T, LuaError> {
+        let top = unsafe { lua_gettop(self.l) };
+        let res = f();
+        unsafe { lua_settop(self.l, top) };
+
+        res
+    }
+}
+
+macro_rules! deserialize_integer {
+    ($($method:ident),*) => {
+        $(
+            fn $method<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+                visitor.visit_i64(self.integer()?)
+            }
+        )*
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = LuaError;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        match self.ty() {
+            LUA_TNIL | LUA_TNONE => visitor.visit_unit(),
+            LUA_TBOOLEAN => visitor.visit_bool(unsafe { lua_toboolean(self.l, self.idx) } != 0),
+            LUA_TNUMBER if unsafe { lua_isinteger(self.l, self.idx) } != 0 => visitor.visit_i64(self.integer()?),
+            LUA_TNUMBER => visitor.visit_f64(self.number()?),
+            LUA_TSTRING => {
+                let bytes = self.bytes()?;
+                match str::from_utf8(bytes) {
+                    Ok(s) => visitor.visit_str(s),
+                    Err(_) => visitor.visit_bytes(bytes),
+                }
+            }
+            LUA_TTABLE => {
+                self.table()?;
+                if self.is_sequence() {
+                    self.deserialize_seq(visitor)
+                } else {
+                    self.deserialize_map(visitor)
+                }
+            }
+            _ => Err(conversion(format!("cannot convert a {} value", typename(self.l, self.idx)))),
+        }
+    }
+
+    fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        if self.ty() == LUA_TBOOLEAN {
+            visitor.visit_bool(unsafe { lua_toboolean(self.l, self.idx) } != 0)
+        } else {
+            Err(self.mismatch("boolean"))
+        }
+    }
+
+    deserialize_integer!(deserialize_i8, deserialize_i16, deserialize_i32, deserialize_i64,
+                         deserialize_u8, deserialize_u16, deserialize_u32, deserialize_u64);
+
+    fn deserialize_f32<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_f64(self.number()?)
+    }
+
+    fn deserialize_f64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_f64(self.number()?)
+    }
+
+    fn deserialize_char<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_str(self.string()?)
+    }
+
+    fn deserialize_str<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_str(self.string()?)
+    }
+
+    fn deserialize_string<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_str(self.string()?)
+    }
+
+    fn deserialize_bytes<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_bytes(self.bytes()?)
+    }
+
+    fn deserialize_byte_buf<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_bytes(self.bytes()?)
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        match self.ty() {
+            LUA_TNIL | LUA_TNONE => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        match self.ty() {
+            LUA_TNIL | LUA_TNONE => visitor.visit_unit(),
+            _ => Err(self.mismatch("nil")),
+        }
+    }
+
+    fn deserialize_unit_struct<V: de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.table()?;
+        let len = unsafe { lua_rawlen(self.l, self.idx) } as i64;
+
+        self.restoring(|| visitor.visit_seq(Seq { l: self.l, table: self.idx, depth: self.depth + 1, next: 1, len }))
+    }
+
+    fn deserialize_tuple<V: de::Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: de::Visitor<'de>>(self, _name: &'static str, _len: usize,
+                                                     visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.table()?;
+
+        self.restoring(|| {
+            unsafe { lua_pushnil(self.l) };
+            visitor.visit_map(Pairs { l: self.l, table: self.idx, depth: self.depth + 1, key: None })
+        })
+    }
+
+    fn deserialize_struct<V: de::Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str],
+                                               visitor: V) -> Result<V::Value, LuaError> {
+        self.table()?;
+
+        self.restoring(|| visitor.visit_map(Fields { l: self.l, table: self.idx, depth: self.depth + 1, fields, key: "" }))
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str],
+                                             visitor: V) -> Result<V::Value, LuaError> {
+        match self.ty() {
+            LUA_TSTRING => visitor.visit_enum(self.string()?.into_deserializer()),
+            LUA_TTABLE => {
+                self.table()?;
+                self.restoring(|| unsafe {
+                    lua_pushnil(self.l);
+                    if lua_next(self.l, self.idx) == 0 || lua_type(self.l, -2) != LUA_TSTRING {
+                        return Err(conversion("expected a table with a variant name as its only key"));
+                    }
+
+                    let name = str::from_utf8(string_bytes(self.l, -2))
+                        .map_err(|_| conversion("string is not valid UTF-8"))?
+                        .to_owned();
+                    lua_pushvalue(self.l, -2);
+                    if lua_next(self.l, self.idx) != 0 {
+                        return Err(conversion(format!("expected only the variant name as a key, found more than '{}'", name)));
+                    }
+
+                    let value = lua_gettop(self.l);
+                    visitor.visit_enum(Variant { l: self.l, value, depth: self.depth + 1, name })
+                })
+            }
+            _ => Err(self.mismatch("string or table")),
+        }
+    }
+
+    fn deserialize_identifier<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_unit()
+    }
+}
+
+/// Reads the array part of a table, `1..=len`, in order.
+struct Seq {
+    l: *mut lua_State,
+    table: c_int,
+    depth: usize,
+    next: i64,
+    len: i64,
+}
+
+impl<'de> de::SeqAccess<'de> for Seq {
+    type Error = LuaError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, LuaError> {
+        if self.next > self.len {
+            return Ok(None);
+        }
+
+        let i = self.next;
+        self.next += 1;
+        unsafe {
+            lua_rawgeti(self.l, self.table, i);
+            let idx = lua_gettop(self.l);
+            let res = seed.deserialize(Deserializer { l: self.l, idx, depth: self.depth });
+            lua_settop(self.l, idx - 1);
+
+            res.map(Some).map_err(|e| within(e, &Segment::Index(i)))
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some((self.len - self.next + 1) as usize)
+    }
+}
+
+/// Reads every pair of a table with `lua_next`. The current key stays on
+/// the stack between calls, starting from the `nil` pushed before the
+/// first one.
+struct Pairs {
+    l: *mut lua_State,
+    table: c_int,
+    depth: usize,
+    key: Option<Segment>,
+}
+
+impl<'de> de::MapAccess<'de> for Pairs {
+    type Error = LuaError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, LuaError> {
+        unsafe {
+            if lua_next(self.l, self.table) == 0 {
+                return Ok(None);
+            }
+
+            self.key = Segment::of_key(self.l, -2);
+            lua_pushvalue(self.l, -2);
+            let idx = lua_gettop(self.l);
+            let res = seed.deserialize(Deserializer { l: self.l, idx, depth: self.depth });
+            lua_settop(self.l, idx - 1);
+
+            res.map(Some)
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, LuaError> {
+        unsafe {
+            let idx = lua_gettop(self.l);
+            let res = seed.deserialize(Deserializer { l: self.l, idx, depth: self.depth });
+            lua_settop(self.l, idx - 1);
+
+            match self.key.take() {
+                Some(ref seg) => res.map_err(|e| within(e, seg)),
+                None => res,
+            }
+        }
+    }
+}
+
+/// Reads the fields a struct declares, skipping those that are `nil` so
+/// that serde can fill in `None` or report them missing.
+struct Fields {
+    l: *mut lua_State,
+    table: c_int,
+    depth: usize,
+    fields: &'static [&'static str],
+    key: &'static str,
+}
+
+impl<'de> de::MapAccess<'de> for Fields {
+    type Error = LuaError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, LuaError> {
+        while let Some((&field, rest)) = self.fields.split_first() {
+            self.fields = rest;
+
+            push_str(self.l, field);
+            if unsafe { lua_rawget(self.l, self.table) } != LUA_TNIL {
+                self.key = field;
+                return seed.deserialize(field.into_deserializer()).map(Some);
+            }
+            unsafe { lua_settop(self.l, -2) };
+        }
+
+        Ok(None)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, LuaError> {
+        unsafe {
+            let idx = lua_gettop(self.l);
+            let res = seed.deserialize(Deserializer { l: self.l, idx, depth: self.depth });
+            lua_settop(self.l, idx - 1);
+
+            res.map_err(|e| within(e, &Segment::Field(self.key.to_owned())))
+        }
+    }
+}
+
+/// The content of an enum variant stored as `{ Name = value }`, with
+/// `value` at `value` on the stack.
+struct Variant {
+    l: *mut lua_State,
+    value: c_int,
+    depth: usize,
+    name: String,
+}
+
+impl Variant {
+    fn content(&self) -> Deserializer {
+        Deserializer { l: self.l, idx: self.value, depth: self.depth }
+    }
+
+    fn annotate<T>(&self, res: Result<T, LuaError>) -> Result<T, LuaError> {
+        res.map_err(|e| within(e, &Segment::Field(self.name.clone())))
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for Variant {
+    type Error = LuaError;
+    type Variant = Variant;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Variant), LuaError> {
+        let name = seed.deserialize(self.name.as_str().into_deserializer())?;
+
+        Ok((name, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant {
+    type Error = LuaError;
+
+    fn unit_variant(self) -> Result<(), LuaError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, LuaError> {
+        self.annotate(seed.deserialize(self.content()))
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, LuaError> {
+        self.annotate(de::Deserializer::deserialize_seq(self.content(), visitor))
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(self, fields: &'static [&'static str],
+                                           visitor: V) -> Result<V::Value, LuaError> {
+        self.annotate(de::Deserializer::deserialize_struct(self.content(), "", fields, visitor))
+    }
+}
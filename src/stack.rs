@@ -0,0 +1,99 @@
+//! Keeping the Lua stack balanced across a region of code.
+
+use std::ops::{Deref, DerefMut};
+use std::thread;
+
+use libc::c_int;
+
+use super::State;
+
+/// Records the top of the stack and restores it when dropped, created by
+/// [`State::guard`](state/struct.State.html#method.guard).
+///
+/// A region is expected to leave the stack as it found it, apart from the
+/// values it was told to [`keep`](#method.keep). In debug builds, dropping
+/// the guard asserts that the region neither leaked values nor popped
+/// values it did not push. In release builds the top is silently restored.
+///
+/// The guard is not dropped when a Lua error unwinds past it, since Lua
+/// errors do not run Rust destructors.
+///
+/// A `StackGuard` dereferences to the [`State`](state/struct.State.html) it
+/// was created from, so the rest of the stack API is available on it.
+///
+/// # Examples
+///
+/// ```
+/// use lua_ffi::State;
+///
+/// let mut state = State::new();
+/// state.push(1);
+///
+/// {
+///     let mut guard = state.guard();
+///     guard.push("temporary");
+///     guard.push(2);
+///     guard.remove(-2);
+///     guard.keep(1);
+/// }
+///
+/// assert_eq!(state.gettop(), 2);
+/// assert_eq!(state.to::<(i32, i32)>(1), Ok((1, 2)));
+/// ```
+pub struct StackGuard<'a> {
+    state: &'a mut State,
+    top: c_int,
+    keep: c_int,
+}
+
+impl<'a> StackGuard<'a> {
+    pub(crate) fn new(state: &'a mut State) -> StackGuard<'a> {
+        let top = state.gettop();
+
+        StackGuard {
+            state,
+            top,
+            keep: 0,
+        }
+    }
+
+    /// Returns the top of the stack when the guard was created.
+    pub fn top(&self) -> c_int {
+        self.top
+    }
+
+    /// Lets the region leave `n` values above the recorded top, such as the
+    /// results of a function.
+    pub fn keep(&mut self, n: c_int) {
+        self.keep = n;
+    }
+}
+
+impl<'a> Deref for StackGuard<'a> {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        self.state
+    }
+}
+
+impl<'a> DerefMut for StackGuard<'a> {
+    fn deref_mut(&mut self) -> &mut State {
+        self.state
+    }
+}
+
+impl<'a> Drop for StackGuard<'a> {
+    fn drop(&mut self) {
+        let expected = self.top + self.keep;
+        let top = self.state.gettop();
+
+        // Asserting while already panicking would abort the process
+        if !thread::panicking() {
+            debug_assert!(top <= expected, "stack guard: {} value(s) leaked", top - expected);
+            debug_assert!(top >= expected, "stack guard: {} value(s) popped past the guarded top", expected - top);
+        }
+
+        self.state.settop(expected);
+    }
+}
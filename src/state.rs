@@ -1,13 +1,32 @@
-use std::{mem, ptr};
+use std::{mem, ptr, slice, str};
+use std::convert::{TryFrom, TryInto};
+use std::fmt::Display;
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_schar};
 use std::path::Path;
 
 use libc::{c_int, c_void};
 
+use super::error::{ErrorValue, LuaError, SyntaxError};
 use super::ffi::*;
-use super::types::{LuaFunction, LuaObject, LuaValue};
-use std::ptr::{null, null_mut};
+use super::types::{FromLua, LuaFunction, LuaNumber, LuaObject, LuaValue};
+use super::scope::Scope;
+use super::stack::StackGuard;
+#[cfg(feature = "serde")]
+use super::serialize;
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+use super::userdata::{self, Slot};
+use super::handler;
+use super::reload;
+use super::sandbox;
+use super::value::{LuaType, Value};
+use std::ptr::null_mut;
+
+/// Free stack slots ensured before pushing a value, which is as many as Lua
+/// guarantees a C function on entry. `LuaValue::push_val` may use them for
+/// temporaries.
+const PUSH_SLOTS: usize = LUA_MINSTACK as usize;
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum ThreadStatus {
@@ -52,11 +71,17 @@ impl State {
         }
     }
 
+    /// Returns the raw `lua_State` pointer wrapped by this state, for use
+    /// with the functions in [`ffi`](../ffi/index.html).
+    pub fn as_ptr(&mut self) -> *mut lua_State {
+        self.state
+    }
+
     /// Wraps an existing Lua state. Suitable for use in function handlers
     /// passed to Lua through the C API.
     pub fn from_ptr(state: *mut lua_State) -> State {
         State {
-            state: state,
+            state,
             owned: false,
         }
     }
@@ -73,57 +98,61 @@ impl State {
 
     /// Opens the Lua basic library on this state.
     pub fn open_base(&mut self) {
-        unsafe {
-            luaopen_base(self.state);
-        }
+        self.open_lib(b"_G\x00", luaopen_base);
     }
 
     /// Opens the Lua math library on this state.
     pub fn open_math(&mut self) {
-        unsafe {
-            luaopen_math(self.state);
-        }
+        self.open_lib(LUA_MATHLIBNAME, luaopen_math);
     }
 
     /// Opens the Lua string library on this state.
     pub fn open_string(&mut self) {
-        unsafe {
-            luaopen_string(self.state);
-        }
+        self.open_lib(LUA_STRLIBNAME, luaopen_string);
     }
 
     /// Opens the Lua table library on this state.
     pub fn open_table(&mut self) {
-        unsafe {
-            luaopen_table(self.state);
-        }
+        self.open_lib(LUA_TABLIBNAME, luaopen_table);
     }
 
     /// Opens the Lua io library on this state.
     pub fn open_io(&mut self) {
-        unsafe {
-            luaopen_io(self.state);
-        }
+        self.open_lib(LUA_IOLIBNAME, luaopen_io);
     }
 
     /// Opens the Lua os library on this state.
     pub fn open_os(&mut self) {
-        unsafe {
-            luaopen_os(self.state);
-        }
+        self.open_lib(LUA_OSLIBNAME, luaopen_os);
     }
 
     /// Opens the Lua package library on this state.
     pub fn open_package(&mut self) {
-        unsafe {
-            luaopen_package(self.state);
-        }
+        self.open_lib(LUA_LOADLIBNAME, luaopen_package);
     }
 
     /// Opens the Lua debug library on this state.
     pub fn open_debug(&mut self) {
+        self.open_lib(LUA_DBLIBNAME, luaopen_debug);
+    }
+
+    /// Opens the Lua coroutine library on this state.
+    pub fn open_coroutine(&mut self) {
+        self.open_lib(LUA_COLIBNAME, luaopen_coroutine);
+    }
+
+    /// Opens the Lua utf8 library on this state.
+    pub fn open_utf8(&mut self) {
+        self.open_lib(LUA_UTF8LIBNAME, luaopen_utf8);
+    }
+
+    /// Opens a standard library and sets it as the global `name`, like
+    /// `require` would, without leaving it on the stack.
+    fn open_lib(&mut self, name: &[u8], open: unsafe extern "C" fn(*mut lua_State) -> c_int) {
+        self.ensure(1);
         unsafe {
-            luaopen_debug(self.state);
+            luaL_requiref(self.state, name.as_ptr() as *const c_schar, Some(open), 1);
+            lua_settop(self.state, -2);
         }
     }
 
@@ -141,21 +170,201 @@ impl State {
         }
     }
 
+    /// Returns the index of the top element of the stack, which is also the
+    /// number of elements on it.
+    pub fn gettop(&mut self) -> c_int {
+        unsafe {
+            lua_gettop(self.state)
+        }
+    }
+
+    /// Converts the acceptable index `idx` into an absolute index, one that
+    /// does not depend on the top of the stack.
+    pub fn absindex(&mut self, idx: c_int) -> c_int {
+        unsafe {
+            lua_absindex(self.state, idx)
+        }
+    }
+
+    /// Moves the top element into the valid index `idx`, shifting up the
+    /// elements above it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx` is not a valid stack index.
+    pub fn insert(&mut self, idx: c_int) {
+        self.check_index(idx);
+        unsafe {
+            lua_insert(self.state, idx);
+        }
+    }
+
+    /// Removes the element at the valid index `idx`, shifting down the
+    /// elements above it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx` is not a valid stack index.
+    pub fn remove(&mut self, idx: c_int) {
+        self.check_index(idx);
+        unsafe {
+            lua_remove(self.state, idx);
+        }
+    }
+
+    /// Pops the top element and stores it at the valid index `idx`,
+    /// without shifting any element.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx` is not a valid stack index.
+    pub fn replace(&mut self, idx: c_int) {
+        self.check_index(idx);
+        unsafe {
+            lua_replace(self.state, idx);
+        }
+    }
+
+    /// Rotates the elements between the valid index `idx` and the top by `n`
+    /// positions, towards the top for a positive `n`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx` is not a valid stack index or `n` is larger than the
+    /// number of rotated elements.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lua_ffi::State;
+    ///
+    /// let mut state = State::new();
+    /// state.do_string("return 1, 2, 3");
+    /// state.rotate(1, 1);
+    /// assert_eq!(state.to::<(i32, i32, i32)>(1), Ok((3, 1, 2)));
+    /// ```
+    pub fn rotate(&mut self, idx: c_int, n: c_int) {
+        let idx = self.check_index(idx);
+        let len = self.gettop() - idx + 1;
+        assert!(n.abs() <= len, "cannot rotate {} elements by {}", len, n);
+        unsafe {
+            lua_rotate(self.state, idx, n);
+        }
+    }
+
+    /// Copies the element at `from` into the valid index `to`, replacing
+    /// the value there.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either index is not a valid stack index.
+    pub fn copy(&mut self, from: c_int, to: c_int) {
+        self.check_index(from);
+        self.check_index(to);
+        unsafe {
+            lua_copy(self.state, from, to);
+        }
+    }
+
+    /// Records the top of the stack, to be restored when the returned guard
+    /// is dropped. See [`StackGuard`](../struct.StackGuard.html).
+    pub fn guard(&mut self) -> StackGuard<'_> {
+        StackGuard::new(self)
+    }
+
+    // Returns `idx` as an absolute index, panicking if it does not refer to
+    // an element of the stack.
+    fn check_index(&mut self, idx: c_int) -> c_int {
+        let top = self.gettop();
+        let abs = if idx < 0 && idx > LUA_REGISTRYINDEX { top + idx + 1 } else { idx };
+        assert!((1..=top).contains(&abs), "invalid stack index {} (stack has {} elements)", idx, top);
+
+        abs
+    }
+
     /// Loads a script or bytecode from specified buffer.
+    ///
+    /// `name` is the chunk name used in error messages and tracebacks. By
+    /// Lua's conventions, `@file.lua` names a chunk loaded from a file and
+    /// `=name` is displayed as is, while any other name is shown as the
+    /// source `[string "name"]`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack cannot grow to hold the chunk.
+    /// [`load_chunk`](#method.load_chunk) returns that error instead.
     pub fn load_buffer(&mut self, buf: &[u8], name: &str) -> ThreadStatus {
+        // Lua would stop reading the name at a NUL anyway.
+        let name = CString::new(name.split('\0').next().unwrap_or_default()).unwrap_or_default();
+        self.ensure(1);
         unsafe {
             luaL_loadbufferx(self.state,
                             buf.as_ptr() as *const c_schar, buf.len(),
-                            name.as_ptr() as *const c_schar, null()).into()
+                            name.as_ptr(), sandbox::load_mode(self.state)).into()
+        }
+    }
+
+    /// Loads `code` as a chunk named `name`, following the conventions of
+    /// [`load_buffer`](#method.load_buffer), and pushes it as a function.
+    ///
+    /// On failure nothing is pushed, and a syntax error is returned as a
+    /// `LuaError::Syntax` locating it in the source.
+    pub fn load_chunk(&mut self, code: &[u8], name: &str) -> Result<(), LuaError> {
+        self.grow(1)?;
+        match self.load_buffer(code, name) {
+            ThreadStatus::Ok => Ok(()),
+            status => Err(self.load_error(status)),
         }
     }
 
+    // Pops the message left by a failed load and turns it into the error
+    // for `status`.
+    fn load_error(&mut self, status: ThreadStatus) -> LuaError {
+        let message = handler::describe(self.state, -1);
+        self.pop(1);
+
+        match status {
+            ThreadStatus::SyntaxError => LuaError::Syntax(SyntaxError::parse(&message)),
+            ThreadStatus::MemoryError => LuaError::OutOfMemory,
+            ThreadStatus::FileError => LuaError::File(message),
+            _ => LuaError::Runtime { message, traceback: None },
+        }
+    }
+
+    /// Loads `code` as a chunk named `name` and runs it, leaving its results
+    /// on the stack. Errors raised while running it are returned like by
+    /// [`pcall_with_traceback`](#method.pcall_with_traceback).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lua_ffi::{LuaError, State};
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    ///
+    /// match state.do_chunk(b"local t = nil\nreturn t.x", "@scripts/init.lua") {
+    ///     Err(LuaError::Runtime { message, .. }) => {
+    ///         assert_eq!(message, "scripts/init.lua:2: attempt to index a nil value (local 't')");
+    ///     }
+    ///     _ => panic!("the chunk should fail"),
+    /// }
+    /// ```
+    pub fn do_chunk(&mut self, code: &[u8], name: &str) -> Result<(), LuaError> {
+        self.load_chunk(code, name)?;
+        self.pcall_with_traceback(0, LUA_MULTIRET)
+    }
+
     /// Executes an arbitrary string as Lua code.
     ///
+    /// Errors go through the message handler set with
+    /// [`set_default_message_handler`](#method.set_default_message_handler),
+    /// and the handled error value is left on the stack.
+    ///
     /// # Examples
     ///
     /// ```
-    /// use luajit::{State, ThreadStatus};
+    /// use lua_ffi::{State, ThreadStatus};
     ///
     /// let mut state = State::new(); // Create new Lua state
     /// state.open_base(); // Need to open base libraries for `print` to be available
@@ -164,9 +373,10 @@ impl State {
     /// assert!(status == ThreadStatus::Ok);
     /// ```
     pub fn do_string(&mut self, s: &str) -> ThreadStatus {
-        let cstr = CString::new(s).unwrap();
-        unsafe {
-            luaL_dostring(self.state, cstr.as_ptr() as *const c_schar).into()
+        // Named after its source, as `luaL_loadstring` does.
+        match self.load_buffer(s.as_bytes(), s) {
+            ThreadStatus::Ok => self.pcall_handled(0, LUA_MULTIRET),
+            status => status,
         }
     }
 
@@ -192,6 +402,89 @@ impl State {
         }
     }
 
+    /// Calls the function below the `nargs` arguments on the top of the
+    /// stack in protected mode, like [`pcall`](#method.pcall), and records
+    /// the stack at the point of any error.
+    ///
+    /// On success the `nres` results are left on the stack. On failure the
+    /// function, its arguments and the error value are all popped, and the
+    /// error is returned as a `LuaError::Runtime` carrying the formatted
+    /// traceback. The error value first goes through the handler set with
+    /// [`set_default_message_handler`](#method.set_default_message_handler).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lua_ffi::{LuaError, State};
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.do_string("function explode() error('boom') end");
+    ///
+    /// state.get_global("explode");
+    /// match state.pcall_with_traceback(0, 0) {
+    ///     Err(LuaError::Runtime { message, traceback }) => {
+    ///         assert!(message.ends_with("boom"));
+    ///         assert!(traceback.unwrap().contains("in function 'explode'"));
+    ///     }
+    ///     _ => panic!("explode should fail"),
+    /// }
+    /// ```
+    pub fn pcall_with_traceback(&mut self, nargs: i32, nres: i32) -> Result<(), LuaError> {
+        self.ensure(1);
+        match handler::pcall(self.state, nargs, nres) {
+            (LUA_OK, _) => Ok(()),
+            (status, traceback) => {
+                let message = handler::describe(self.state, -1);
+                self.pop(1);
+
+                Err(if sandbox::take_limit_hit(self.state) {
+                    LuaError::InstructionLimit { traceback }
+                } else if status == LUA_ERRMEM {
+                    LuaError::OutOfMemory
+                } else {
+                    LuaError::Runtime { message, traceback }
+                })
+            }
+        }
+    }
+
+    /// Sets a function rewriting the error value of every failed protected
+    /// call made by [`do_string`](#method.do_string),
+    /// [`do_file`](#method.do_file) and
+    /// [`pcall_with_traceback`](#method.pcall_with_traceback), such as to
+    /// add context or translate error codes. It runs before the stack is
+    /// unwound, so it can inspect where the error happened.
+    ///
+    /// If `f` panics, the panic is caught, as it cannot unwind through Lua,
+    /// and the error value becomes a message saying so.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lua_ffi::{State, Value};
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.set_default_message_handler(|_, err| match err {
+    ///     Value::String(msg) => Value::from(format!("[mod] {}", msg.to_string_lossy())),
+    ///     other => other,
+    /// });
+    ///
+    /// state.do_string("error('missing texture', 0)");
+    /// assert_eq!(state.to_str(-1), Some("[mod] missing texture"));
+    /// ```
+    pub fn set_default_message_handler<F>(&mut self, f: F) where F: Fn(&mut State, Value) -> Value + 'static {
+        handler::set(self, Box::new(f));
+    }
+
+    // Calls the function below the `nargs` arguments with the message
+    // handler, leaving the results or the handled error value.
+    fn pcall_handled(&mut self, nargs: i32, nres: i32) -> ThreadStatus {
+        self.ensure(1);
+        handler::pcall(self.state, nargs, nres).0.into()
+    }
+
     /// Maps directly to `lua_pcall` without additional handling.
     pub fn pcallx(&mut self, nargs: i32, nres: i32, err_func: i32) -> ThreadStatus {
         unsafe {
@@ -199,13 +492,80 @@ impl State {
         }
     }
 
+    /// Caps the memory Lua may allocate on this state at `limit` bytes, or
+    /// lifts the cap with `None`. Allocations past it fail as if the system
+    /// ran out of memory: Lua collects garbage and retries, then raises a
+    /// memory error, which protected calls return as
+    /// `LuaError::OutOfMemory`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this `State` was created with
+    /// [`from_ptr`](#method.from_ptr), as the limit must live as long as
+    /// the Lua state.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        assert!(self.owned, "cannot limit the memory of a borrowed Lua state");
+        self.ensure(1);
+        sandbox::set_memory_limit(self.state, limit);
+    }
+
+    /// Returns the number of bytes Lua has allocated on this state.
+    pub fn memory_used(&mut self) -> usize {
+        sandbox::memory_used(self.state)
+    }
+
+    /// Makes Lua code on this state raise an error after running `limit`
+    /// more virtual machine instructions, or lifts the limit with `None`.
+    /// Protected calls return the error as `LuaError::InstructionLimit`.
+    ///
+    /// Once the limit is hit, every following instruction raises the error
+    /// again, so scripts cannot catch it with `pcall` and carry on; set a
+    /// new limit before running more code. Coroutines created afterwards
+    /// inherit the limit and count their own instructions.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lua_ffi::{LuaError, State};
+    ///
+    /// let mut state = State::new();
+    /// state.open_base();
+    /// state.set_instruction_limit(Some(10_000));
+    ///
+    /// match state.do_chunk(b"while true do pcall(error) end", "=spin") {
+    ///     Err(LuaError::InstructionLimit { .. }) => {}
+    ///     res => panic!("unexpected {:?}", res),
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero or larger than `i32::MAX`.
+    pub fn set_instruction_limit(&mut self, limit: Option<u32>) {
+        let limit = limit.map(|n| match c_int::try_from(n) {
+            Ok(n) if n > 0 => n,
+            _ => panic!("invalid instruction limit {}", n),
+        });
+        self.ensure(1);
+        sandbox::set_instruction_limit(self.state, limit);
+    }
+
+    /// Makes this state refuse precompiled binary chunks, so only Lua source
+    /// can be run. This covers chunks loaded from Rust and the `load`,
+    /// `loadfile` and `dofile` functions of the basic library, which must be
+    /// opened first. Modules found by `require` are still loaded by the
+    /// package library as they are.
+    pub fn refuse_binary_chunks(&mut self) {
+        sandbox::refuse_binary_chunks(self.state);
+    }
+
     /// Registers function `f` as a Lua global named `name`
     ///
     /// # Examples
     ///
     /// ```
-    /// use luajit::{State, ThreadStatus, c_int};
-    /// use luajit::ffi::lua_State;
+    /// use lua_ffi::{State, ThreadStatus, c_int};
+    /// use lua_ffi::ffi::lua_State;
     ///
     /// unsafe extern "C" fn hello(L: *mut lua_State) -> c_int {
     ///     println!("Hello world!");
@@ -223,8 +583,8 @@ impl State {
     /// Using an argument.
     ///
     /// ```
-    /// use luajit::{State, ThreadStatus, c_int};
-    /// use luajit::ffi::lua_State;
+    /// use lua_ffi::{State, ThreadStatus, c_int};
+    /// use lua_ffi::ffi::lua_State;
     ///
     /// unsafe extern "C" fn hello_name(l: *mut lua_State) -> c_int {
     ///     let mut state = State::from_ptr(l);
@@ -256,6 +616,21 @@ impl State {
         }
     }
 
+    /// Returns the type of the value at `idx`, or `LuaType::None` if `idx` is
+    /// past the top of the stack.
+    pub fn type_of(&mut self, idx: c_int) -> LuaType {
+        LuaType::from_raw(unsafe { lua_type(self.state, idx) })
+    }
+
+    /// Test if the value at `idx` on the stack is a number with the integer
+    /// subtype. Floats with an integral value, such as `3.0`, and numeric
+    /// strings are not integers.
+    pub fn is_integer(&mut self, idx: c_int) -> bool {
+        unsafe {
+            lua_isinteger(self.state, idx) != 0
+        }
+    }
+
     /// Test if the value at `idx` on the stack is a string.
     pub fn is_string(&mut self, idx: c_int) -> bool {
         unsafe {
@@ -270,62 +645,98 @@ impl State {
         }
     }
 
-    /// Test if the value at `idx` on the stack is a userdata object
+    /// Test if the value at `idx` on the stack is a userdata object,
+    /// either full or light
     pub fn is_userdata(&mut self, idx: c_int) -> bool {
         unsafe {
             lua_isuserdata(self.state, idx) != 0
         }
     }
 
-    /// Retrieves a string from the Lua stack.
+    /// Test if the value at `idx` on the stack is a light userdata
+    pub fn is_light_userdata(&mut self, idx: c_int) -> bool {
+        unsafe {
+            lua_islightuserdata(self.state, idx)
+        }
+    }
+
+    /// Retrieves a string from the Lua stack. Returns `None` if the value is
+    /// not a string or number, or if the string is not valid UTF-8; use
+    /// [`to_bytes`](#method.to_bytes) to read arbitrary byte strings.
     pub fn to_str(&mut self, idx: c_int) -> Option<&str> {
+        self.to_bytes(idx).and_then(|bytes| str::from_utf8(bytes).ok())
+    }
+
+    /// Retrieves a string from the Lua stack as raw bytes, including any
+    /// embedded NULs. Like `lua_tolstring`, a number is converted to a
+    /// string in place.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lua_ffi::State;
+    ///
+    /// let mut state = State::new();
+    /// state.do_string(r#"return "map\0\255""#);
+    ///
+    /// assert_eq!(state.to_bytes(-1), Some(&b"map\0\xff"[..]));
+    /// assert_eq!(state.to_str(-1), None);
+    /// ```
+    pub fn to_bytes(&mut self, idx: c_int) -> Option<&[u8]> {
+        let mut len = 0;
         let ptr = unsafe {
-            lua_tostring(self.state, idx)
+            lua_tolstring(self.state, idx, &mut len)
         };
 
         if ptr.is_null() {
             None
         } else {
-            let cstr = unsafe {
-                CStr::from_ptr(ptr as *const c_char)
-            };
-
-            match cstr.to_str() {
-                Ok(s) => Some(s),
-                Err(_) => None
+            unsafe {
+                Some(slice::from_raw_parts(ptr as *const u8, len))
             }
         }
     }
 
-    /// Return the value on the stack at `idx` as an integer.
+    /// Return the value on the stack at `idx` as an integer. Returns `None`
+    /// if it has a fractional part or does not fit in an `i32`.
     pub fn to_int(&mut self, idx: c_int) -> Option<i32> {
-        if self.is_number(idx) {
-            unsafe {
-                Some(lua_tointegerx(self.state, idx, null_mut()) as i32)
-            }
-        } else {
-            None
-        }
+        self.to_long(idx).and_then(|n| i32::try_from(n).ok())
     }
 
+    /// Return the value on the stack at `idx` as an unsigned integer.
+    /// Returns `None` if it has a fractional part or does not fit in a `u32`.
     pub fn to_uint(&mut self, idx: c_int) -> Option<u32> {
-        if self.is_number(idx) {
-            unsafe {
-                Some(lua_tointegerx(self.state, idx, null_mut()) as u32)
-            }
+        self.to_long(idx).and_then(|n| u32::try_from(n).ok())
+    }
+
+    /// Return the value on the stack at `idx` as a 64-bit integer. Floats
+    /// and numeric strings are converted only when they have an exact
+    /// integer representation, so `3.0` reads as `3` but `3.5` as `None`.
+    pub fn to_long(&mut self, idx: c_int) -> Option<i64> {
+        let mut isnum = 0;
+        let n = unsafe {
+            lua_tointegerx(self.state, idx, &mut isnum)
+        };
+
+        if isnum != 0 {
+            Some(n)
         } else {
             None
         }
     }
 
-    /// Return the value on the stack at `idx` as an integer.
-    pub fn to_long(&mut self, idx: c_int) -> Option<i64> {
-        if self.is_number(idx) {
-            unsafe {
-                Some(lua_tointegerx(self.state, idx, null_mut()) as i64)
+    /// Return the number at `idx` with its integer or float subtype, or
+    /// `None` if the value is not a number. Numeric strings are not
+    /// converted.
+    pub fn to_number(&mut self, idx: c_int) -> Option<LuaNumber> {
+        unsafe {
+            if lua_type(self.state, idx) != LUA_TNUMBER {
+                None
+            } else if lua_isinteger(self.state, idx) != 0 {
+                Some(LuaNumber::Integer(lua_tointegerx(self.state, idx, null_mut())))
+            } else {
+                Some(LuaNumber::Float(lua_tonumberx(self.state, idx, null_mut())))
             }
-        } else {
-            None
         }
     }
 
@@ -362,9 +773,240 @@ impl State {
         }
     }
 
-    /// Returns the userdata on the top of the Lua stack as a raw pointer
+    /// Reads the value at `idx` on the stack as any type implementing
+    /// `FromLua`, without removing it from the stack.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lua_ffi::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push(5);
+    /// state.push("Hello world!");
+    ///
+    /// assert_eq!(state.to::<i32>(-2), Ok(5));
+    /// assert!(state.to::<bool>(-1).is_err());
+    /// ```
+    pub fn to<T>(&mut self, idx: c_int) -> Result<T, LuaError> where T: FromLua {
+        T::from_lua(self, idx)
+    }
+
+    /// Reads argument `n` of the Rust function Lua is calling, failing with
+    /// a `LuaError::BadArgument` naming the argument and the function if it
+    /// cannot be converted to `T`.
+    ///
+    /// Unlike `luaL_checkinteger` and friends, nothing is raised: the error
+    /// is returned, so Rust values on the stack are dropped normally.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use] extern crate lua_ffi;
+    ///
+    /// use lua_ffi::{c_int, LuaError, State, ThreadStatus};
+    ///
+    /// fn double(state: &mut State) -> Result<c_int, LuaError> {
+    ///     let n = state.check_arg::<i32>(1)?;
+    ///     state.push(n * 2);
+    ///
+    ///     Ok(1)
+    /// }
+    ///
+    /// fn main() {
+    ///     let mut state = State::new();
+    ///     state.open_libs();
+    ///     state.register("double", lua_fn!(double).unwrap());
+    ///
+    ///     let res = state.do_string(r#"
+    ///         local ok, err = pcall(double, "two")
+    ///         assert(err == "bad argument #1 to '?' (number expected, got string)")
+    ///         assert(double(2) == 4)
+    ///     "#);
+    ///     assert_eq!(res, ThreadStatus::Ok);
+    /// }
+    /// ```
+    pub fn check_arg<T>(&mut self, n: c_int) -> Result<T, LuaError> where T: FromLua {
+        self.to(n).map_err(|err| self.arg_error(n, err.to_string()))
+    }
+
+    /// Reads argument `n` like [`check_arg`](#method.check_arg), but
+    /// returns `default` if the argument is `nil` or absent.
+    pub fn opt_arg<T>(&mut self, n: c_int, default: T) -> Result<T, LuaError> where T: FromLua {
+        match self.type_of(n) {
+            LuaType::None | LuaType::Nil => Ok(default),
+            _ => self.check_arg(n),
+        }
+    }
+
+    /// Reads argument `n` as a string that must be one of `options`, and
+    /// returns its position in `options`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lua_ffi::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push("rb");
+    /// state.push("append");
+    ///
+    /// assert_eq!(state.check_option(1, &["r", "rb", "w"]), Ok(1));
+    /// assert_eq!(
+    ///     state.check_option(2, &["r", "rb", "w"]).unwrap_err().to_string(),
+    ///     "bad argument #2 to '?' (invalid option 'append')"
+    /// );
+    /// ```
+    pub fn check_option(&mut self, n: c_int, options: &[&str]) -> Result<usize, LuaError> {
+        let name = match self.type_of(n) {
+            LuaType::String => self.to_bytes(n).unwrap_or_default().to_owned(),
+            ty => return Err(self.arg_error(n, format!("string expected, got {}", ty))),
+        };
+
+        match options.iter().position(|opt| opt.as_bytes() == &name[..]) {
+            Some(i) => Ok(i),
+            None => {
+                let message = format!("invalid option '{}'", String::from_utf8_lossy(&name));
+                Err(self.arg_error(n, message))
+            }
+        }
+    }
+
+    // Builds the error for a bad argument `n` to the running function.
+    pub(crate) fn arg_error(&mut self, n: c_int, message: String) -> LuaError {
+        LuaError::BadArgument {
+            index: n,
+            function: self.function_name().map_or_else(|| "?".to_owned(), |(name, _)| name),
+            message,
+        }
+    }
+
+    // Returns the name Lua called the running function by, if it has one,
+    // and whether it was called as a method.
+    fn function_name(&mut self) -> Option<(String, bool)> {
+        unsafe {
+            let mut ar: lua_Debug = mem::zeroed();
+            if lua_getstack(self.state, 0, &mut ar) == 0 || lua_getinfo(self.state, b"n\0".as_ptr() as *const c_schar, &mut ar) == 0 {
+                return None;
+            }
+
+            if ar.name.is_null() {
+                None
+            } else {
+                let method = CStr::from_ptr(ar.namewhat).to_bytes() == b"method";
+                Some((CStr::from_ptr(ar.name).to_string_lossy().into_owned(), method))
+            }
+        }
+    }
+
+    /// Pushes a Rust value implementing `Serialize` as a Lua value. Structs
+    /// and maps become tables, sequences become arrays starting at 1,
+    /// `None` becomes `nil`, and enums become the string of a unit variant's
+    /// name or a table holding the variant's content under its name.
+    ///
+    /// Nothing is pushed if the value cannot be represented in Lua, for
+    /// instance a `u64` above `i64::MAX` or a `nil` map key. Requires the
+    /// `serde` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use] extern crate serde;
+    /// extern crate lua_ffi;
+    ///
+    /// use lua_ffi::{State, ThreadStatus};
+    ///
+    /// #[derive(Serialize)]
+    /// struct Enemy {
+    ///     name: String,
+    ///     hp: i32,
+    /// }
+    ///
+    /// fn main() {
+    ///     let mut state = State::new();
+    ///     state.open_libs();
+    ///
+    ///     let enemies = vec![Enemy { name: "slime".to_owned(), hp: 5 }];
+    ///     state.to_lua(&enemies).unwrap();
+    ///     state.set_global("enemies");
+    ///
+    ///     assert_eq!(state.do_string("assert(enemies[1].hp == 5)"), ThreadStatus::Ok);
+    /// }
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn to_lua<T>(&mut self, val: &T) -> Result<(), LuaError> where T: Serialize + ?Sized {
+        serialize::to_lua(self.state, val)
+    }
+
+    /// Reads the value at `idx` into a Rust value implementing
+    /// `Deserialize`, following the same mapping as
+    /// [`to_lua`](#method.to_lua). The value is left on the stack.
+    ///
+    /// Errors name the path to the offending value, such as
+    /// `enemies[3].hp: expected integer, got string`. Requires the `serde`
+    /// feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use] extern crate serde;
+    /// extern crate lua_ffi;
+    ///
+    /// use lua_ffi::State;
+    ///
+    /// #[derive(Deserialize, PartialEq, Debug)]
+    /// struct Level {
+    ///     name: String,
+    ///     width: u32,
+    ///     music: Option<String>,
+    /// }
+    ///
+    /// fn main() {
+    ///     let mut state = State::new();
+    ///     state.do_string(r#"return { name = "cave", width = 40 }"#);
+    ///
+    ///     let level: Level = state.from_lua(-1).unwrap();
+    ///     assert_eq!(level, Level { name: "cave".to_owned(), width: 40, music: None });
+    /// }
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn from_lua<T>(&mut self, idx: c_int) -> Result<T, LuaError> where T: DeserializeOwned {
+        serialize::from_lua(self.state, idx)
+    }
+
+    /// Returns the name of the type of the value at `idx`, as used in
+    /// Lua error messages.
+    pub(crate) fn typename_of(&mut self, idx: c_int) -> &'static str {
+        unsafe {
+            let name = CStr::from_ptr(luaL_typename(self.state, idx) as *const c_char);
+            name.to_str().unwrap_or("?")
+        }
+    }
+
+    /// Builds the error for a failed conversion of the value at `idx`.
+    pub(crate) fn type_mismatch(&mut self, idx: c_int, expected: &str) -> LuaError {
+        LuaError::TypeMismatch {
+            expected: expected.to_owned(),
+            got: self.typename_of(idx).to_owned(),
+        }
+    }
+
+    /// Returns the full userdata at `idx` as a raw pointer to its block.
+    /// Light userdata are not returned, see
+    /// [`to_light_userdata`](#method.to_light_userdata).
     pub fn to_raw_userdata(&mut self, idx: c_int) -> Option<*mut c_void> {
-        if self.is_userdata(idx) {
+        if unsafe { lua_type(self.state, idx) } == LUA_TUSERDATA {
+            unsafe {
+                Some(lua_touserdata(self.state, idx))
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Returns the pointer held by the light userdata at `idx`.
+    pub fn to_light_userdata(&mut self, idx: c_int) -> Option<*mut c_void> {
+        if self.is_light_userdata(idx) {
             unsafe {
                 Some(lua_touserdata(self.state, idx))
             }
@@ -377,38 +1019,109 @@ impl State {
     /// to type `T`
     ///
     /// See [`new_userdata`](#method.new_userdata) for more usage.
-    pub fn to_userdata<T>(&mut self, idx: c_int) -> Option<*mut T> {
+    ///
+    /// # Safety
+    ///
+    /// No check is made that the userdata holds a `T`, scripts can pass
+    /// any userdata in its place. The caller must know the block was
+    /// allocated for a `T`. Use [`check_userdata`](#method.check_userdata)
+    /// for types implementing `LuaObject`.
+    pub unsafe fn to_userdata<T>(&mut self, idx: c_int) -> Option<*mut T> {
         self.to_raw_userdata(idx).map(|pt| pt as *mut T)
     }
 
     /// Validates that the userdata at `idx` has metatable `ty` from the Lua registry
     /// and returns a pointer to the userdata object
-    pub fn check_userdata_ex<T>(&mut self, idx: c_int, ty: &str) -> Option<*mut T> {
-        unsafe {
-            let udata = luaL_checkudata(self.state, idx, CString::new(ty).unwrap().as_ptr() as *const c_schar);
+    ///
+    /// # Safety
+    ///
+    /// Only the metatable name is checked. The caller must make sure that
+    /// every userdata given the metatable `ty` holds a `T`.
+    pub unsafe fn check_userdata_ex<T>(&mut self, idx: c_int, ty: &str) -> Option<*mut T> {
+        let udata = luaL_checkudata(self.state, idx, CString::new(ty).unwrap().as_ptr() as *const c_schar);
 
-            if udata == ptr::null_mut() {
-                None
-            } else {
-                Some(udata as *mut T)
-            }
+        if udata.is_null() {
+            None
+        } else {
+            Some(udata as *mut T)
         }
     }
 
     /// Validates that the userdata at `idx` is an instance of struct `T` where
-    /// `T` implements `LuaObject`, and returns a pointer to the userdata object
-    pub fn check_userdata<T>(&mut self, idx: i32) -> Option<*mut T> where T: LuaObject {
-        unsafe {
-            let udata = luaL_checkudata(self.state, idx, T::name());
+    /// `T` implements `LuaObject`, and returns a pointer to the userdata object.
+    /// Otherwise returns a `LuaError::BadArgument` with the message
+    /// `luaL_checkudata` would raise, for the caller to raise once its Rust
+    /// values are dropped.
+    ///
+    /// The type of the Rust value stored in the userdata is checked rather than
+    /// its metatable, so two types sharing a name can never be confused.
+    /// Instances of types declaring `T` as their parent, directly or through
+    /// other parents, are accepted as well.
+    ///
+    /// The returned pointer bypasses borrow tracking, read the value as a
+    /// [`LuaUserData`](../struct.LuaUserData.html) to borrow it safely.
+    pub fn check_userdata<T>(&mut self, idx: i32) -> Result<*mut T, LuaError> where T: LuaObject {
+        if let Some(udata) = self.test_userdata::<T>(idx) {
+            return Ok(udata);
+        }
 
-            if udata == ptr::null_mut() {
-                None
+        let type_name = userdata::type_name::<T>();
+        let message = unsafe {
+            if userdata::holds::<T>(self, idx) {
+                LuaError::Expired { type_name }.to_string()
+            } else if !luaL_testudata(self.state, idx, T::name()).is_null() {
+                format!("{} expected, got foreign userdata", type_name)
             } else {
-                Some(udata as *mut T)
+                format!("{} expected, got {}", type_name, self.described_type(idx))
+            }
+        };
+
+        let index = match self.function_name() {
+            // The receiver of a method call is reported as `self`
+            Some((_, true)) if idx == 1 => 0,
+            _ => idx,
+        };
+
+        Err(self.arg_error(index, message))
+    }
+
+    // Names the type of the value at `idx` like `luaL_typeerror`, using the
+    // `__name` of its metatable if it has one.
+    fn described_type(&mut self, idx: c_int) -> String {
+        let name = unsafe {
+            lua_checkstack(self.state, 1);
+            match luaL_getmetafield(self.state, idx, b"__name\x00".as_ptr() as *const c_schar) {
+                LUA_TNIL => None,
+                LUA_TSTRING => {
+                    let name = self.to_str(-1).map(str::to_owned);
+                    self.pop(1);
+                    name
+                }
+                _ => {
+                    self.pop(1);
+                    None
+                }
             }
+        };
+
+        match name {
+            Some(name) => name,
+            None if unsafe { lua_type(self.state, idx) } == LUA_TLIGHTUSERDATA => "light userdata".to_owned(),
+            None => self.typename_of(idx).to_owned(),
         }
     }
 
+    /// Tests if the value at `idx` is an instance of struct `T` where `T`
+    /// implements `LuaObject`, returning a pointer to the userdata object.
+    /// Unlike [`check_userdata`](#method.check_userdata) this never raises
+    /// a Lua error.
+    ///
+    /// The returned pointer bypasses borrow tracking, read the value as a
+    /// [`LuaUserData`](../struct.LuaUserData.html) to borrow it safely.
+    pub fn test_userdata<T>(&mut self, idx: i32) -> Option<*mut T> where T: LuaObject {
+        unsafe { userdata::value_at::<T>(self, idx) }
+    }
+
     /// Pops a value of the Lua stack and sets it as a global value
     /// named `name`
     pub fn set_global(&mut self, name: &str) {
@@ -442,7 +1155,8 @@ impl State {
         match name {
             Some(s) => unsafe {
                 // luaL_openlib(self.state, CString::new(s).unwrap().as_ptr() as *const c_schar, fns.as_ptr(), 0);
-                let name = CString::new(s).unwrap().as_ptr() as *const c_schar;
+                let cstr = CString::new(s).unwrap();
+                let name = cstr.as_ptr() as *const c_schar;
                 lua_getglobal(self.state, name);
                 if lua_isnil(self.state, -1) {
                     lua_pop(self.state, 1);
@@ -458,18 +1172,62 @@ impl State {
     }
 
     /// Raises an error, similar to luaL_error but without support for formatted strings.
+    ///
+    /// Raising an error jumps straight back into Lua, skipping the
+    /// destructors of every Rust value still alive in the calling function.
+    /// Functions wrapped by `lua_fn!` can return `Err` instead, which is
+    /// raised once they have returned.
     pub fn error(&mut self, err: &str) {
-        let cstr = CString::new(err).unwrap();
+        self.push_bytes(err.as_bytes());
 
         unsafe {
-            lua_pushstring(self.state, cstr.as_ptr() as *const c_schar);
             lua_error(self.state);
         }
     }
 
+    /// Turns `val` into an error that raises `val` itself, rather than a
+    /// message, when returned from a function called by Lua. Lua code
+    /// catching it with `pcall` receives the value unchanged.
+    ///
+    /// The state keeps only the latest such value, see
+    /// [`ErrorValue`](../error/struct.ErrorValue.html).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use] extern crate lua_ffi;
+    ///
+    /// use std::collections::HashMap;
+    ///
+    /// use lua_ffi::{c_int, LuaError, State, ThreadStatus};
+    ///
+    /// fn fetch(state: &mut State) -> Result<c_int, LuaError> {
+    ///     let mut err = HashMap::new();
+    ///     err.insert("code", 404);
+    ///
+    ///     Err(state.error_value(err))
+    /// }
+    ///
+    /// fn main() {
+    ///     let mut state = State::new();
+    ///     state.open_libs();
+    ///     state.register("fetch", lua_fn!(fetch).unwrap());
+    ///
+    ///     let res = state.do_string("local ok, err = pcall(fetch) assert(err.code == 404)");
+    ///     assert_eq!(res, ThreadStatus::Ok);
+    /// }
+    /// ```
+    pub fn error_value<T>(&mut self, val: T) -> LuaError where T: LuaValue {
+        self.push(val);
+        let val = unsafe { ErrorValue::capture(self.state, -1) };
+        self.pop(1);
+
+        LuaError::Value(val)
+    }
+
     /// Copys the value at `idx` to the top of the stack
     pub fn push_value(&mut self, idx: i32) {
-        self.checkstack(1);
+        self.ensure(1);
         unsafe {
             lua_pushvalue(self.state, idx);
         }
@@ -480,7 +1238,7 @@ impl State {
     /// # Examples
     ///
     /// ```
-    /// use luajit::State;
+    /// use lua_ffi::State;
     ///
     /// let mut state = State::new();
     /// state.push(5);
@@ -490,10 +1248,10 @@ impl State {
     /// Can also be used with structs that implement `LuaObject`
     ///
     /// ```
-    /// #[macro_use] extern crate luajit;
+    /// #[macro_use] extern crate lua_ffi;
     ///
-    /// use luajit::{State, LuaObject, c_int};
-    /// use luajit::ffi::luaL_Reg;
+    /// use lua_ffi::{State, LuaObject, c_int};
+    /// use lua_ffi::ffi::luaL_Reg;
     ///
     /// struct Point2D {
     ///     x: i32,
@@ -532,54 +1290,206 @@ impl State {
     ///     state.push(Point2D::new());
     ///     state.set_global("point");
     ///     let res = state.do_string(r#"print(point:add())"#);
-    ///     assert_eq!(res, luajit::ThreadStatus::Ok);
+    ///     assert_eq!(res, lua_ffi::ThreadStatus::Ok);
     /// }
     /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack cannot grow to hold the value or the value
+    /// cannot be represented in Lua, such as a `u64` past `i64::MAX`. Use
+    /// [`try_push`](#method.try_push) to handle those cases instead.
     pub fn push<T>(&mut self, val: T) where T: LuaValue {
-        val.push_val(self.state);
+        let res = self.try_push(val);
+        self.expect_ok(res);
+    }
+
+    /// Pushes a LuaValue to the lua stack, growing the stack as needed.
+    /// Returns `LuaError::StackOverflow`, and pushes nothing, if the stack
+    /// cannot grow any further. A value that cannot be represented in Lua
+    /// pushes nothing either and returns its `LuaError::Conversion`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lua_ffi::{LuaError, State};
+    ///
+    /// let mut state = State::new();
+    /// let mut res = Ok(());
+    /// while res.is_ok() {
+    ///     res = state.try_push(true);
+    /// }
+    ///
+    /// assert_eq!(res, Err(LuaError::StackOverflow));
+    /// ```
+    pub fn try_push<T>(&mut self, val: T) -> Result<(), LuaError> where T: LuaValue {
+        if !self.checkstack(PUSH_SLOTS) {
+            return Err(LuaError::StackOverflow);
+        }
+
+        val.try_push_val(self.state)
+    }
+
+    /// Pushes `n` as a Lua integer, failing instead of wrapping if it does
+    /// not fit in Lua's 64-bit integers.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lua_ffi::State;
+    ///
+    /// let mut state = State::new();
+    ///
+    /// assert!(state.push_integer(u64::MAX >> 1).is_ok());
+    /// assert!(state.push_integer(u64::MAX).is_err());
+    /// assert!(state.is_integer(-1));
+    /// ```
+    pub fn push_integer<T>(&mut self, n: T) -> Result<(), LuaError> where T: TryInto<i64> + Display + Copy {
+        match n.try_into() {
+            Ok(n) => {
+                self.grow(1)?;
+                unsafe {
+                    lua_pushinteger(self.state, n);
+                }
+
+                Ok(())
+            }
+            Err(_) => Err(LuaError::Conversion {
+                path: String::new(),
+                message: format!("integer {} does not fit in a Lua integer", n),
+            }),
+        }
+    }
+
+    /// Pushes `bytes` as a Lua string. Lua strings are byte strings, so
+    /// embedded NULs and invalid UTF-8 are kept as they are.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack cannot grow. Use
+    /// [`try_push_bytes`](#method.try_push_bytes) to handle that case instead.
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        let res = self.try_push_bytes(bytes);
+        self.expect_ok(res);
+    }
+
+    /// Pushes `bytes` as a Lua string, returning `LuaError::StackOverflow`
+    /// if the stack cannot grow.
+    pub fn try_push_bytes(&mut self, bytes: &[u8]) -> Result<(), LuaError> {
+        self.grow(1)?;
+        unsafe {
+            lua_pushlstring(self.state, bytes.as_ptr() as *const c_schar, bytes.len());
+        }
+
+        Ok(())
     }
 
     /// Push a new nil value onto the Lua stack.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack cannot grow. Use
+    /// [`try_push_nil`](#method.try_push_nil) to handle that case instead.
     pub fn push_nil(&mut self) {
-        self.checkstack(1);
+        let res = self.try_push_nil();
+        self.expect_ok(res);
+    }
+
+    /// Pushes nil, returning `LuaError::StackOverflow` if the stack cannot
+    /// grow.
+    pub fn try_push_nil(&mut self) -> Result<(), LuaError> {
+        self.grow(1)?;
         unsafe {
             lua_pushnil(self.state);
         }
+
+        Ok(())
     }
 
     /// Gets a value from the globals object and pushes it to the
     /// top of the stack.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack cannot grow. Use
+    /// [`try_get_global`](#method.try_get_global) to handle that case instead.
     pub fn get_global(&mut self, name: &str) {
-        self.checkstack(1);
+        let res = self.try_get_global(name);
+        self.expect_ok(res);
+    }
+
+    /// Pushes the global `name`, returning `LuaError::StackOverflow` if the
+    /// stack cannot grow.
+    pub fn try_get_global(&mut self, name: &str) -> Result<(), LuaError> {
+        let name = CString::new(name).unwrap();
+        self.grow(1)?;
         unsafe {
-            lua_getglobal(self.state, CString::new(name).unwrap().as_ptr() as *const c_schar);
+            lua_getglobal(self.state, name.as_ptr() as *const c_schar);
         }
+
+        Ok(())
     }
 
     /// Gets a value `name` from the table on the stack at `idx` and
     /// and pushes the fetched value to the top of the stack.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack cannot grow. Use
+    /// [`try_get_field`](#method.try_get_field) to handle that case instead.
     pub fn get_field(&mut self, idx: i32, name: &str) {
-        self.checkstack(1);
+        let res = self.try_get_field(idx, name);
+        self.expect_ok(res);
+    }
+
+    /// Pushes the field `name` of the table at `idx`, returning
+    /// `LuaError::StackOverflow` if the stack cannot grow.
+    pub fn try_get_field(&mut self, idx: i32, name: &str) -> Result<(), LuaError> {
+        let name = CString::new(name).unwrap();
+        self.grow(1)?;
         unsafe {
-            lua_getfield(self.state, idx, CString::new(name).unwrap().as_ptr() as *const c_schar);
+            lua_getfield(self.state, idx, name.as_ptr() as *const c_schar);
         }
+
+        Ok(())
     }
 
     /// Creates a new table and pushes it to the top of the stack
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack cannot grow. Use
+    /// [`try_new_table`](#method.try_new_table) to handle that case instead.
     pub fn new_table(&mut self) {
-        self.checkstack(1);
+        let res = self.try_new_table();
+        self.expect_ok(res);
+    }
+
+    /// Creates a new table and pushes it, returning
+    /// `LuaError::StackOverflow` if the stack cannot grow.
+    pub fn try_new_table(&mut self) -> Result<(), LuaError> {
+        self.grow(1)?;
         unsafe {
             lua_newtable(self.state);
         }
+
+        Ok(())
     }
 
     /// Allocates a new Lua userdata block, and returns the pointer
     /// to it. The returned pointer is owned by the Lua state.
     pub fn new_raw_userdata(&mut self, sz: usize) -> *mut c_void {
-        self.checkstack(1);
+        self.new_raw_userdata_uv(sz, 0)
+    }
+
+    /// Allocates a new Lua userdata block with `nuvalue` user values, all
+    /// `nil`, and returns the pointer to it. The returned pointer is owned
+    /// by the Lua state.
+    pub fn new_raw_userdata_uv(&mut self, sz: usize, nuvalue: c_int) -> *mut c_void {
+        self.ensure(2);
         unsafe {
-            let new_ptr = lua_newuserdata(self.state, sz);
-            if new_ptr == ptr::null_mut() {
+            let new_ptr = lua_newuserdatauv(self.state, sz, nuvalue);
+            if new_ptr.is_null() {
                 panic!("Lua returned null pointer allocating new userdata");
             }
 
@@ -587,6 +1497,50 @@ impl State {
         }
     }
 
+    /// Pushes the `n`th user value of the userdata at `idx`, counting from
+    /// 1. Pushes `nil` and returns `false` if it has no such user value.
+    ///
+    /// User values are traced by the garbage collector, so they stay alive
+    /// exactly as long as the userdata holding them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// extern crate lua_ffi;
+    ///
+    /// use lua_ffi::State;
+    ///
+    /// fn main() {
+    ///     let mut state = State::new();
+    ///     state.new_raw_userdata_uv(8, 2);
+    ///
+    ///     state.push("callback");
+    ///     assert!(state.set_user_value(-2, 2));
+    ///
+    ///     assert!(state.get_user_value(-1, 2));
+    ///     assert_eq!(state.to_str(-1), Some("callback"));
+    ///
+    ///     state.pop(1);
+    ///     assert!(!state.get_user_value(-1, 3));
+    /// }
+    /// ```
+    pub fn get_user_value(&mut self, idx: c_int, n: c_int) -> bool {
+        self.ensure(3);
+        unsafe {
+            lua_getiuservalue(self.state, idx, n) != LUA_TNONE
+        }
+    }
+
+    /// Pops a value and stores it as the `n`th user value of the userdata
+    /// at `idx`, counting from 1. Returns `false` and discards the value if
+    /// it has no such user value.
+    pub fn set_user_value(&mut self, idx: c_int, n: c_int) -> bool {
+        self.ensure(2);
+        unsafe {
+            lua_setiuservalue(self.state, idx, n) != 0
+        }
+    }
+
     /// Allocates a new Lua userdata block of size `sizeof(T)` for
     /// use to store Rust objects on the Lua stack. The returned
     /// pointer is owned by the Lua state.
@@ -596,9 +1550,9 @@ impl State {
     /// Useful for pushing an arbitrary struct to the Lua stack
     ///
     /// ```
-    /// extern crate luajit;
+    /// extern crate lua_ffi;
     ///
-    /// use luajit::State;
+    /// use lua_ffi::State;
     ///
     /// struct Point2D {
     ///     x: i32,
@@ -631,7 +1585,7 @@ impl State {
     ///     let mut state = State::new();
     ///     state.open_libs();
     ///     unsafe {
-    ///         *state.new_userdata() = Point2D::new();
+    ///         std::ptr::write(state.new_userdata(), Point2D::new());
     ///     }
     ///
     ///     let point: &mut Point2D = unsafe { &mut *state.to_userdata(-1).unwrap() };
@@ -648,79 +1602,218 @@ impl State {
 
     /// Registers all of the methods for LuaObject `T` as a global metatable
     /// with name `struct_type` and leaves it on the top of the stack.
+    ///
+    /// Indexing an instance looks up the fields returned by `T::lua_fields`
+    /// first, then the methods, and raises an error for any other key.
+    /// Assigning to an instance is only allowed for fields with a setter.
+    /// The metamethods returned by `T::lua_metamethods` are added to the
+    /// metatable, and instances are dropped when Lua collects them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a different type has already registered a metatable with
+    /// the same name.
     pub fn register_struct<T>(&mut self) where T: LuaObject {
         unsafe {
-            if luaL_newmetatable(self.state, T::name()) == 1 {
+            if luaL_newmetatable(self.state, T::name()) == 0 {
+                if !userdata::is_metatable_of::<T>(self) {
+                    panic!("metatable {} is already registered for another type", userdata::type_name::<T>());
+                }
+            } else {
+                let mt = lua_gettop(self.state);
+                userdata::set_metatable_type::<T>(self);
+
+                let parent = T::lua_parent();
+                if let Some(ref parent) = parent {
+                    (parent.register)(self);
+                    userdata::add_upcasts::<T>(self, parent);
+                }
+
                 self.new_table();
                 self.register_fns(None, T::lua_fns());
 
-                self.push_value(-1);
-                lua_setglobal(self.state, T::name());
+                userdata::push_class::<T>(self);
+                if T::lua_global() {
+                    self.push_value(-1);
+                    lua_setglobal(self.state, T::name());
+                }
+                userdata::set_class(self, mt);
+
+                // Stack: metatable, methods, parent metatable
+                if parent.is_some() {
+                    lua_rotate(self.state, -2, 1);
+                } else {
+                    lua_pushnil(self.state);
+                }
 
-                self.set_field(-2, "__index");
+                userdata::set_dispatch::<T>(self);
+                userdata::set_metamethods::<T>(self);
             }
         }
     }
 
-    /// Allocates a userdata object on the Lua stack for storing a rust struct.
-    /// This method also sets the userdata object's metatable to the metatable
-    /// saved for `struct_type`, it will call `lua_fns` and create a new metatable
-    /// to store in the Lua registry if one has not already been created.
-    pub(crate) fn new_struct<T>(&mut self) -> *mut T where T: LuaObject {
-        let userdata = self.new_userdata();
+    /// Pushes the class table of `T`, registering `T` first if needed. It
+    /// holds the static functions and constants of `T`, falls back to the
+    /// functions of `T::lua_fns`, and calling it calls its `new` function.
+    /// Unless `T::lua_global` returns `false` it is also published as a
+    /// global named after `T`.
+    pub fn push_class<T>(&mut self) where T: LuaObject {
+        self.register_struct::<T>();
+        userdata::get_class(self);
+    }
+
+    /// Moves `val` into a new userdata object on the top of the stack. The
+    /// userdata object's metatable is set to the metatable registered for `T`,
+    /// which is created first if needed, and it is tagged with the type of
+    /// `T` so later borrows can verify it.
+    pub(crate) fn push_struct<T>(&mut self, val: T) where T: LuaObject {
+        self.push_slot(Slot::Owned(val));
+    }
+
+    /// Pushes a userdata for `slot`, with the metatable registered for `T`.
+    pub(crate) fn push_slot<T>(&mut self, slot: Slot<T>) where T: LuaObject {
+        userdata::push_tagged(self, slot);
+        self.register_struct::<T>();
 
         unsafe {
-            self.register_struct::<T>();
-
             lua_setmetatable(self.state, -2);
         }
+    }
 
-        userdata
+    /// Runs `f` with a [`Scope`](../struct.Scope.html) that can hand Lua
+    /// references to Rust values instead of moving them into userdata.
+    ///
+    /// Every userdata created by the scope is invalidated when `f` returns
+    /// or panics. Lua code keeping one around gets an error when it uses
+    /// it afterwards, rather than reaching a dangling reference.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[macro_use] extern crate lua_ffi;
+    /// use lua_ffi::{LuaField, LuaObject, State, ThreadStatus};
+    /// use lua_ffi::ffi::luaL_Reg;
+    ///
+    /// struct Player {
+    ///     hp: i32,
+    /// }
+    ///
+    /// impl LuaObject for Player {
+    ///     fn name() -> *const i8 {
+    ///         c_str!("Player")
+    ///     }
+    ///
+    ///     fn lua_fns() -> Vec<luaL_Reg> {
+    ///         vec!()
+    ///     }
+    ///
+    ///     fn lua_fields() -> Vec<LuaField<Player>> {
+    ///         vec!(LuaField::new("hp").getter(|p: &Player| p.hp).setter(|p: &mut Player, hp| p.hp = hp))
+    ///     }
+    /// }
+    ///
+    /// fn main() {
+    ///     let mut state = State::new();
+    ///     state.open_libs();
+    ///     let mut player = Player { hp: 10 };
+    ///
+    ///     state.scope(|s| {
+    ///         s.push_ref(&mut player);
+    ///         s.set_global("player");
+    ///         s.do_string("player.hp = player.hp - 3");
+    ///     });
+    ///
+    ///     assert_eq!(player.hp, 7);
+    ///     assert_eq!(state.do_string("return player.hp"), ThreadStatus::RuntimeError);
+    /// }
+    /// ```
+    pub fn scope<'scope, F, R>(&'scope mut self, f: F) -> R where F: FnOnce(&mut Scope<'scope>) -> R {
+        let mut scope = Scope::new(self.state);
+        f(&mut scope)
     }
 
-    /// Maps to `luaL_loadfile`, this method validates that the file exists
-    /// before passing it into the Lua C API.
-    pub fn load_file(&mut self, path: &Path) -> Result<(), (ThreadStatus, String)> {
-        if path.is_file() {
-            let p = path.canonicalize().unwrap();
-            let full_path = p.to_string_lossy();
+    /// Maps to `luaL_loadfile`, loading the file at `path` as a chunk named
+    /// after `path` and pushing it as a function.
+    ///
+    /// On failure nothing is pushed. A file that cannot be opened or read
+    /// is returned as a `LuaError::File`, and other errors as by
+    /// [`load_chunk`](#method.load_chunk).
+    ///
+    /// Once a [`Reloader`](../reload/struct.Reloader.html) was created, the
+    /// file is remembered by its canonical path so it can be reloaded.
+    pub fn load_file(&mut self, path: &Path) -> Result<(), LuaError> {
+        let name = CString::new(path.to_string_lossy().as_ref())
+            .map_err(|_| LuaError::File(format!("cannot open {}: path contains a NUL", path.display())))?;
+
+        self.grow(1)?;
+        let canonical = reload::watched(path);
+        let modified = canonical.as_ref().and_then(|canonical| reload::modified(canonical));
+        let status = unsafe { luaL_loadfilex(self.state, name.as_ptr(), sandbox::load_mode(self.state)) };
+        if status != LUA_OK {
+            return Err(self.load_error(status.into()));
+        }
 
-            unsafe {
-                let cstr = CString::new(full_path.as_ref()).unwrap();
-                let res: ThreadStatus = luaL_loadfilex(self.state, cstr.as_ptr() as *const i8, null()).into();
-                if res != ThreadStatus::Ok {
-                    Err((res, self.to_str(-1).unwrap_or_default().to_owned()))
-                } else {
-                    Ok(())
-                }
-            }
-        } else {
-            Err((ThreadStatus::FileError, "Path does not exist".to_owned()))
+        if let Some(canonical) = canonical {
+            reload::record(self.state, &canonical, path, modified);
         }
+        Ok(())
     }
 
-    /// Equivalent of `luaL_dofile`, loads a file and then immediately executes
-    /// it with `pcall`, returning the result.
-    pub fn do_file(&mut self, path: &Path) -> Result<(), (ThreadStatus, String)> {
-        self.load_file(path).and_then(|_| {
-            self.pcall(0, LUA_MULTIRET, 0)
-        })
+    /// Equivalent of `luaL_dofile`, loads a file and runs it, leaving its
+    /// results on the stack. Errors are returned like by
+    /// [`load_file`](#method.load_file) and
+    /// [`pcall_with_traceback`](#method.pcall_with_traceback), after going
+    /// through the message handler set with
+    /// [`set_default_message_handler`](#method.set_default_message_handler).
+    pub fn do_file(&mut self, path: &Path) -> Result<(), LuaError> {
+        self.load_file(path)?;
+        self.pcall_with_traceback(0, LUA_MULTIRET)
     }
 
     /// Ensures that there are at least `n` free stack slots in the stack. Returns
     /// false if it cannot grow the stack to that size.
     pub fn checkstack(&mut self, n: usize) -> bool {
-        unsafe {
-            lua_checkstack(self.state, n as c_int) != 0
+        n <= LUAI_MAXSTACK as usize && unsafe { lua_checkstack(self.state, n as c_int) != 0 }
+    }
+
+    // Ensures there are `n` free stack slots, returning
+    // `LuaError::StackOverflow` if the stack cannot grow to that size.
+    fn grow(&mut self, n: c_int) -> Result<(), LuaError> {
+        if self.checkstack(n as usize) {
+            Ok(())
+        } else {
+            Err(LuaError::StackOverflow)
         }
     }
+
+    // Like `grow`, but panics, for the methods documented to panic when
+    // the stack is full.
+    fn ensure(&mut self, n: c_int) {
+        let res = self.grow(n);
+        self.expect_ok(res);
+    }
+
+    // Panics with the error of a fallible helper. Raising it as a Lua
+    // error instead would jump over the destructors of the caller, and
+    // abort outside of a protected call.
+    fn expect_ok(&self, res: Result<(), LuaError>) {
+        if let Err(err) = res {
+            panic!("{}", err);
+        }
+    }
+}
+
+impl Default for State {
+    fn default() -> State {
+        State::new()
+    }
 }
 
 impl Drop for State {
     fn drop(&mut self) {
         if self.owned {
             unsafe {
-                lua_close(self.state);
+                sandbox::close(self.state);
             }
         }
     }
@@ -0,0 +1,506 @@
+//! Runs unit tests written in Lua, in files named `*_test.lua`.
+//!
+//! Test files group cases with `describe`, define them with `it`, and check
+//! values with `assert_eq(actual, expected [, message])`, which compares
+//! tables by their contents:
+//!
+//! ```lua
+//! describe("Point2D", function()
+//!     it("adds its coordinates", function()
+//!         local point = Point2D:new()
+//!         point:setX(2)
+//!         assert_eq(point:add(), 2)
+//!     end)
+//! end)
+//! ```
+//!
+//! Every case runs in a fresh `State` prepared by the setup function of the
+//! [`Harness`](struct.Harness.html), so cases cannot leak globals into each
+//! other: the file is run once per case, skipping the bodies of the other
+//! cases. Failed cases are reported with the line defining them and the
+//! error they raised.
+//!
+//! From a `#[test]`, panicking if a case failed:
+//!
+//! ```no_run
+//! extern crate lua_ffi;
+//!
+//! use lua_ffi::testing::Harness;
+//!
+//! fn main() {
+//!     Harness::discover("tests/lua").unwrap()
+//!         .setup(|state| state.open_libs())
+//!         .run()
+//!         .assert_success();
+//! }
+//! ```
+//!
+//! Or as a test target of its own, with `harness = false` set for it in
+//! `Cargo.toml`, by ending `main` with [`Report::exit`](struct.Report.html#method.exit)
+//! instead.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::raw::c_schar;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use libc::{c_int, c_void};
+
+use super::error::{self, LuaError};
+use super::ffi::*;
+use super::pretty::pretty;
+use super::value::LuaType;
+use super::State;
+
+/// Prepares the state a test case runs in.
+type SetupFn = Box<dyn Fn(&mut State)>;
+
+/// Finds and runs Lua test files.
+pub struct Harness {
+    files: Vec<PathBuf>,
+    setup: Option<SetupFn>,
+}
+
+impl Harness {
+    /// Creates a harness with no test files.
+    pub fn new() -> Harness {
+        Harness {
+            files: Vec::new(),
+            setup: None,
+        }
+    }
+
+    /// Creates a harness running every `*_test.lua` file in `dir` and its
+    /// subdirectories, in the order of their paths.
+    pub fn discover<P>(dir: P) -> io::Result<Harness> where P: AsRef<Path> {
+        let mut harness = Harness::new();
+        find_tests(dir.as_ref(), &mut harness.files)?;
+        harness.files.sort();
+
+        Ok(harness)
+    }
+
+    /// Adds the test file at `path`, whatever its name.
+    pub fn file<P>(mut self, path: P) -> Harness where P: Into<PathBuf> {
+        self.files.push(path.into());
+        self
+    }
+
+    /// Sets the function preparing the state of every test case, such as
+    /// to open libraries and register bindings. Without one, cases run
+    /// with the standard library.
+    pub fn setup<F>(mut self, f: F) -> Harness where F: Fn(&mut State) + 'static {
+        self.setup = Some(Box::new(f));
+        self
+    }
+
+    /// Returns the test files this harness runs.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Runs every case of every test file.
+    pub fn run(&self) -> Report {
+        let mut cases = Vec::new();
+        for path in &self.files {
+            self.run_file(path, &mut cases);
+        }
+
+        Report { cases }
+    }
+
+    fn run_file(&self, path: &Path, cases: &mut Vec<Case>) {
+        let failed = |error| Case {
+            file: path.to_owned(),
+            name: String::new(),
+            line: 0,
+            error: Some(error),
+        };
+
+        let code = match fs::read(path) {
+            Ok(code) => code,
+            Err(err) => {
+                let message = format!("cannot read {}: {}", path.display(), err);
+                cases.push(failed(LuaError::Runtime { message, traceback: None }));
+                return;
+            }
+        };
+
+        // A first run lists the cases without running them.
+        let mut listing = Run::new(None);
+        if let Err(err) = self.exec(path, &code, &mut listing) {
+            cases.push(failed(err));
+            return;
+        }
+
+        for (index, (name, line)) in listing.found.into_iter().enumerate() {
+            let mut run = Run::new(Some(index));
+            let res = self.exec(path, &code, &mut run);
+            let error = match run.outcome {
+                Some(outcome) => outcome.err(),
+                None => Some(res.err().unwrap_or_else(|| LuaError::Runtime {
+                    message: "the case was not reached".to_owned(),
+                    traceback: None,
+                })),
+            };
+
+            cases.push(Case {
+                file: path.to_owned(),
+                name,
+                line,
+                error,
+            });
+        }
+    }
+
+    fn exec(&self, path: &Path, code: &[u8], run: &mut Run) -> Result<(), LuaError> {
+        let mut state = State::new();
+        match self.setup {
+            Some(ref setup) => setup(&mut state),
+            None => state.open_libs(),
+        }
+
+        let l = state.as_ptr();
+        let run = run as *mut Run as *mut c_void;
+        state.checkstack(1);
+        unsafe {
+            lua_pushlightuserdata(l, run);
+            lua_pushcclosure(l, Some(lua_describe), 1);
+            lua_setglobal(l, b"describe\x00".as_ptr() as *const c_schar);
+            lua_pushlightuserdata(l, run);
+            lua_pushcclosure(l, Some(lua_it), 1);
+            lua_setglobal(l, b"it\x00".as_ptr() as *const c_schar);
+        }
+        state.register("assert_eq", lua_assert_eq);
+
+        state.do_chunk(code, &format!("@{}", path.display()))
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Harness {
+        Harness::new()
+    }
+}
+
+fn find_tests(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_tests(&path, files)?;
+        } else if path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.ends_with("_test.lua")) {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// The outcome of a test case.
+#[derive(Debug)]
+pub struct Case {
+    /// The test file defining the case.
+    pub file: PathBuf,
+    /// The names of the enclosing `describe` blocks and of the case,
+    /// separated by spaces. Empty for an error raised by the file outside
+    /// of any case, which stops its cases from running.
+    pub name: String,
+    /// The line of the `it` call defining the case.
+    pub line: u32,
+    /// The error the case raised, if it failed.
+    pub error: Option<LuaError>,
+}
+
+impl Case {
+    /// Returns whether the case passed.
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// The outcome of all the test cases run by a `Harness`.
+#[derive(Debug)]
+pub struct Report {
+    pub cases: Vec<Case>,
+}
+
+impl Report {
+    /// Returns the number of cases that passed.
+    pub fn passed(&self) -> usize {
+        self.cases.iter().filter(|case| case.passed()).count()
+    }
+
+    /// Returns the number of cases that failed.
+    pub fn failed(&self) -> usize {
+        self.cases.len() - self.passed()
+    }
+
+    /// Returns whether every case passed.
+    pub fn is_success(&self) -> bool {
+        self.failed() == 0
+    }
+
+    /// Panics with the report if a case failed.
+    pub fn assert_success(&self) {
+        if !self.is_success() {
+            panic!("Lua tests failed:\n{}", self);
+        }
+    }
+
+    /// Prints the report and exits the process, with status 101 if a case
+    /// failed, like the standard test harness.
+    pub fn exit(&self) -> ! {
+        print!("{}", self);
+        process::exit(if self.is_success() { 0 } else { 101 })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for case in &self.cases {
+            let status = if case.passed() { "ok  " } else { "FAIL" };
+            if case.name.is_empty() {
+                writeln!(f, "{} {}", status, case.file.display())?;
+            } else {
+                writeln!(f, "{} {}:{}: {}", status, case.file.display(), case.line, case.name)?;
+            }
+
+            if let Some(ref err) = case.error {
+                for line in err.to_string().lines() {
+                    writeln!(f, "    {}", line)?;
+                }
+            }
+        }
+
+        writeln!(f, "{} passed, {} failed", self.passed(), self.failed())
+    }
+}
+
+/// A run of a test file.
+struct Run {
+    /// The index of the case to run, or `None` to only list the cases.
+    target: Option<usize>,
+    /// The names of the enclosing `describe` blocks.
+    prefix: Vec<String>,
+    /// The names of the cases seen so far, with the line defining them.
+    found: Vec<(String, u32)>,
+    /// The outcome of the target case, once it ran.
+    outcome: Option<Result<(), LuaError>>,
+}
+
+impl Run {
+    fn new(target: Option<usize>) -> Run {
+        Run {
+            target,
+            prefix: Vec::new(),
+            found: Vec::new(),
+            outcome: None,
+        }
+    }
+}
+
+unsafe extern "C" fn lua_describe(l: *mut lua_State) -> c_int {
+    let run = &mut *(lua_touserdata(l, lua_upvalueindex(1)) as *mut Run);
+    let res = describe(&mut State::from_ptr(l), run);
+
+    match res {
+        Ok(()) => 0,
+        // The traceback is rebuilt by the call running the file.
+        Err(LuaError::Runtime { message, .. }) => error::raise(l, message),
+        Err(err) => error::raise_error(l, err),
+    }
+}
+
+fn describe(state: &mut State, run: &mut Run) -> Result<(), LuaError> {
+    let name = state.check_arg::<String>(1)?;
+    check_function(state, 2)?;
+
+    run.prefix.push(name);
+    state.settop(2);
+    let res = state.pcall_with_traceback(0, 0);
+    run.prefix.pop();
+
+    res
+}
+
+unsafe extern "C" fn lua_it(l: *mut lua_State) -> c_int {
+    let run = &mut *(lua_touserdata(l, lua_upvalueindex(1)) as *mut Run);
+    let res = it(&mut State::from_ptr(l), run);
+
+    match res {
+        Ok(()) => 0,
+        Err(err) => error::raise_error(l, err),
+    }
+}
+
+fn it(state: &mut State, run: &mut Run) -> Result<(), LuaError> {
+    let name = state.check_arg::<String>(1)?;
+    check_function(state, 2)?;
+
+    let mut full_name = run.prefix.join(" ");
+    if !full_name.is_empty() {
+        full_name.push(' ');
+    }
+    full_name.push_str(&name);
+
+    let index = run.found.len();
+    run.found.push((full_name, caller_line(state)));
+
+    if run.target == Some(index) {
+        state.settop(2);
+        run.outcome = Some(state.pcall_with_traceback(0, 0));
+    }
+
+    Ok(())
+}
+
+fn check_function(state: &mut State, n: c_int) -> Result<(), LuaError> {
+    if state.type_of(n) == LuaType::Function {
+        Ok(())
+    } else {
+        let message = format!("function expected, got {}", state.typename_of(n));
+        Err(state.arg_error(n, message))
+    }
+}
+
+/// Returns the current line of the function calling the running one.
+fn caller_line(state: &mut State) -> u32 {
+    unsafe {
+        let l = state.as_ptr();
+        let mut ar: lua_Debug = mem::zeroed();
+        if lua_getstack(l, 1, &mut ar) == 0 || lua_getinfo(l, b"l\0".as_ptr() as *const c_schar, &mut ar) == 0 {
+            return 0;
+        }
+
+        ar.currentline.max(0) as u32
+    }
+}
+
+unsafe extern "C" fn lua_assert_eq(l: *mut lua_State) -> c_int {
+    let res = assert_eq(&mut State::from_ptr(l));
+
+    match res {
+        Ok(()) => 0,
+        Err(msg) => error::raise(l, msg),
+    }
+}
+
+fn assert_eq(state: &mut State) -> Result<(), String> {
+    if state.gettop() < 2 {
+        return Err(state.arg_error(2, "value expected".to_owned()).to_string());
+    }
+
+    if equal(state, 1, 2, &mut Vec::new())? {
+        return Ok(());
+    }
+
+    let mut message = format!("expected {}, got {}", pretty(state, 2), pretty(state, 1));
+    if state.type_of(3) == LuaType::String {
+        message = format!("{}: {}", state.to_str(3).unwrap_or_default(), message);
+    }
+
+    let l = state.as_ptr();
+    state.checkstack(1);
+    unsafe {
+        luaL_where(l, 1);
+    }
+    let location = state.to_str(-1).unwrap_or_default().to_owned();
+    state.pop(1);
+
+    Err(location + &message)
+}
+
+/// Compares the values at the absolute indices `a` and `b` like `==`, but
+/// tables without an `__eq` metamethod by their contents. `path` holds the
+/// pairs of tables being compared, which are taken as equal when they recur
+/// so that cycles end.
+fn equal(state: &mut State, a: c_int, b: c_int, path: &mut Vec<(*const c_void, *const c_void)>) -> Result<bool, String> {
+    let l = state.as_ptr();
+    state.checkstack(3);
+
+    unsafe {
+        if lua_rawequal(l, a, b) != 0 {
+            return Ok(true);
+        }
+
+        let (ta, tb) = (lua_type(l, a), lua_type(l, b));
+        if ta != tb || (ta != LUA_TTABLE && ta != LUA_TUSERDATA) {
+            return Ok(false);
+        }
+        if ta == LUA_TUSERDATA || has_eq(l, a) || has_eq(l, b) {
+            return compare(state, a, b);
+        }
+
+        let pair = (lua_topointer(l, a), lua_topointer(l, b));
+        if path.contains(&pair) {
+            return Ok(true);
+        }
+        path.push(pair);
+
+        let mut len = 0;
+        lua_pushnil(l);
+        while lua_next(l, a) != 0 {
+            len += 1;
+            lua_pushvalue(l, -2);
+            lua_rawget(l, b);
+
+            let top = lua_gettop(l);
+            let same = lua_type(l, top) != LUA_TNIL && equal(state, top - 1, top, path)?;
+            lua_settop(l, -3);
+            if !same {
+                lua_settop(l, -2);
+                path.pop();
+                return Ok(false);
+            }
+        }
+
+        lua_pushnil(l);
+        while lua_next(l, b) != 0 {
+            len -= 1;
+            lua_settop(l, -2);
+        }
+
+        path.pop();
+        Ok(len == 0)
+    }
+}
+
+unsafe fn has_eq(l: *mut lua_State, idx: c_int) -> bool {
+    if luaL_getmetafield(l, idx, b"__eq\x00".as_ptr() as *const c_schar) == LUA_TNIL {
+        false
+    } else {
+        lua_settop(l, -2);
+        true
+    }
+}
+
+/// Compares two values with `==` in a protected call, as `__eq` may raise
+/// an error.
+fn compare(state: &mut State, a: c_int, b: c_int) -> Result<bool, String> {
+    unsafe extern "C" fn eq(l: *mut lua_State) -> c_int {
+        let eq = lua_compare(l, 1, 2, LUA_OPEQ);
+        lua_pushboolean(l, eq);
+
+        1
+    }
+
+    let l = state.as_ptr();
+    unsafe {
+        lua_pushcfunction(l, Some(eq));
+        lua_pushvalue(l, a);
+        lua_pushvalue(l, b);
+        if lua_pcallk(l, 2, 1, 0, 0, None) != LUA_OK {
+            let message = state.to_str(-1).unwrap_or_default().to_owned();
+            state.pop(1);
+            return Err(message);
+        }
+
+        let eq = lua_toboolean(l, -1) != 0;
+        state.pop(1);
+
+        Ok(eq)
+    }
+}
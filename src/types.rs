@@ -1,66 +1,104 @@
-use std::ffi::CString;
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::ops::Deref;
 use std::os::raw::c_schar;
+use std::rc::Rc;
+use std::str;
 
-use libc::{c_int, ptrdiff_t};
+use libc::{c_int, c_void};
 
 use super::ffi;
+use super::error::LuaError;
+use super::metamethod::LuaMetaMethod;
+use super::userdata::Cast;
 use super::State;
 
 /// Represents any value that can be pushed onto the Lua stack
 pub trait LuaValue {
     /// `push_val` should push the value of this type the the top
     /// of the stack on Lua state `l`.
+    ///
+    /// `State::push` makes room for `LUA_MINSTACK` values before calling
+    /// it. Implementations needing more, such as nested tables, must call
+    /// `lua_checkstack` themselves.
     fn push_val(self, l: *mut ffi::lua_State);
-}
 
-impl LuaValue for i32 {
-    fn push_val(self, l: *mut ffi::lua_State) {
-        unsafe {
-            ffi::lua_pushinteger(l, self as ptrdiff_t)
-        }
+    /// Pushes the value like `push_val`, but returns an error, leaving the
+    /// stack as it was, when the value has no Lua representation. Types
+    /// that always convert keep this default.
+    fn try_push_val(self, l: *mut ffi::lua_State) -> Result<(), LuaError> where Self: Sized {
+        self.push_val(l);
+        Ok(())
     }
 }
 
-impl LuaValue for i64 {
-    fn push_val(self, l: *mut ffi::lua_State) {
-        unsafe {
-            ffi::lua_pushinteger(l, self as ptrdiff_t)
-        }
+/// Panics with the error of a failed `try_push_val`, for the `push_val` of
+/// types that cannot always be pushed.
+pub(crate) fn unwrap_push(res: Result<(), LuaError>) {
+    if let Err(err) = res {
+        panic!("{}", err);
     }
 }
 
-impl LuaValue for u32 {
-    fn push_val(self, l: *mut ffi::lua_State) {
-        unsafe {
-            ffi::lua_pushinteger(l, self as ptrdiff_t)
-        }
+macro_rules! push_integer {
+    ($($t:ty),*) => {
+        $(
+            impl LuaValue for $t {
+                fn push_val(self, l: *mut ffi::lua_State) {
+                    unsafe {
+                        ffi::lua_pushinteger(l, self as ffi::lua_Integer)
+                    }
+                }
+            }
+        )*
     }
 }
 
-impl LuaValue for u64 {
-    fn push_val(self, l: *mut ffi::lua_State) {
-        unsafe {
-            ffi::lua_pushinteger(l, self as ptrdiff_t)
-        }
+push_integer!(i8, i16, i32, i64, u8, u16, u32);
+
+macro_rules! push_checked_integer {
+    ($($t:ty),*) => {
+        $(
+            /// Values that do not fit in a 64-bit Lua integer fail to push:
+            /// `State::push` raises, `State::try_push` and functions called
+            /// from Lua return the error, and `push_val` itself panics.
+            impl LuaValue for $t {
+                fn push_val(self, l: *mut ffi::lua_State) {
+                    unwrap_push(self.try_push_val(l));
+                }
+
+                fn try_push_val(self, l: *mut ffi::lua_State) -> Result<(), LuaError> {
+                    match i64::try_from(self) {
+                        Ok(n) => {
+                            unsafe { ffi::lua_pushinteger(l, n) };
+                            Ok(())
+                        }
+                        Err(_) => Err(LuaError::Conversion {
+                            path: String::new(),
+                            message: format!("{} {} does not fit in a Lua integer", stringify!($t), self),
+                        }),
+                    }
+                }
+            }
+        )*
     }
 }
 
-impl<'a> LuaValue for &'a str {
+push_checked_integer!(u64, isize, usize, i128, u128);
+
+impl LuaValue for &str {
     fn push_val(self, l: *mut ffi::lua_State) {
-        let cstr = CString::new(self).unwrap();
         unsafe {
-            ffi::lua_pushstring(l, cstr.as_ptr() as *const c_schar);
+            ffi::lua_pushlstring(l, self.as_ptr() as *const c_schar, self.len());
         }
     }
 }
 
 impl LuaValue for String {
     fn push_val(self, l: *mut ffi::lua_State) {
-        let r: &str = self.as_ref();
-        let cstr = CString::new(r).unwrap();
-        unsafe {
-            ffi::lua_pushstring(l, cstr.as_ptr() as *const c_schar);
-        }
+        self.as_str().push_val(l);
     }
 }
 
@@ -100,35 +138,429 @@ impl LuaValue for LuaFunction {
     }
 }
 
-impl LuaValue for Option<LuaFunction> {
+impl<T> LuaValue for Option<T> where T: LuaValue {
     fn push_val(self, l: *mut ffi::lua_State) {
-        if let Some(func) = self {
-            unsafe {
-                ffi::lua_pushcfunction(l, Some(func));
-            }
+        unwrap_push(self.try_push_val(l));
+    }
+
+    fn try_push_val(self, l: *mut ffi::lua_State) -> Result<(), LuaError> {
+        if let Some(val) = self {
+            val.try_push_val(l)
         } else {
             unsafe {
                 ffi::lua_pushnil(l);
             }
+
+            Ok(())
         }
     }
 }
 
-impl<T> LuaValue for T where T: LuaObject {
+/// A Lua number, keeping the integer or float subtype Lua 5.3 gives it.
+///
+/// Integers and floats compare equal in Lua when their values are, but
+/// they are distinct subtypes: `3` and `3.0` print differently and `//`
+/// and bitwise operators treat them differently. Reading a `LuaNumber` and
+/// pushing it back preserves the subtype.
+///
+/// # Examples
+///
+/// ```
+/// extern crate lua_ffi;
+///
+/// use lua_ffi::{LuaNumber, State};
+///
+/// fn main() {
+///     let mut state = State::new();
+///     state.do_string("return 3, 3.0, 2^63");
+///
+///     assert_eq!(state.to(-3), Ok(LuaNumber::Integer(3)));
+///     assert_eq!(state.to(-2), Ok(LuaNumber::Float(3.0)));
+///     assert_eq!(state.to::<LuaNumber>(-1).map(|n| n.to_i64()), Ok(None));
+/// }
+/// ```
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum LuaNumber {
+    Integer(i64),
+    Float(f64),
+}
+
+impl LuaNumber {
+    /// Returns whether the number has the integer subtype.
+    pub fn is_integer(&self) -> bool {
+        match *self {
+            LuaNumber::Integer(_) => true,
+            LuaNumber::Float(_) => false,
+        }
+    }
+
+    /// Returns the number as an integer, if it is one or is a float with an
+    /// exact integer representation.
+    pub fn to_i64(&self) -> Option<i64> {
+        match *self {
+            LuaNumber::Integer(n) => Some(n),
+            LuaNumber::Float(f) if f.fract() == 0.0 && f >= -(2f64.powi(63)) && f < 2f64.powi(63) => Some(f as i64),
+            LuaNumber::Float(_) => None,
+        }
+    }
+
+    /// Returns the number as a float, rounding integers beyond 2^53.
+    pub fn to_f64(&self) -> f64 {
+        match *self {
+            LuaNumber::Integer(n) => n as f64,
+            LuaNumber::Float(f) => f,
+        }
+    }
+}
+
+impl From<i64> for LuaNumber {
+    fn from(n: i64) -> LuaNumber {
+        LuaNumber::Integer(n)
+    }
+}
+
+impl From<f64> for LuaNumber {
+    fn from(f: f64) -> LuaNumber {
+        LuaNumber::Float(f)
+    }
+}
+
+impl LuaValue for LuaNumber {
     fn push_val(self, l: *mut ffi::lua_State) {
-        let mut state = State::from_ptr(l);
         unsafe {
-            *state.new_struct::<T>() = self;
+            match self {
+                LuaNumber::Integer(n) => ffi::lua_pushinteger(l, n),
+                LuaNumber::Float(f) => ffi::lua_pushnumber(l, f),
+            }
+        }
+    }
+}
+
+/// A light userdata: a raw pointer stored in Lua as a plain value.
+///
+/// Unlike full userdata it has no memory of its own, no per-value
+/// metatable and is never collected, so the pointer must be managed by
+/// the host. Two light userdata are equal in Lua when their pointers are,
+/// which makes them usable as table keys, for example to cache values in
+/// the registry under the address of a host object.
+///
+/// # Examples
+///
+/// ```
+/// extern crate lua_ffi;
+///
+/// use lua_ffi::{LightUserData, State, ThreadStatus};
+///
+/// fn main() {
+///     let mut state = State::new();
+///     state.open_libs();
+///
+///     let mut host = 0u8;
+///     let key = LightUserData(&mut host as *mut u8 as *mut _);
+///     state.push(key);
+///     state.set_global("key");
+///
+///     let res = state.do_string("cache = { [key] = 'host' } return cache[key]");
+///     assert_eq!(res, ThreadStatus::Ok);
+///     assert_eq!(state.to_str(-1), Some("host"));
+///
+///     state.get_global("key");
+///     assert_eq!(state.to(-1), Ok(key));
+/// }
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct LightUserData(pub *mut c_void);
+
+impl LuaValue for LightUserData {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        unsafe {
+            ffi::lua_pushlightuserdata(l, self.0);
+        }
+    }
+}
+
+/// A Lua string as raw bytes, either borrowed from the stack or owned.
+///
+/// Lua strings may hold any bytes, including NULs and invalid UTF-8, which
+/// makes them suitable for binary blobs. Unlike `&str` and `String`, a
+/// `LuaString` round-trips such strings unchanged. Reading a `LuaString`
+/// with `FromLua` copies the bytes; borrow them with
+/// [`State::to_bytes`](../state/struct.State.html#method.to_bytes) instead.
+///
+/// # Examples
+///
+/// ```
+/// extern crate lua_ffi;
+///
+/// use lua_ffi::{LuaString, State};
+///
+/// fn main() {
+///     let mut state = State::new();
+///     state.open_libs();
+///
+///     state.push(LuaString::from(&b"\x00\x01tiles\xff"[..]));
+///     state.set_global("blob");
+///     state.do_string("return #blob, blob:byte(-1)");
+///     assert_eq!(state.to::<(i32, i32)>(-2), Ok((8, 255)));
+///
+///     state.get_global("blob");
+///     let blob: LuaString = state.to(-1).unwrap();
+///     assert_eq!(blob.as_bytes(), b"\x00\x01tiles\xff");
+///     assert_eq!(blob.to_str(), None);
+/// }
+/// ```
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct LuaString<'a>(Cow<'a, [u8]>);
+
+impl<'a> LuaString<'a> {
+    /// Returns the bytes of the string.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the string as `&str` if it is valid UTF-8.
+    pub fn to_str(&self) -> Option<&str> {
+        str::from_utf8(&self.0).ok()
+    }
+
+    /// Returns the string as UTF-8, replacing invalid sequences with `U+FFFD`.
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// Copies the bytes if they are borrowed, detaching the string from
+    /// the stack.
+    pub fn into_owned(self) -> LuaString<'static> {
+        LuaString(Cow::Owned(self.0.into_owned()))
+    }
+
+    /// Returns the bytes, copying them if they are borrowed.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0.into_owned()
+    }
+}
+
+impl<'a> Deref for LuaString<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a [u8]> for LuaString<'a> {
+    fn from(bytes: &'a [u8]) -> LuaString<'a> {
+        LuaString(Cow::Borrowed(bytes))
+    }
+}
+
+impl<'a> From<&'a str> for LuaString<'a> {
+    fn from(s: &'a str) -> LuaString<'a> {
+        LuaString(Cow::Borrowed(s.as_bytes()))
+    }
+}
+
+impl From<Vec<u8>> for LuaString<'static> {
+    fn from(bytes: Vec<u8>) -> LuaString<'static> {
+        LuaString(Cow::Owned(bytes))
+    }
+}
+
+impl From<String> for LuaString<'static> {
+    fn from(s: String) -> LuaString<'static> {
+        LuaString(Cow::Owned(s.into_bytes()))
+    }
+}
+
+impl<'a> LuaValue for LuaString<'a> {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        unsafe {
+            ffi::lua_pushlstring(l, self.0.as_ptr() as *const c_schar, self.0.len());
+        }
+    }
+}
+
+impl<T> LuaValue for T where T: LuaObject {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        State::from_ptr(l).push_struct(self);
+    }
+}
+
+/// Represents any value that can be read from the Lua stack
+pub trait FromLua: Sized {
+    /// `from_lua` should read the value at `idx` on the stack of `state`
+    /// without removing it, failing if it has the wrong type.
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError>;
+}
+
+/// Reads the value at `idx` as a 64-bit integer, failing if it is not a
+/// number or has no exact integer representation.
+fn read_integer(state: &mut State, idx: c_int) -> Result<i64, LuaError> {
+    if !state.is_number(idx) {
+        return Err(state.type_mismatch(idx, "number"));
+    }
+
+    state.to_long(idx).ok_or_else(|| LuaError::Conversion {
+        path: String::new(),
+        message: "number has no integer representation".to_owned(),
+    })
+}
+
+macro_rules! from_integer {
+    ($($t:ty),*) => {
+        $(
+            /// Fails rather than truncating if the number has a fractional
+            /// part or is out of range for the type.
+            impl FromLua for $t {
+                fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+                    let n = read_integer(state, idx)?;
+                    <$t>::try_from(n).map_err(|_| LuaError::Conversion {
+                        path: String::new(),
+                        message: format!("integer {} is out of range for {}", n, stringify!($t)),
+                    })
+                }
+            }
+        )*
+    }
+}
+
+from_integer!(i8, i16, i32, i64, u8, u16, u32, u64, isize, usize, i128, u128);
+
+impl FromLua for f32 {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        state.to_float(idx).ok_or_else(|| state.type_mismatch(idx, "number"))
+    }
+}
+
+impl FromLua for f64 {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        state.to_double(idx).ok_or_else(|| state.type_mismatch(idx, "number"))
+    }
+}
+
+impl FromLua for bool {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        state.to_bool(idx).ok_or_else(|| state.type_mismatch(idx, "boolean"))
+    }
+}
+
+/// Only numbers are accepted, not numeric strings, so that the subtype is
+/// the one Lua stored.
+impl FromLua for LuaNumber {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        state.to_number(idx).ok_or_else(|| state.type_mismatch(idx, "number"))
+    }
+}
+
+impl FromLua for LightUserData {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        state.to_light_userdata(idx).map(LightUserData).ok_or_else(|| state.type_mismatch(idx, "light userdata"))
+    }
+}
+
+impl FromLua for String {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        match state.to_str(idx) {
+            Some(s) => Ok(s.to_owned()),
+            None => Err(state.type_mismatch(idx, "string")),
         }
     }
 }
 
-pub type LuaFunction = unsafe extern "C" fn(L: *mut ffi::lua_State) -> c_int;
+impl FromLua for LuaString<'static> {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        match state.to_bytes(idx) {
+            Some(bytes) => Ok(LuaString::from(bytes.to_vec())),
+            None => Err(state.type_mismatch(idx, "string")),
+        }
+    }
+}
+
+/// `nil`, or a missing argument, reads as `None`.
+impl<T> FromLua for Option<T> where T: FromLua {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        if unsafe { ffi::lua_type(state.as_ptr(), idx) } <= ffi::LUA_TNIL {
+            Ok(None)
+        } else {
+            T::from_lua(state, idx).map(Some)
+        }
+    }
+}
+
+/// Represents the result of a Rust function called from Lua, which
+/// can push any number of return values.
+pub trait LuaReturn {
+    /// `push_return` should push every returned value to the stack
+    /// and return how many values were pushed, or the error of a value
+    /// that cannot be pushed.
+    fn push_return(self, state: &mut State) -> Result<c_int, LuaError>;
+}
+
+impl LuaReturn for () {
+    fn push_return(self, _state: &mut State) -> Result<c_int, LuaError> {
+        Ok(0)
+    }
+}
+
+impl<T> LuaReturn for T where T: LuaValue {
+    fn push_return(self, state: &mut State) -> Result<c_int, LuaError> {
+        state.try_push(self)?;
+
+        Ok(1)
+    }
+}
+
+/// Represents the result of a Rust method exposed with `#[lua_methods]`:
+/// either values to return, or an error to raise in Lua once every Rust
+/// value of the call has been dropped.
+pub trait LuaResult {
+    /// `push_result` should push every returned value to the stack and
+    /// return how many values were pushed, or return the error to raise.
+    fn push_result(self, state: &mut State) -> Result<c_int, LuaError>;
+}
+
+impl<R> LuaResult for R where R: LuaReturn {
+    fn push_result(self, state: &mut State) -> Result<c_int, LuaError> {
+        self.push_return(state)
+    }
+}
+
+impl<R> LuaResult for Result<R, LuaError> where R: LuaReturn {
+    fn push_result(self, state: &mut State) -> Result<c_int, LuaError> {
+        self.and_then(|ret| ret.push_return(state))
+    }
+}
+
+/// Represents the result of a function wrapped by `lua_fn!` or
+/// `lua_method!`: the number of values it pushed, or, for handlers
+/// returning `Result<c_int, LuaError>`, an error to raise in Lua once the
+/// handler has returned and its Rust values have been dropped.
+pub trait LuaFnResult {
+    fn into_result(self) -> Result<c_int, LuaError>;
+}
+
+impl LuaFnResult for c_int {
+    fn into_result(self) -> Result<c_int, LuaError> {
+        Ok(self)
+    }
+}
+
+impl LuaFnResult for Result<c_int, LuaError> {
+    fn into_result(self) -> Result<c_int, LuaError> {
+        self
+    }
+}
+
+pub type LuaFunction = unsafe extern "C" fn(l: *mut ffi::lua_State) -> c_int;
 
 /// Structs can implement this trait to enable easy interaction with
 /// the Lua stack. Any struct implementing this trait can be pushed
 /// to the Lua stack as userdata.
-pub trait LuaObject {
+///
+/// Userdata created for a `LuaObject` records the `TypeId` of the type,
+/// and [`State::check_userdata`](../state/struct.State.html#method.check_userdata)
+/// verifies it, so the type must be `'static`.
+pub trait LuaObject: 'static {
     /// The string returned by this method will serve as the name
     /// of this type's metatable in the Lua registry. A good value
     /// is the name of the type LuaObject is being implemented for.
@@ -139,4 +571,288 @@ pub trait LuaObject {
     /// Return a list of all Lua functions on this struct. They will
     /// be registered in the metatable automatically.
     fn lua_fns() -> Vec<ffi::luaL_Reg>;
+
+    /// Return a list of static functions. They are stored on the class
+    /// table published for this type, such as `Point2D.new`, but unlike
+    /// the functions of `lua_fns` cannot be reached through instances.
+    fn lua_statics() -> Vec<ffi::luaL_Reg> where Self: Sized {
+        Vec::new()
+    }
+
+    /// Return a list of constants to store on the class table, such as
+    /// `Point2D.ZERO`. Each is pushed once when the type is registered.
+    fn lua_constants() -> Vec<LuaConstant> where Self: Sized {
+        Vec::new()
+    }
+
+    /// Return whether registering this type publishes its class table as
+    /// a global named after it. The class table can always be pushed with
+    /// [`State::push_class`](../state/struct.State.html#method.push_class).
+    fn lua_global() -> bool where Self: Sized {
+        true
+    }
+
+    /// Return a list of fields that Lua scripts can read or assign
+    /// with `obj.field` syntax. Fields are looked up before methods.
+    fn lua_fields() -> Vec<LuaField<Self>> where Self: Sized {
+        Vec::new()
+    }
+
+    /// Return a list of metamethods, such as operators or `__tostring`,
+    /// to store in this type's metatable. `__index` and `__newindex` are
+    /// reserved for field and method lookup, and `__gc` always drops the
+    /// Rust value.
+    fn lua_metamethods() -> Vec<LuaMetaMethod<Self>> where Self: Sized {
+        Vec::new()
+    }
+
+    /// Return the parent type of this type, if it has one. Keys that are
+    /// neither a field nor a method of this type are looked up on the
+    /// parent, and instances are accepted wherever the parent is expected.
+    /// Metamethods are not inherited.
+    fn lua_parent() -> Option<LuaParent<Self>> where Self: Sized {
+        None
+    }
+
+    /// Return the number of user values each instance carries, for use
+    /// with [`State::get_user_value`](../state/struct.State.html#method.get_user_value)
+    /// and [`State::set_user_value`](../state/struct.State.html#method.set_user_value).
+    fn user_values() -> c_int where Self: Sized {
+        0
+    }
+}
+
+/// A named field on a [`LuaObject`](trait.LuaObject.html), readable and
+/// writable from Lua through getter and setter closures.
+///
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate lua_ffi;
+/// use lua_ffi::{LuaObject, LuaField};
+/// use lua_ffi::ffi::luaL_Reg;
+///
+/// struct Point2D {
+///     x: i32,
+///     y: i32,
+/// }
+///
+/// impl LuaObject for Point2D {
+///     fn name() -> *const i8 {
+///         c_str!("Point2D")
+///     }
+///
+///     fn lua_fns() -> Vec<luaL_Reg> {
+///         vec!()
+///     }
+///
+///     fn lua_fields() -> Vec<LuaField<Point2D>> {
+///         vec!(
+///             LuaField::new("x").getter(|p: &Point2D| p.x).setter(|p: &mut Point2D, x| p.x = x),
+///             LuaField::new("y").getter(|p: &Point2D| p.y),
+///         )
+///     }
+/// }
+/// # fn main() {}
+/// ```
+pub struct LuaField<T> {
+    pub(crate) name: String,
+    pub(crate) getter: Option<FieldGetter<T>>,
+    pub(crate) setter: Option<FieldSetter<T>>,
+}
+
+/// Reads the value of a field. The returned closure pushes it once the
+/// object is no longer borrowed.
+pub(crate) type FieldGetter<T> = Box<dyn Fn(&T) -> FieldValue>;
+
+/// Pushes a value read by a `FieldGetter`.
+pub(crate) type FieldValue = Box<dyn FnOnce(&mut State) -> Result<(), LuaError>>;
+
+/// Assigns a field from the value at the given stack index.
+pub(crate) type FieldSetter<T> = Box<dyn Fn(&mut T, &mut State, c_int) -> Result<(), LuaError>>;
+
+impl<T> LuaField<T> {
+    /// Creates a field named `name` that can neither be read nor written
+    /// until a getter or setter is added.
+    pub fn new(name: &str) -> LuaField<T> {
+        LuaField {
+            name: name.to_owned(),
+            getter: None,
+            setter: None,
+        }
+    }
+
+    /// Makes the field readable, pushing the value returned by `get`.
+    pub fn getter<V, F>(mut self, get: F) -> LuaField<T>
+        where V: LuaValue + 'static, F: Fn(&T) -> V + 'static {
+        self.getter = Some(Box::new(move |obj| {
+            let val = get(obj);
+            Box::new(move |state: &mut State| state.try_push(val))
+        }));
+        self
+    }
+
+    /// Makes the field writable, passing the assigned value to `set`
+    /// after converting it with `FromLua`.
+    pub fn setter<V, F>(mut self, set: F) -> LuaField<T>
+        where V: FromLua, F: Fn(&mut T, V) + 'static {
+        self.setter = Some(Box::new(move |obj, state, idx| {
+            set(obj, V::from_lua(state, idx)?);
+            Ok(())
+        }));
+        self
+    }
+}
+
+/// A named constant on the class table of a [`LuaObject`](trait.LuaObject.html).
+///
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate lua_ffi;
+/// use lua_ffi::{LuaConstant, LuaObject, State, ThreadStatus};
+/// use lua_ffi::ffi::luaL_Reg;
+///
+/// struct Circle;
+///
+/// impl LuaObject for Circle {
+///     fn name() -> *const i8 {
+///         c_str!("Circle")
+///     }
+///
+///     fn lua_fns() -> Vec<luaL_Reg> {
+///         vec!()
+///     }
+///
+///     fn lua_constants() -> Vec<LuaConstant> {
+///         vec!(LuaConstant::new("SIDES", 0), LuaConstant::new("UNIT", Circle))
+///     }
+/// }
+///
+/// fn main() {
+///     let mut state = State::new();
+///     state.open_libs();
+///     state.register_struct::<Circle>();
+///
+///     let res = state.do_string("assert(Circle.SIDES == 0 and getmetatable(Circle.UNIT) ~= nil)");
+///     assert_eq!(res, ThreadStatus::Ok);
+/// }
+/// ```
+pub struct LuaConstant {
+    pub(crate) name: String,
+    pub(crate) push: Box<dyn FnOnce(&mut State)>,
+}
+
+impl LuaConstant {
+    /// Creates a constant named `name` holding `val`.
+    pub fn new<V>(name: &str, val: V) -> LuaConstant where V: LuaValue + 'static {
+        LuaConstant {
+            name: name.to_owned(),
+            push: Box::new(move |state| state.push(val)),
+        }
+    }
+}
+
+/// The parent type of a [`LuaObject`](trait.LuaObject.html), returned by
+/// `LuaObject::lua_parent`. The closures return the parent value embedded
+/// in the child, for shared and for mutable borrows.
+///
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate lua_ffi;
+/// use lua_ffi::{LuaField, LuaObject, LuaParent, State, ThreadStatus};
+/// use lua_ffi::ffi::luaL_Reg;
+///
+/// struct Entity {
+///     hp: i32,
+/// }
+///
+/// impl LuaObject for Entity {
+///     fn name() -> *const i8 {
+///         c_str!("Entity")
+///     }
+///
+///     fn lua_fns() -> Vec<luaL_Reg> {
+///         vec!()
+///     }
+///
+///     fn lua_fields() -> Vec<LuaField<Entity>> {
+///         vec!(LuaField::new("hp").getter(|e: &Entity| e.hp))
+///     }
+/// }
+///
+/// struct Monster {
+///     entity: Entity,
+/// }
+///
+/// impl LuaObject for Monster {
+///     fn name() -> *const i8 {
+///         c_str!("Monster")
+///     }
+///
+///     fn lua_fns() -> Vec<luaL_Reg> {
+///         vec!()
+///     }
+///
+///     fn lua_parent() -> Option<LuaParent<Monster>> {
+///         Some(LuaParent::new(|m: &Monster| &m.entity, |m: &mut Monster| &mut m.entity))
+///     }
+/// }
+///
+/// fn main() {
+///     let mut state = State::new();
+///     state.open_libs();
+///     state.push(Monster { entity: Entity { hp: 3 } });
+///     state.set_global("monster");
+///     assert_eq!(state.do_string("assert(monster.hp == 3)"), ThreadStatus::Ok);
+/// }
+/// ```
+pub struct LuaParent<T> {
+    pub(crate) type_id: TypeId,
+    pub(crate) register: fn(&mut State),
+    pub(crate) cast: Cast,
+    pub(crate) cast_mut: Cast,
+    marker: PhantomData<fn(&T)>,
+}
+
+impl<T> LuaParent<T> where T: LuaObject {
+    /// Declares `P` as the parent of `T`, reached through `get` and
+    /// `get_mut`.
+    pub fn new<P, G, M>(get: G, get_mut: M) -> LuaParent<T>
+        where P: LuaObject, G: Fn(&T) -> &P + 'static, M: Fn(&mut T) -> &mut P + 'static {
+        LuaParent {
+            type_id: TypeId::of::<P>(),
+            register: State::register_struct::<P>,
+            cast: Rc::new(move |val| get(unsafe { &*(val as *const T) }) as *const P as *mut ()),
+            cast_mut: Rc::new(move |val| get_mut(unsafe { &mut *(val as *mut T) }) as *mut P as *mut ()),
+            marker: PhantomData,
+        }
+    }
+}
+
+/// Lists the Lua functions generated for a type by a `#[lua_methods]`
+/// impl block. `#[derive(LuaObject)]` uses this to implement
+/// [`LuaObject::lua_fns`](trait.LuaObject.html#tymethod.lua_fns).
+pub trait LuaMethods {
+    /// Return a list of all Lua methods generated for this type.
+    fn lua_methods() -> Vec<ffi::luaL_Reg>;
+
+    /// Return the static functions generated for this type, from the
+    /// functions marked `#[lua(function)]`.
+    fn lua_statics() -> Vec<ffi::luaL_Reg> {
+        Vec::new()
+    }
+
+    /// Return the constants generated for this type, from the functions
+    /// marked `#[lua(constant)]`.
+    fn lua_constants() -> Vec<LuaConstant> {
+        Vec::new()
+    }
+
+    /// Return the metamethods of this type, taken from the function
+    /// marked `#[lua(metamethods)]`, if there is one.
+    fn lua_metamethods() -> Vec<LuaMetaMethod<Self>> where Self: Sized {
+        Vec::new()
+    }
 }
@@ -0,0 +1,736 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_schar;
+use std::any::TypeId;
+use std::cell::{Ref, RefCell, RefMut, UnsafeCell};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::{mem, ptr, slice, str};
+
+use libc::{c_int, size_t};
+
+use super::error::{self, LuaError};
+use super::metamethod::MetaFn;
+use super::ffi::*;
+use super::types::{FromLua, LuaField, LuaObject, LuaParent};
+use super::value::LuaRef;
+use super::State;
+
+/// The fields of a `LuaObject`, keyed by name. Stored as an upvalue of the
+/// `__index` and `__newindex` closures of the type's metatable.
+type Fields<T> = HashMap<String, LuaField<T>>;
+
+/// The common prefix of every userdata holding a `LuaObject`. The
+/// `TypeId` is checked before the value is ever borrowed, and the
+/// `RefCell` tracks borrows made from Rust while Lua code keeps running.
+/// Borrows through a parent type share the same flag.
+#[repr(C)]
+struct Header {
+    type_id: TypeId,
+    borrow: RefCell<()>,
+}
+
+/// The layout of userdata holding a `LuaObject`.
+#[repr(C)]
+struct Tagged<T> {
+    header: Header,
+    slot: UnsafeCell<Slot<T>>,
+}
+
+/// Where the value of a `LuaObject` userdata lives.
+pub(crate) enum Slot<T> {
+    /// Moved into the userdata, and dropped along with it.
+    Owned(T),
+    /// Borrowed from Rust for the duration of a `State::scope`.
+    Borrowed(*mut T),
+    /// Borrowed by a scope that has since ended.
+    Expired,
+}
+
+impl<T> Slot<T> {
+    fn as_ptr(&mut self) -> Option<*mut T> {
+        match *self {
+            Slot::Owned(ref mut val) => Some(val),
+            Slot::Borrowed(ptr) => Some(ptr),
+            Slot::Expired => None,
+        }
+    }
+}
+
+/// The alignment Lua guarantees for the block of a userdata, that of
+/// `LUAI_MAXALIGN`: the strictest of its numbers and pointers.
+const MAX_ALIGN: usize = {
+    let (n, p, i) = (mem::align_of::<lua_Number>(), mem::align_of::<*mut ()>(), mem::align_of::<lua_Integer>());
+    let max = if n > p { n } else { p };
+    if max > i { max } else { i }
+};
+
+/// Fails to compile uses of `Tagged<T>` for a `T` Lua cannot align.
+struct Aligned<T>(PhantomData<T>);
+
+impl<T> Aligned<T> {
+    const CHECK: () = assert!(mem::align_of::<Tagged<T>>() <= MAX_ALIGN, "LuaObject types cannot be aligned past LUAI_MAXALIGN");
+}
+
+/// Moves `slot` into a new userdata on the top of the stack, tagged with
+/// the type of `T` and with `T::user_values` user values. The caller sets
+/// the metatable.
+pub(crate) fn push_tagged<T>(state: &mut State, slot: Slot<T>) where T: LuaObject {
+    #[allow(clippy::let_unit_value)]
+    let () = Aligned::<T>::CHECK;
+    let udata = state.new_raw_userdata_uv(mem::size_of::<Tagged<T>>(), T::user_values()) as *mut Tagged<T>;
+
+    unsafe {
+        ptr::write(udata, Tagged {
+            header: Header {
+                type_id: TypeId::of::<T>(),
+                borrow: RefCell::new(()),
+            },
+            slot: UnsafeCell::new(slot),
+        });
+    }
+}
+
+/// Returns the header of the userdata at `idx` if it was created by
+/// `push_tagged`, which is told by its metatable: only metatables made by
+/// `State::register_struct` record a type. Other userdata, such as those
+/// of C libraries, are never read.
+unsafe fn header(l: *mut lua_State, idx: c_int) -> Option<*const Header> {
+    if lua_type(l, idx) != LUA_TUSERDATA || lua_rawlen(l, idx) < mem::size_of::<Header>() {
+        return None;
+    }
+
+    if lua_checkstack(l, 2) == 0 || lua_getmetatable(l, idx) == 0 {
+        return None;
+    }
+    lua_getfield(l, -1, TYPE_FIELD.as_ptr() as *const c_schar);
+    let registered = lua_type(l, -1) == LUA_TUSERDATA;
+    lua_pop(l, 2);
+
+    if registered {
+        Some(lua_touserdata(l, idx) as *const Header)
+    } else {
+        None
+    }
+}
+
+/// Returns the userdata at `idx` if it was created by `push_tagged` for
+/// exactly the type `T`.
+unsafe fn tagged_ptr<T>(l: *mut lua_State, idx: c_int) -> Option<*mut Tagged<T>> where T: LuaObject {
+    let header = header(l, idx)?;
+    if (*header).type_id == TypeId::of::<T>() && lua_rawlen(l, idx) >= mem::size_of::<Tagged<T>>() {
+        Some(header as *mut Tagged<T>)
+    } else {
+        None
+    }
+}
+
+/// Marks the borrowed value of the userdata on the top of the stack as
+/// expired, so it can no longer be reached from Lua.
+pub(crate) unsafe fn expire<T>(l: *mut lua_State) where T: LuaObject {
+    if let Some(tagged) = tagged_ptr::<T>(l, -1) {
+        // Only guards skipped by a Lua error can still hold a borrow once
+        // the scope has ended, and those are never used again
+        *(*tagged).slot.get() = Slot::Expired;
+    }
+}
+
+/// Returns a pointer to the value of the `Tagged<T>` starting at `header`,
+/// erased so it can be stored along with upcasts from other types.
+unsafe fn value_ptr<T>(header: *const Header) -> Option<*mut ()> {
+    let tagged = header as *const Tagged<T>;
+    (*(*tagged).slot.get()).as_ptr().map(|val| val as *mut ())
+}
+
+/// Converts a pointer to a value into a pointer to one of its parents.
+pub(crate) type Cast = Rc<dyn Fn(*mut ()) -> *mut ()>;
+
+/// How to reach a value of some type from the header of a userdata.
+#[derive(Clone)]
+struct Upcast {
+    /// Returns the value of the userdata, as its own type.
+    value: unsafe fn(*const Header) -> Option<*mut ()>,
+    /// Converts the value to a parent type, for shared and for mutable
+    /// borrows. `None` if the userdata holds the type itself.
+    casts: Option<(Cast, Cast)>,
+}
+
+/// Upcasts known to a state, keyed by the type of the value in a userdata
+/// and the parent type it can be borrowed as.
+type Upcasts = HashMap<(TypeId, TypeId), Upcast>;
+
+/// Registry field holding the `Upcasts` of a state.
+const UPCASTS_FIELD: &[u8] = b"lua_ffi.upcasts\x00";
+
+/// Returns the upcasts of the state, creating them if `create` is set.
+unsafe fn upcasts<'a>(state: &mut State, create: bool) -> Option<&'a mut Upcasts> {
+    let l = state.as_ptr();
+
+    if lua_getfield(l, LUA_REGISTRYINDEX, UPCASTS_FIELD.as_ptr() as *const c_schar) == LUA_TNIL {
+        lua_pop(l, 1);
+        if !create {
+            return None;
+        }
+
+        push_gc(state, Upcasts::new());
+        lua_pushvalue(l, -1);
+        lua_setfield(l, LUA_REGISTRYINDEX, UPCASTS_FIELD.as_ptr() as *const c_schar);
+    }
+
+    let upcasts = lua_touserdata(l, -1) as *mut Upcasts;
+    lua_pop(l, 1);
+    Some(&mut *upcasts)
+}
+
+/// Records that a `T` can be borrowed as the parent type of `parent`, and
+/// as every type that parent can be borrowed as in turn. The parent must
+/// already be registered.
+pub(crate) fn add_upcasts<T>(state: &mut State, parent: &LuaParent<T>) where T: LuaObject {
+    let upcasts = unsafe { upcasts(state, true).unwrap() };
+    let from = TypeId::of::<T>();
+
+    let mut added = vec![((from, parent.type_id), Upcast {
+        value: value_ptr::<T>,
+        casts: Some((parent.cast.clone(), parent.cast_mut.clone())),
+    })];
+
+    for (&(child, ancestor), upcast) in upcasts.iter() {
+        if child != parent.type_id {
+            continue;
+        }
+
+        if let Some((ref cast, ref cast_mut)) = upcast.casts {
+            added.push(((from, ancestor), Upcast {
+                value: value_ptr::<T>,
+                casts: Some((compose(&parent.cast, cast), compose(&parent.cast_mut, cast_mut))),
+            }));
+        }
+    }
+
+    upcasts.extend(added);
+}
+
+fn compose(first: &Cast, then: &Cast) -> Cast {
+    let (first, then) = (first.clone(), then.clone());
+    Rc::new(move |val| then(first(val)))
+}
+
+/// Finds the header of the userdata at `idx` and how to reach a `T` from
+/// it, if it holds a `T` or a type registered with `T` as one of its
+/// parents.
+unsafe fn locate<T>(state: &mut State, idx: c_int) -> Option<(*const Header, Upcast)> where T: LuaObject {
+    let l = state.as_ptr();
+    if let Some(tagged) = tagged_ptr::<T>(l, idx) {
+        return Some((tagged as *const Header, Upcast {
+            value: value_ptr::<T>,
+            casts: None,
+        }));
+    }
+
+    let header = header(l, idx)?;
+    let upcast = upcasts(state, false)?.get(&((*header).type_id, TypeId::of::<T>()))?.clone();
+
+    Some((header, upcast))
+}
+
+/// Returns a pointer to the `T` in the userdata at `idx`, without checking
+/// borrows. `None` if it holds no `T` or its value has expired.
+pub(crate) unsafe fn value_at<T>(state: &mut State, idx: c_int) -> Option<*mut T> where T: LuaObject {
+    let (header, upcast) = locate::<T>(state, idx)?;
+    cast_value(header, &upcast, true)
+}
+
+/// Tests if the userdata at `idx` holds a `T`, even one whose value has
+/// expired.
+pub(crate) unsafe fn holds<T>(state: &mut State, idx: c_int) -> bool where T: LuaObject {
+    locate::<T>(state, idx).is_some()
+}
+
+/// Returns a handle to the userdata at `idx` if it holds a `T`, or a type
+/// registered with `T` as one of its parents. Fails if the stack cannot
+/// grow to anchor the userdata.
+pub(crate) unsafe fn handle<T>(state: &mut State, idx: c_int) -> Result<Option<LuaUserData<T>>, LuaError> where T: LuaObject {
+    let (header, upcast) = match locate::<T>(state, idx) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    Ok(Some(LuaUserData {
+        header,
+        upcast,
+        anchor: LuaRef::new(state.as_ptr(), idx)?,
+        marker: PhantomData,
+    }))
+}
+
+/// Returns a pointer to the value reached by `upcast` from `header`, for
+/// shared or mutable borrows.
+unsafe fn cast_value<T>(header: *const Header, upcast: &Upcast, mutable: bool) -> Option<*mut T> {
+    let val = (upcast.value)(header)?;
+
+    let val = match upcast.casts {
+        Some((ref cast, _)) if !mutable => cast(val),
+        Some((_, ref cast_mut)) => cast_mut(val),
+        None => val,
+    };
+
+    Some(val as *mut T)
+}
+
+/// A borrow-checked handle to a `LuaObject` stored in a Lua userdata.
+///
+/// Borrows are tracked at runtime like a `RefCell`, so Lua code calling
+/// back into Rust can never create a second `&mut` to a value that is
+/// already borrowed. The handle keeps the userdata from being collected,
+/// and fails to borrow once its `State` is closed. Guards must not be kept
+/// past the `State` itself.
+///
+/// A Lua error raised while a guard is alive skips its destructor and
+/// leaves the value borrowed for good. Use `pcall` for calls back into Lua
+/// made while holding a borrow.
+///
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate lua_ffi;
+/// use lua_ffi::{LuaObject, LuaUserData, State};
+/// use lua_ffi::ffi::luaL_Reg;
+///
+/// struct Counter(i32);
+///
+/// impl LuaObject for Counter {
+///     fn name() -> *const i8 {
+///         c_str!("Counter")
+///     }
+///
+///     fn lua_fns() -> Vec<luaL_Reg> {
+///         vec!()
+///     }
+/// }
+///
+/// fn main() {
+///     let mut state = State::new();
+///     state.push(Counter(0));
+///
+///     let counter: LuaUserData<Counter> = state.to(-1).unwrap();
+///     let mut guard = counter.borrow_mut().unwrap();
+///     guard.0 += 1;
+///
+///     assert!(counter.borrow().is_err());
+///     drop(guard);
+///     assert_eq!(counter.borrow().unwrap().0, 1);
+/// }
+/// ```
+pub struct LuaUserData<T> {
+    header: *const Header,
+    upcast: Upcast,
+    anchor: LuaRef,
+    marker: PhantomData<*mut T>,
+}
+
+impl<T> LuaUserData<T> where T: LuaObject {
+    /// Immutably borrows the value, failing if it is mutably borrowed.
+    pub fn borrow(&self) -> Result<Ref<'_, T>, LuaError> {
+        unsafe { self.borrow_unbound() }
+    }
+
+    /// Mutably borrows the value, failing if it is borrowed at all.
+    pub fn borrow_mut(&self) -> Result<RefMut<'_, T>, LuaError> {
+        unsafe { self.borrow_mut_unbound() }
+    }
+
+    /// Returns a raw pointer to the value, without checking borrows. The
+    /// pointer is null if the value was borrowed by a scope that has ended,
+    /// or its state was closed.
+    pub fn as_ptr(&self) -> *mut T {
+        if !self.anchor.is_open() {
+            return ptr::null_mut();
+        }
+
+        unsafe { cast_value(self.header, &self.upcast, true).unwrap_or(ptr::null_mut()) }
+    }
+
+    /// Checks that the state holding the value is still open, before its
+    /// header is read.
+    fn check_open(&self) -> Result<(), LuaError> {
+        if self.anchor.is_open() {
+            Ok(())
+        } else {
+            Err(LuaError::Closed { type_name: type_name::<T>() })
+        }
+    }
+
+    /// Like `borrow`, for borrows that outlive the handle. The caller
+    /// chooses a lifetime during which the userdata stays alive.
+    pub(crate) unsafe fn borrow_unbound<'a>(&self) -> Result<Ref<'a, T>, LuaError> {
+        self.check_open()?;
+        let flag = (*self.header).borrow.try_borrow().map_err(|_| LuaError::AlreadyBorrowed {
+            type_name: type_name::<T>(),
+            mutably: true,
+        })?;
+
+        let val = cast_value::<T>(self.header, &self.upcast, false)
+            .ok_or_else(|| LuaError::Expired { type_name: type_name::<T>() })?;
+        Ok(Ref::map(flag, |_| &*val))
+    }
+
+    /// Like `borrow_mut`, for borrows that outlive the handle. The caller
+    /// chooses a lifetime during which the userdata stays alive.
+    pub(crate) unsafe fn borrow_mut_unbound<'a>(&self) -> Result<RefMut<'a, T>, LuaError> {
+        self.check_open()?;
+        let borrow = &(*self.header).borrow;
+        let flag = borrow.try_borrow_mut().map_err(|_| LuaError::AlreadyBorrowed {
+            type_name: type_name::<T>(),
+            mutably: borrow.try_borrow().is_err(),
+        })?;
+
+        let val = cast_value::<T>(self.header, &self.upcast, true)
+            .ok_or_else(|| LuaError::Expired { type_name: type_name::<T>() })?;
+        Ok(RefMut::map(flag, |_| &mut *val))
+    }
+}
+
+impl<T> Clone for LuaUserData<T> {
+    fn clone(&self) -> LuaUserData<T> {
+        LuaUserData {
+            header: self.header,
+            upcast: self.upcast.clone(),
+            anchor: self.anchor.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> FromLua for LuaUserData<T> where T: LuaObject {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        match unsafe { handle::<T>(state, idx)? } {
+            Some(obj) => Ok(obj),
+            None => Err(state.type_mismatch(idx, &type_name::<T>())),
+        }
+    }
+}
+
+/// Tag of userdata whose value has already been dropped by `__gc`.
+struct Finalized;
+
+/// Metatable field holding the `TypeId` of the type it was registered for.
+const TYPE_FIELD: &[u8] = b"__lua_ffi_type\x00";
+
+/// Records `T` as the owner of the metatable on the top of the stack.
+pub(crate) fn set_metatable_type<T>(state: &mut State) where T: LuaObject {
+    push_gc(state, TypeId::of::<T>());
+
+    unsafe {
+        lua_setfield(state.as_ptr(), -2, TYPE_FIELD.as_ptr() as *const c_schar);
+    }
+}
+
+/// Tests if the metatable on the top of the stack was registered for `T`.
+pub(crate) fn is_metatable_of<T>(state: &mut State) -> bool where T: LuaObject {
+    unsafe {
+        let l = state.as_ptr();
+        lua_getfield(l, -1, TYPE_FIELD.as_ptr() as *const c_schar);
+        let type_id = lua_touserdata(l, -1) as *const TypeId;
+        let res = !type_id.is_null() && *type_id == TypeId::of::<T>();
+        lua_pop(l, 1);
+        res
+    }
+}
+
+/// Moves `val` into a new userdata on the top of the stack, with a
+/// metatable that drops it when the userdata is collected.
+pub(crate) fn push_gc<T>(state: &mut State, val: T) {
+    let udata = state.new_userdata::<T>();
+
+    unsafe {
+        ptr::write(udata, val);
+
+        let l = state.as_ptr();
+        lua_createtable(l, 0, 1);
+        lua_pushcfunction(l, Some(gc::<T>));
+        lua_setfield(l, -2, b"__gc\x00".as_ptr() as *const c_schar);
+        lua_setmetatable(l, -2);
+    }
+}
+
+unsafe extern "C" fn gc<T>(l: *mut lua_State) -> c_int {
+    ptr::drop_in_place(lua_touserdata(l, 1) as *mut T);
+
+    0
+}
+
+/// Sets `__index` and `__newindex` on the metatable below the method table
+/// and the parent's metatable (or `nil`) on the top of the stack,
+/// dispatching to the fields of `T`, then to its methods, then to the
+/// parent. Pops the method table and the parent's metatable.
+pub(crate) fn set_dispatch<T>(state: &mut State) where T: LuaObject {
+    let fields: Fields<T> = T::lua_fields().into_iter()
+        .map(|field| (field.name.clone(), field))
+        .collect();
+
+    push_gc(state, fields);
+
+    unsafe {
+        let l = state.as_ptr();
+
+        // Stack: metatable, methods, parent, fields
+        lua_pushvalue(l, -1);
+        lua_pushvalue(l, -4);
+        parent_field(l, -4, b"__index\x00");
+        lua_pushcclosure(l, Some(index::<T>), 3);
+        lua_setfield(l, -5, b"__index\x00".as_ptr() as *const c_schar);
+
+        parent_field(l, -2, b"__newindex\x00");
+        lua_pushcclosure(l, Some(newindex::<T>), 2);
+        lua_setfield(l, -4, b"__newindex\x00".as_ptr() as *const c_schar);
+        lua_pop(l, 2);
+    }
+}
+
+/// Pushes the field `name` of the parent metatable at `idx`, or `nil` if
+/// there is no parent.
+unsafe fn parent_field(l: *mut lua_State, idx: c_int, name: &[u8]) {
+    if lua_istable(l, idx) {
+        lua_getfield(l, idx, name.as_ptr() as *const c_schar);
+    } else {
+        lua_pushnil(l);
+    }
+}
+
+/// Metatable field holding the class table of the type.
+const CLASS_FIELD: &[u8] = b"__lua_ffi_class\x00";
+
+/// Pushes a new class table for `T`, given its method table on the top of
+/// the stack.
+pub(crate) fn push_class<T>(state: &mut State) where T: LuaObject {
+    state.new_table();
+    state.register_fns(None, T::lua_statics());
+
+    for constant in T::lua_constants() {
+        (constant.push)(state);
+        state.set_field(-2, &constant.name);
+    }
+
+    unsafe {
+        let l = state.as_ptr();
+        lua_createtable(l, 0, 2);
+        lua_pushvalue(l, -3);
+        lua_setfield(l, -2, b"__index\x00".as_ptr() as *const c_schar);
+        lua_pushcfunction(l, Some(call_class::<T>));
+        lua_setfield(l, -2, b"__call\x00".as_ptr() as *const c_schar);
+        lua_setmetatable(l, -2);
+    }
+}
+
+/// Pops the class table on the top of the stack and stores it in the
+/// metatable at the absolute index `mt`.
+pub(crate) fn set_class(state: &mut State, mt: c_int) {
+    unsafe {
+        lua_setfield(state.as_ptr(), mt, CLASS_FIELD.as_ptr() as *const c_schar);
+    }
+}
+
+/// Replaces the metatable on the top of the stack with its class table.
+pub(crate) fn get_class(state: &mut State) {
+    unsafe {
+        let l = state.as_ptr();
+        lua_getfield(l, -1, CLASS_FIELD.as_ptr() as *const c_schar);
+        lua_remove(l, -2);
+    }
+}
+
+/// `__call` of class tables, forwarding `Class(...)` to `Class.new(...)`.
+unsafe extern "C" fn call_class<T>(l: *mut lua_State) -> c_int where T: LuaObject {
+    lua_getfield(l, 1, b"new\x00".as_ptr() as *const c_schar);
+    if !lua_isfunction(l, -1) {
+        return error::raise(l, format!("{} has no 'new' function to call", type_name::<T>()));
+    }
+
+    lua_replace(l, 1);
+    lua_callk(l, lua_gettop(l) - 1, LUA_MULTIRET, 0, None);
+    lua_gettop(l)
+}
+
+/// Stores the metamethods of `T` in the metatable on the top of the stack,
+/// along with a `__gc` that drops the Rust value.
+pub(crate) fn set_metamethods<T>(state: &mut State) where T: LuaObject {
+    for meta in T::lua_metamethods() {
+        if RESERVED.contains(&meta.name) {
+            panic!("{} cannot be set as a metamethod of {}", meta.name, type_name::<T>());
+        }
+
+        let name = CString::new(meta.name).unwrap();
+        push_gc(state, meta.func);
+
+        unsafe {
+            let l = state.as_ptr();
+            lua_pushcclosure(l, Some(call_meta), 1);
+            lua_setfield(l, -2, name.as_ptr());
+        }
+    }
+
+    unsafe {
+        let l = state.as_ptr();
+        lua_pushcfunction(l, Some(gc_tagged::<T>));
+        lua_setfield(l, -2, b"__gc\x00".as_ptr() as *const c_schar);
+    }
+}
+
+unsafe extern "C" fn gc_tagged<T>(l: *mut lua_State) -> c_int where T: LuaObject {
+    if let Some(tagged) = tagged_ptr::<T>(l, 1) {
+        // Handles keep the userdata alive, so only a direct call of `__gc`
+        // or closing the state can get here while the value is borrowed.
+        // It is left alone rather than dropped under the guard.
+        if (*tagged).header.borrow.try_borrow_mut().is_err() {
+            return 0;
+        }
+
+        ptr::drop_in_place((*tagged).slot.get());
+
+        // A finalized object can be resurrected, make sure it is never
+        // borrowed again
+        (*tagged).header.type_id = TypeId::of::<Finalized>();
+    }
+
+    0
+}
+
+/// Metatable fields managed by `register_struct` itself.
+const RESERVED: &[&str] = &["__index", "__newindex", "__gc"];
+
+unsafe extern "C" fn call_meta(l: *mut lua_State) -> c_int {
+    let res = {
+        let func = &*(lua_touserdata(l, lua_upvalueindex(1)) as *const MetaFn);
+        func(&mut State::from_ptr(l))
+    };
+
+    match res {
+        Ok(n) => n,
+        Err(err) => error::raise_error(l, err),
+    }
+}
+
+unsafe extern "C" fn index<T>(l: *mut lua_State) -> c_int where T: LuaObject {
+    match dispatch_index::<T>(l) {
+        Ok(Some(n)) => n,
+        Ok(None) => call_parent(l, lua_upvalueindex(3), 2, 1),
+        Err(msg) => error::raise(l, msg),
+    }
+}
+
+/// Looks up the key in argument 2 on the `T` in argument 1. Returns `None`
+/// if it is neither a field nor a method and `T` has a parent to ask.
+unsafe fn dispatch_index<T>(l: *mut lua_State) -> Result<Option<c_int>, String> where T: LuaObject {
+    let mut state = State::from_ptr(l);
+    let obj = self_handle::<T>(&mut state).map_err(|err| err.to_string())?;
+    let fields = &*(lua_touserdata(l, lua_upvalueindex(1)) as *const Fields<T>);
+
+    if let Some(field) = string_key(l, 2).and_then(|key| fields.get(key)) {
+        return match field.getter {
+            Some(ref get) => {
+                let val = get(&*obj.borrow().map_err(|err| err.to_string())?);
+                val(&mut state).map(|_| Some(1)).map_err(|err| err.to_string())
+            }
+            None => Err(format!("field '{}' on {} is write-only", field.name, type_name::<T>())),
+        };
+    }
+
+    lua_pushvalue(l, 2);
+    lua_rawget(l, lua_upvalueindex(2));
+    if !lua_isnil(l, -1) {
+        return Ok(Some(1));
+    }
+
+    if !lua_isnil(l, lua_upvalueindex(3)) {
+        return Ok(None);
+    }
+
+    Err(format!("no field '{}' on {}", key_name(l, 2), type_name::<T>()))
+}
+
+unsafe extern "C" fn newindex<T>(l: *mut lua_State) -> c_int where T: LuaObject {
+    match dispatch_newindex::<T>(l) {
+        Ok(true) => 0,
+        Ok(false) => call_parent(l, lua_upvalueindex(2), 3, 0),
+        Err(msg) => error::raise(l, msg),
+    }
+}
+
+/// Assigns the value in argument 3 to the field in argument 2 of the `T`
+/// in argument 1. Returns `false` if it is not a field and `T` has a
+/// parent to ask.
+unsafe fn dispatch_newindex<T>(l: *mut lua_State) -> Result<bool, String> where T: LuaObject {
+    let mut state = State::from_ptr(l);
+    let obj = self_handle::<T>(&mut state).map_err(|err| err.to_string())?;
+    let fields = &*(lua_touserdata(l, lua_upvalueindex(1)) as *const Fields<T>);
+
+    match string_key(l, 2).and_then(|key| fields.get(key)) {
+        Some(field) => match field.setter {
+            Some(ref set) => {
+                let mut obj = obj.borrow_mut().map_err(|err| err.to_string())?;
+                set(&mut obj, &mut state, 3).map(|_| true).map_err(|err| {
+                    format!("bad value for field '{}' on {} ({})", field.name, type_name::<T>(), err)
+                })
+            }
+            None => Err(format!("field '{}' on {} is read-only", field.name, type_name::<T>())),
+        },
+        None if !lua_isnil(l, lua_upvalueindex(2)) => Ok(false),
+        None => Err(format!("no field '{}' on {}", key_name(l, 2), type_name::<T>())),
+    }
+}
+
+/// Calls the parent's metamethod at `func` with the first `nargs`
+/// arguments of the running one. Called once no Rust value is left to
+/// drop, as the parent may raise an error.
+unsafe fn call_parent(l: *mut lua_State, func: c_int, nargs: c_int, nres: c_int) -> c_int {
+    lua_pushvalue(l, func);
+    for i in 1..=nargs {
+        lua_pushvalue(l, i);
+    }
+
+    lua_callk(l, nargs, nres, 0, None);
+    nres
+}
+
+/// Returns a handle to the `T` in argument 1, or the standard argument
+/// error if it is not one.
+pub(crate) fn self_handle<T>(state: &mut State) -> Result<LuaUserData<T>, LuaError> where T: LuaObject {
+    // Expired values are reported when they are borrowed
+    if let Ok(obj) = state.to::<LuaUserData<T>>(1) {
+        return Ok(obj);
+    }
+
+    Err(state.check_userdata::<T>(1).unwrap_err())
+}
+
+/// Returns the key at `idx` if it is a string. The returned slice is only
+/// valid while the key stays on the stack.
+unsafe fn string_key<'a>(l: *mut lua_State, idx: c_int) -> Option<&'a str> {
+    if lua_type(l, idx) != LUA_TSTRING {
+        return None;
+    }
+
+    let mut len: size_t = 0;
+    let ptr = lua_tolstring(l, idx, &mut len);
+    str::from_utf8(slice::from_raw_parts(ptr as *const u8, len)).ok()
+}
+
+/// Formats the key at `idx` for error messages.
+unsafe fn key_name(l: *mut lua_State, idx: c_int) -> String {
+    match lua_type(l, idx) {
+        LUA_TSTRING | LUA_TNUMBER => {
+            // Convert a copy, `lua_tolstring` changes numbers in place
+            lua_pushvalue(l, idx);
+            let name = CStr::from_ptr(lua_tostring(l, -1) as *const _).to_string_lossy().into_owned();
+            lua_pop(l, 1);
+            name
+        }
+        ty => format!("<{}>", CStr::from_ptr(lua_typename(l, ty) as *const c_schar).to_string_lossy()),
+    }
+}
+
+pub(crate) fn type_name<T>() -> String where T: LuaObject {
+    unsafe {
+        CStr::from_ptr(T::name() as *const c_schar).to_string_lossy().into_owned()
+    }
+}
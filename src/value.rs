@@ -0,0 +1,434 @@
+//! Dynamically typed Lua values, for code that passes values along without
+//! knowing their types up front, such as event buses and debug inspectors.
+
+use std::cell::Cell;
+use std::os::raw::c_schar;
+use std::rc::Rc;
+use std::{fmt, slice};
+
+use libc::c_int;
+
+use super::error::LuaError;
+use super::ffi;
+use super::types::{FromLua, LightUserData, LuaNumber, LuaString, LuaValue};
+use super::userdata;
+use super::State;
+
+/// The type of a value on the Lua stack, as returned by
+/// [`State::type_of`](../state/struct.State.html#method.type_of).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum LuaType {
+    /// An index past the top of the stack.
+    None,
+    Nil,
+    Boolean,
+    LightUserData,
+    Number,
+    String,
+    Table,
+    Function,
+    UserData,
+    Thread,
+}
+
+impl LuaType {
+    pub(crate) fn from_raw(ty: c_int) -> LuaType {
+        match ty {
+            ffi::LUA_TNIL => LuaType::Nil,
+            ffi::LUA_TBOOLEAN => LuaType::Boolean,
+            ffi::LUA_TLIGHTUSERDATA => LuaType::LightUserData,
+            ffi::LUA_TNUMBER => LuaType::Number,
+            ffi::LUA_TSTRING => LuaType::String,
+            ffi::LUA_TTABLE => LuaType::Table,
+            ffi::LUA_TFUNCTION => LuaType::Function,
+            ffi::LUA_TUSERDATA => LuaType::UserData,
+            ffi::LUA_TTHREAD => LuaType::Thread,
+            _ => LuaType::None,
+        }
+    }
+
+    /// Returns the name Lua's `type` function gives the type. Light and
+    /// full userdata are both `userdata`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LuaType::None => "no value",
+            LuaType::Nil => "nil",
+            LuaType::Boolean => "boolean",
+            LuaType::LightUserData | LuaType::UserData => "userdata",
+            LuaType::Number => "number",
+            LuaType::String => "string",
+            LuaType::Table => "table",
+            LuaType::Function => "function",
+            LuaType::Thread => "thread",
+        }
+    }
+}
+
+impl fmt::Display for LuaType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Registry field holding the `Closing` guard of a state.
+const OPEN_FIELD: &[u8] = b"lua_ffi.open\x00";
+
+/// Whether the state a `LuaRef` points into is still open.
+type Open = Rc<Cell<bool>>;
+
+/// Kept in the registry, clears the flag of its state when `lua_close`
+/// collects it.
+struct Closing(Open);
+
+impl Drop for Closing {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+/// Returns the flag telling whether the state of `l` is still open,
+/// creating it the first time. Needs 3 free stack slots.
+unsafe fn open_flag(l: *mut ffi::lua_State) -> Open {
+    if ffi::lua_getfield(l, ffi::LUA_REGISTRYINDEX, OPEN_FIELD.as_ptr() as *const c_schar) != ffi::LUA_TUSERDATA {
+        ffi::lua_settop(l, -2);
+        userdata::push_gc(&mut State::from_ptr(l), Closing(Rc::new(Cell::new(true))));
+        ffi::lua_pushvalue(l, -1);
+        ffi::lua_setfield(l, ffi::LUA_REGISTRYINDEX, OPEN_FIELD.as_ptr() as *const c_schar);
+    }
+
+    let open = (*(ffi::lua_touserdata(l, -1) as *const Closing)).0.clone();
+    ffi::lua_settop(l, -2);
+
+    open
+}
+
+/// An owned reference to a Lua value, kept alive in the registry until the
+/// `LuaRef` is dropped.
+///
+/// Cloning a `LuaRef` refers to the same value, so two clones of a table
+/// see each other's changes. A `LuaRef` may outlive the `State` it was read
+/// from: once the state is closed it refers to nothing, its type is
+/// `LuaType::None` and it pushes `nil`.
+pub struct LuaRef {
+    main: *mut ffi::lua_State,
+    key: c_int,
+    open: Open,
+}
+
+impl LuaRef {
+    /// References the value at `idx` on the stack of `l`, which may be any
+    /// thread of the state. Fails if the stack cannot grow to hold the
+    /// temporaries.
+    pub(crate) fn new(l: *mut ffi::lua_State, idx: c_int) -> Result<LuaRef, LuaError> {
+        unsafe {
+            if ffi::lua_checkstack(l, 4) == 0 {
+                return Err(LuaError::StackOverflow);
+            }
+
+            let idx = ffi::lua_absindex(l, idx);
+            let open = open_flag(l);
+            ffi::lua_pushvalue(l, idx);
+            let key = ffi::luaL_ref(l, ffi::LUA_REGISTRYINDEX);
+            ffi::lua_rawgeti(l, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_MAINTHREAD);
+            let main = ffi::lua_tothread(l, -1);
+            ffi::lua_settop(l, -2);
+
+            Ok(LuaRef { main, key, open })
+        }
+    }
+
+    fn push(&self, l: *mut ffi::lua_State) {
+        unsafe {
+            if self.open.get() {
+                ffi::lua_rawgeti(l, ffi::LUA_REGISTRYINDEX, self.key as ffi::lua_Integer);
+            } else {
+                ffi::lua_pushnil(l);
+            }
+        }
+    }
+
+    /// Pushes the value onto the main thread, runs `f` with `n` more free
+    /// slots and pops the value again. Returns `None` if the state was
+    /// closed or its stack cannot grow.
+    fn with_value<R, F>(&self, n: c_int, f: F) -> Option<R> where F: FnOnce(*mut ffi::lua_State) -> R {
+        if !self.open.get() {
+            return None;
+        }
+
+        unsafe {
+            if ffi::lua_checkstack(self.main, 1 + n) == 0 {
+                return None;
+            }
+
+            let top = ffi::lua_gettop(self.main);
+            self.push(self.main);
+            let res = f(self.main);
+            ffi::lua_settop(self.main, top);
+
+            Some(res)
+        }
+    }
+
+    /// Returns whether the state the value was read from is still open.
+    pub(crate) fn is_open(&self) -> bool {
+        self.open.get()
+    }
+
+    /// Returns the type of the referenced value, or `LuaType::None` once
+    /// the state is closed.
+    pub fn type_of(&self) -> LuaType {
+        self.with_value(0, |l| LuaType::from_raw(unsafe { ffi::lua_type(l, -1) }))
+            .unwrap_or(LuaType::None)
+    }
+
+    /// Returns the referenced value as text if it is a string or a number,
+    /// replacing invalid UTF-8. Metamethods are not called.
+    pub fn to_string_lossy(&self) -> Option<String> {
+        self.with_value(0, |l| unsafe {
+            match ffi::lua_type(l, -1) {
+                ffi::LUA_TSTRING | ffi::LUA_TNUMBER => {
+                    let mut len = 0;
+                    let ptr = ffi::lua_tolstring(l, -1, &mut len);
+                    let bytes = slice::from_raw_parts(ptr as *const u8, len);
+                    Some(String::from_utf8_lossy(bytes).into_owned())
+                }
+                _ => None,
+            }
+        }).and_then(|text| text)
+    }
+}
+
+/// Panics if the stack of the state cannot grow to copy the reference.
+impl Clone for LuaRef {
+    fn clone(&self) -> LuaRef {
+        if !self.open.get() {
+            return LuaRef {
+                main: self.main,
+                key: self.key,
+                open: self.open.clone(),
+            };
+        }
+
+        self.with_value(0, |l| LuaRef::new(l, -1))
+            .unwrap_or(Err(LuaError::StackOverflow))
+            .unwrap_or_else(|err| panic!("cannot clone a LuaRef: {}", err))
+    }
+}
+
+/// Two references are equal when they refer to the same value, compared
+/// without metamethods. Once the state is closed, the values can no longer
+/// be compared, and a reference only equals the clones made of it since.
+impl PartialEq for LuaRef {
+    fn eq(&self, other: &LuaRef) -> bool {
+        if !Rc::ptr_eq(&self.open, &other.open) {
+            return false;
+        }
+
+        if self.key == other.key {
+            return true;
+        }
+
+        self.with_value(1, |l| unsafe {
+            other.push(l);
+            ffi::lua_rawequal(l, -1, -2) != 0
+        }).unwrap_or(false)
+    }
+}
+
+impl Eq for LuaRef {}
+
+impl fmt::Debug for LuaRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LuaRef({})", self.key)
+    }
+}
+
+impl Drop for LuaRef {
+    fn drop(&mut self) {
+        unsafe {
+            // Without room for the free list, the slot is only reclaimed
+            // when the state closes
+            if self.open.get() && ffi::lua_checkstack(self.main, 1) != 0 {
+                ffi::luaL_unref(self.main, ffi::LUA_REGISTRYINDEX, self.key);
+            }
+        }
+    }
+}
+
+impl LuaValue for LuaRef {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        self.push(l);
+    }
+}
+
+impl LuaValue for &LuaRef {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        self.push(l);
+    }
+}
+
+impl FromLua for LuaRef {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        LuaRef::new(state.as_ptr(), idx)
+    }
+}
+
+/// Any Lua value, owned by Rust.
+///
+/// Scalars and strings are copied out of Lua. Tables, functions, threads
+/// and full userdata are held through a [`LuaRef`](struct.LuaRef.html), so
+/// pushing one back gives Lua the very same object.
+///
+/// # Examples
+///
+/// ```
+/// extern crate lua_ffi;
+///
+/// use lua_ffi::{LuaType, State, ThreadStatus, Value};
+///
+/// fn main() {
+///     let mut state = State::new();
+///     state.open_libs();
+///     state.do_string("return 1, 'two', { 3 }");
+///
+///     let values: Vec<Value> = (1..=3).map(|i| state.to(i).unwrap()).collect();
+///     assert_eq!(values[0], Value::Integer(1));
+///     assert_eq!(values[1], Value::from("two"));
+///     assert_eq!(values[2].type_of(), LuaType::Table);
+///     state.settop(0);
+///
+///     for value in values {
+///         state.push(value);
+///     }
+///     state.set_global("t");
+///     assert_eq!(state.do_string("assert(t[1] == 3)"), ThreadStatus::Ok);
+/// }
+/// ```
+#[derive(Clone, PartialEq, Debug, Default)]
+pub enum Value {
+    #[default]
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(LuaString<'static>),
+    Table(LuaRef),
+    Function(LuaRef),
+    Thread(LuaRef),
+    UserData(LuaRef),
+    LightUserData(LightUserData),
+}
+
+impl Value {
+    /// Returns the type of the value.
+    pub fn type_of(&self) -> LuaType {
+        match *self {
+            Value::Nil => LuaType::Nil,
+            Value::Boolean(_) => LuaType::Boolean,
+            Value::Integer(_) | Value::Number(_) => LuaType::Number,
+            Value::String(_) => LuaType::String,
+            Value::Table(_) => LuaType::Table,
+            Value::Function(_) => LuaType::Function,
+            Value::Thread(_) => LuaType::Thread,
+            Value::UserData(_) => LuaType::UserData,
+            Value::LightUserData(_) => LuaType::LightUserData,
+        }
+    }
+
+    /// Returns whether the value is `nil`.
+    pub fn is_nil(&self) -> bool {
+        *self == Value::Nil
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Boolean(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Integer(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Value {
+        Value::Number(n)
+    }
+}
+
+impl From<LuaNumber> for Value {
+    fn from(n: LuaNumber) -> Value {
+        match n {
+            LuaNumber::Integer(n) => Value::Integer(n),
+            LuaNumber::Float(n) => Value::Number(n),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Value {
+        Value::String(LuaString::from(s.to_owned()))
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(LuaString::from(s))
+    }
+}
+
+impl From<LuaString<'static>> for Value {
+    fn from(s: LuaString<'static>) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<LightUserData> for Value {
+    fn from(p: LightUserData) -> Value {
+        Value::LightUserData(p)
+    }
+}
+
+impl<T> From<Option<T>> for Value where T: Into<Value> {
+    fn from(val: Option<T>) -> Value {
+        val.map_or(Value::Nil, Into::into)
+    }
+}
+
+impl LuaValue for Value {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        match self {
+            Value::Nil => unsafe { ffi::lua_pushnil(l) },
+            Value::Boolean(b) => b.push_val(l),
+            Value::Integer(n) => n.push_val(l),
+            Value::Number(n) => n.push_val(l),
+            Value::String(s) => s.push_val(l),
+            Value::Table(r) | Value::Function(r) | Value::Thread(r) | Value::UserData(r) => r.push_val(l),
+            Value::LightUserData(p) => p.push_val(l),
+        }
+    }
+}
+
+/// Never fails: an index past the top of the stack reads as `Nil`.
+impl FromLua for Value {
+    fn from_lua(state: &mut State, idx: c_int) -> Result<Self, LuaError> {
+        let l = state.as_ptr();
+        let value = match state.type_of(idx) {
+            LuaType::None | LuaType::Nil => Value::Nil,
+            LuaType::Boolean => Value::Boolean(unsafe { ffi::lua_toboolean(l, idx) } != 0),
+            LuaType::LightUserData => Value::LightUserData(LightUserData(unsafe { ffi::lua_touserdata(l, idx) })),
+            LuaType::Number if state.is_integer(idx) => Value::Integer(state.to_long(idx).unwrap_or_default()),
+            LuaType::Number => Value::Number(state.to_double(idx).unwrap_or_default()),
+            LuaType::String => Value::String(LuaString::from(state.to_bytes(idx).unwrap_or_default().to_vec())),
+            LuaType::Table => Value::Table(LuaRef::new(l, idx)?),
+            LuaType::Function => Value::Function(LuaRef::new(l, idx)?),
+            LuaType::Thread => Value::Thread(LuaRef::new(l, idx)?),
+            LuaType::UserData => Value::UserData(LuaRef::new(l, idx)?),
+        };
+
+        Ok(value)
+    }
+}
@@ -0,0 +1,96 @@
+extern crate lua_ffi;
+
+use std::path::Path;
+
+use lua_ffi::{LuaError, State, SyntaxError, ThreadStatus};
+
+fn syntax_error(res: Result<(), LuaError>) -> SyntaxError {
+    match res {
+        Err(LuaError::Syntax(err)) => err,
+        res => panic!("expected a syntax error, got {:?}", res),
+    }
+}
+
+#[test]
+pub fn test_chunk_names() {
+    let mut state = State::new();
+    state.open_libs();
+
+    let res = state.load_buffer(b"error('here')", "=quest");
+    assert_eq!(res, ThreadStatus::Ok);
+    assert_eq!(state.pcall(0, 0, 0), Err((ThreadStatus::RuntimeError, "quest:1: here".to_owned())));
+    state.settop(0);
+
+    let err = syntax_error(state.load_chunk(b"x = = 1", "@mods/quest.lua"));
+    assert_eq!(err.chunk, "mods/quest.lua");
+    assert_eq!(err.to_string(), "mods/quest.lua:1: unexpected symbol near '='");
+
+    let err = syntax_error(state.load_chunk(b"x = = 1", "inline"));
+    assert_eq!(err.chunk, "[string \"inline\"]");
+    assert_eq!(state.gettop(), 0);
+
+    match state.do_chunk(b"\n\nerror('late')", "=init") {
+        Err(LuaError::Runtime { message, traceback }) => {
+            assert_eq!(message, "init:3: late");
+            assert!(traceback.unwrap().contains("init:3: in main chunk"));
+        }
+        res => panic!("expected a runtime error, got {:?}", res),
+    }
+
+    assert_eq!(state.do_chunk(b"return 1 + 1", "=sum"), Ok(()));
+    assert_eq!(state.to::<i32>(-1), Ok(2));
+}
+
+#[test]
+pub fn test_incomplete_input() {
+    let mut state = State::new();
+
+    let err = syntax_error(state.load_chunk(b"function f()\n  return 1\n", "=repl"));
+    assert_eq!(err, SyntaxError {
+        chunk: "repl".to_owned(),
+        line: 3,
+        message: "'end' expected (to close 'function' at line 1) near <eof>".to_owned(),
+        incomplete_input: true,
+    });
+
+    let err = syntax_error(state.load_chunk(b"s = \"abc", "=repl"));
+    assert!(err.incomplete_input, "{:?}", err);
+
+    let err = syntax_error(state.load_chunk(b"return )", "=repl"));
+    assert!(!err.incomplete_input);
+}
+
+#[test]
+pub fn test_parse() {
+    let err = SyntaxError::parse("[string \"a:1: b\"]:4: '=' expected near 'c'");
+    assert_eq!(err.chunk, "[string \"a:1: b\"]");
+    assert_eq!(err.line, 4);
+    assert_eq!(err.message, "'=' expected near 'c'");
+
+    let err = SyntaxError::parse("C:\\mods\\a.lua:12: unexpected symbol near '2:'");
+    assert_eq!(err.chunk, "C:\\mods\\a.lua");
+    assert_eq!(err.line, 12);
+
+    let err = SyntaxError::parse("not enough memory");
+    assert_eq!((err.chunk.as_str(), err.line), ("", 0));
+    assert_eq!(err.to_string(), "not enough memory");
+}
+
+#[test]
+pub fn test_file_errors() {
+    let mut state = State::new();
+    state.open_libs();
+
+    match state.do_file(Path::new("./tests/lua/missing.lua")) {
+        Err(LuaError::File(message)) => assert!(message.starts_with("cannot open ./tests/lua/missing.lua"), "{}", message),
+        res => panic!("expected a file error, got {:?}", res),
+    }
+
+    let err = syntax_error(state.load_file(Path::new("./tests/lua/test2.lua")));
+    assert_eq!(err.chunk, "./tests/lua/test2.lua");
+    assert_eq!(err.line, 1);
+    assert_eq!(state.gettop(), 0);
+
+    assert_eq!(state.do_string("x = 1\0"), ThreadStatus::SyntaxError);
+    assert!(state.to_str(-1).unwrap().starts_with("[string \"x = 1\"]:1: unexpected symbol"));
+}
@@ -0,0 +1,198 @@
+#[macro_use] extern crate lua_ffi;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use lua_ffi::types::LuaValue;
+use lua_ffi::{c_int, ffi, FromLua, LuaError, LuaReturn, State, ThreadStatus};
+
+fn read<T>(state: &mut State, code: &str) -> Result<T, LuaError> where T: FromLua {
+    assert_eq!(state.do_string(code), ThreadStatus::Ok);
+    let top = unsafe { lua_ffi::ffi::lua_gettop(state.as_ptr()) };
+    let res = state.to::<T>(-1);
+    assert_eq!(unsafe { lua_ffi::ffi::lua_gettop(state.as_ptr()) }, top);
+    state.pop(1);
+
+    res
+}
+
+fn message<T>(res: Result<T, LuaError>) -> String {
+    match res {
+        Ok(_) => panic!("conversion should have failed"),
+        Err(err) => err.to_string(),
+    }
+}
+
+#[test]
+pub fn test_sequences() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.push(vec![1, 2, 3]);
+    state.set_global("v");
+    state.push(&["a", "b"][..]);
+    state.set_global("s");
+    let res = state.do_string(r#"assert(#v == 3 and v[3] == 3) assert(#s == 2 and s[1] == "a")"#);
+    assert_eq!(res, ThreadStatus::Ok);
+
+    assert_eq!(read::<Vec<i32>>(&mut state, "return { 4, 5, 6 }"), Ok(vec![4, 5, 6]));
+    assert_eq!(read::<Vec<i32>>(&mut state, "return {}"), Ok(vec![]));
+    assert_eq!(
+        read::<Vec<Vec<String>>>(&mut state, "return { { 'a' }, {}, { 'b', 'c' } }"),
+        Ok(vec![vec!["a".to_owned()], vec![], vec!["b".to_owned(), "c".to_owned()]])
+    );
+
+    assert_eq!(message(read::<Vec<i32>>(&mut state, "return { 1, nil, 3 }")), "sequence has a hole at index 2");
+    assert_eq!(message(read::<Vec<i32>>(&mut state, "return { 1, 2, [5] = 5 }")), "sequence has a hole at index 3");
+    assert_eq!(
+        message(read::<Vec<i32>>(&mut state, "return { 1, 2, name = 'x' }")),
+        "expected a sequence, found key 'name'"
+    );
+    assert_eq!(
+        message(read::<Vec<i32>>(&mut state, "return { [0] = 0, 1 }")),
+        "expected a sequence, found key 0"
+    );
+    assert_eq!(message(read::<Vec<i32>>(&mut state, "return { 1, 'two' }")), "[2]: number expected, got string");
+    assert_eq!(
+        message(read::<Vec<Vec<i32>>>(&mut state, "return { {}, { 1, true } }")),
+        "[2][2]: number expected, got boolean"
+    );
+    assert_eq!(message(read::<Vec<i32>>(&mut state, "return 'abc'")), "table expected, got string");
+}
+
+#[test]
+pub fn test_maps() {
+    let mut state = State::new();
+    state.open_libs();
+
+    let mut map = HashMap::new();
+    map.insert("hp".to_owned(), 10);
+    map.insert("mp".to_owned(), 4);
+    state.push(map.clone());
+    state.set_global("m");
+    let mut tree = BTreeMap::new();
+    tree.insert(3, "three");
+    state.push(tree);
+    state.set_global("t");
+    let res = state.do_string("assert(m.hp == 10 and m.mp == 4) assert(t[3] == 'three')");
+    assert_eq!(res, ThreadStatus::Ok);
+
+    assert_eq!(read::<HashMap<String, i32>>(&mut state, "return m"), Ok(map));
+    let tree = read::<BTreeMap<i32, String>>(&mut state, "return { [2] = 'b', [1] = 'a' }").unwrap();
+    assert_eq!(tree.into_iter().collect::<Vec<_>>(), vec![(1, "a".to_owned()), (2, "b".to_owned())]);
+
+    assert_eq!(
+        message(read::<HashMap<String, i32>>(&mut state, "return { hp = 'full' }")),
+        "hp: number expected, got string"
+    );
+    assert_eq!(
+        message(read::<HashMap<String, i32>>(&mut state, "return { hp = 1, [true] = 2 }")),
+        "invalid key (string expected, got boolean)"
+    );
+    assert_eq!(
+        message(read::<HashMap<String, Vec<i32>>>(&mut state, "return { path = { 1, nil, 3 } }")),
+        "path: sequence has a hole at index 2"
+    );
+}
+
+#[test]
+pub fn test_sets() {
+    let mut state = State::new();
+    state.open_libs();
+
+    let set: HashSet<String> = vec!["fire".to_owned(), "ice".to_owned()].into_iter().collect();
+    state.push(set.clone());
+    state.set_global("tags");
+    let res = state.do_string("assert(tags.fire == true and tags.ice == true and tags.earth == nil)");
+    assert_eq!(res, ThreadStatus::Ok);
+
+    assert_eq!(read::<HashSet<String>>(&mut state, "return tags"), Ok(set));
+    assert_eq!(read::<HashSet<i32>>(&mut state, "return { [1] = true, [4] = true }"), Ok(vec![1, 4].into_iter().collect()));
+    assert_eq!(
+        message(read::<HashSet<String>>(&mut state, "return { fire = true, ice = false }")),
+        "ice: true expected, got false"
+    );
+    assert_eq!(
+        message(read::<HashSet<String>>(&mut state, "return { 'fire' }")),
+        "[1]: true expected, got string"
+    );
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct NotANumber;
+
+impl LuaValue for NotANumber {
+    fn push_val(self, l: *mut ffi::lua_State) {
+        unsafe { ffi::lua_pushnumber(l, f64::NAN) };
+    }
+}
+
+#[test]
+pub fn test_invalid_keys() {
+    let mut state = State::new();
+
+    let mut map = BTreeMap::new();
+    map.insert(None, 1);
+    map.insert(Some(2), 2);
+    assert_eq!(message(state.try_push(vec![map])), "[1]: table key cannot be nil or NaN");
+
+    let set: HashSet<NotANumber> = vec![NotANumber].into_iter().collect();
+    assert_eq!(message(state.try_push(set)), "table key cannot be nil or NaN");
+
+    let mut map = HashMap::new();
+    map.insert(u64::MAX, "max");
+    assert_eq!(message(state.try_push(map)), "invalid key (u64 18446744073709551615 does not fit in a Lua integer)");
+
+    let mut map = HashMap::new();
+    map.insert("max", u64::MAX);
+    assert_eq!(message(state.try_push(map)), "max: u64 18446744073709551615 does not fit in a Lua integer");
+    assert_eq!(state.gettop(), 0);
+}
+
+#[test]
+pub fn test_option() {
+    let mut state = State::new();
+
+    state.push(Some(5));
+    state.push(None::<i32>);
+    assert_eq!(state.to::<Option<i32>>(-2), Ok(Some(5)));
+    assert_eq!(state.to::<Option<i32>>(-1), Ok(None));
+    assert_eq!(state.to::<Option<i32>>(10), Ok(None));
+    assert!(state.to::<Option<bool>>(-2).is_err());
+}
+
+fn min_max(state: &mut State) -> Result<c_int, LuaError> {
+    let values: Vec<f64> = state.to(1)?;
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+    (min, max).push_return(state)
+}
+
+fn origin(state: &mut State) -> Result<c_int, LuaError> {
+    [0, 0, 0].push_return(state)
+}
+
+#[test]
+pub fn test_multiple_values() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(lua_fn!(min_max));
+    state.set_global("min_max");
+    state.push(lua_fn!(origin));
+    state.set_global("origin");
+
+    let res = state.do_string(
+        "local lo, hi = min_max({ 3, 1, 2 })
+        assert(lo == 1 and hi == 3)
+        assert(select('#', origin()) == 3)"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+
+    state.do_string("return 1, 'two', true");
+    assert_eq!(state.to::<(i32, String, bool)>(-3), Ok((1, "two".to_owned(), true)));
+    assert!(state.to::<(i32, i32)>(-3).is_err());
+    state.pop(3);
+
+    state.do_string("return origin()");
+    assert_eq!(state.to::<[i32; 3]>(-3), Ok([0, 0, 0]));
+}
@@ -1,6 +1,9 @@
-#[macro_use] extern crate luajit;
+#[macro_use] extern crate lua_ffi;
 
-use luajit::{State, c_int, ThreadStatus};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use lua_ffi::{State, c_int, LuaError, LuaType, ThreadStatus};
 
 fn return_42(state: &mut State) -> c_int {
     state.push(42);
@@ -28,3 +31,125 @@ fn test_push_lua_function() {
     let status = state.do_string("if return_42() ~= 42 then error() end");
     assert_eq!(status, ThreadStatus::Ok);
 }
+
+fn open(state: &mut State) -> Result<c_int, LuaError> {
+    let path = state.check_arg::<String>(1)?;
+    let mode = state.check_option(2, &["r", "w", "a"])?;
+    let retries = state.opt_arg(3, 1u32)?;
+    state.push(format!("{} {} {}", path, mode, retries));
+
+    Ok(1)
+}
+
+#[test]
+fn test_argument_checks() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(lua_fn!(open));
+    state.set_global("open");
+
+    let status = state.do_string(
+        r#"assert(open("log", "a") == "log 2 1")
+        assert(open("log", "w", nil) == "log 1 1")
+        assert(open("log", "r", 3) == "log 0 3")
+        local function message(...)
+            local ok, err = pcall(function(...) open(...) end, ...)
+            assert(not ok)
+            return err
+        end
+        assert(message() == "bad argument #1 to 'open' (string expected, got no value)")
+        assert(message({}) == "bad argument #1 to 'open' (string expected, got table)")
+        assert(message("log", "x") == "bad argument #2 to 'open' (invalid option 'x')")
+        assert(message("log", 1) == "bad argument #2 to 'open' (string expected, got number)")
+        assert(message("log", "r", -1) == "bad argument #3 to 'open' (integer -1 is out of range for u32)")"#
+    );
+    assert_eq!(status, ThreadStatus::Ok);
+}
+
+thread_local! {
+    static DROPPED: Cell<u32> = const { Cell::new(0) };
+}
+
+struct Tracked(#[allow(dead_code)] String);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        DROPPED.with(|d| d.set(d.get() + 1));
+    }
+}
+
+fn fail(state: &mut State) -> Result<c_int, LuaError> {
+    let _name = Tracked(state.check_arg(1)?);
+    let _buf = Tracked(String::with_capacity(1024));
+
+    Err(LuaError::Conversion { path: String::new(), message: "failed after allocating".to_owned() })
+}
+
+fn fail_with_code(state: &mut State) -> Result<c_int, LuaError> {
+    let _guard = Tracked(String::new());
+    let mut err = HashMap::new();
+    err.insert("code", state.check_arg::<i32>(1)?);
+
+    Err(state.error_value(err))
+}
+
+#[test]
+fn test_result_handlers() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(lua_fn!(fail));
+    state.set_global("fail");
+    state.push(lua_fn!(fail_with_code));
+    state.set_global("fail_with_code");
+
+    let status = state.do_string(
+        r#"local ok, err = pcall(fail, "x")
+        assert(not ok and err == "failed after allocating")
+        ok, err = pcall(fail_with_code, 7)
+        assert(not ok and type(err) == "table" and err.code == 7)
+        ok, err = pcall(fail_with_code, "x")
+        assert(err == "bad argument #1 to '?' (number expected, got string)")"#
+    );
+    assert_eq!(status, ThreadStatus::Ok);
+    assert_eq!(DROPPED.with(Cell::get), 4);
+
+    let err = state.error_value(HashMap::<i32, i32>::new());
+    assert_eq!(err.to_string(), "(error object is a table value)");
+    assert_eq!(state.error_value("plain").to_string(), "plain");
+    assert_eq!(state.error_value(7).to_string(), "7");
+}
+
+fn assert_send_sync<T: Send + Sync + 'static>(_: T) {}
+
+#[test]
+fn test_error_value_outlives_state() {
+    let err = {
+        let mut state = State::new();
+        state.error_value(HashMap::<&str, i32>::new())
+    };
+
+    match err {
+        LuaError::Value(ref val) => assert_eq!(val.type_of(), LuaType::Table),
+        ref err => panic!("unexpected {:?}", err),
+    }
+    assert_send_sync(err);
+
+    // Only the latest value is kept, an older one raises its description
+    let mut state = State::new();
+    state.open_libs();
+    let older = state.error_value(HashMap::<&str, i32>::new());
+    state.error_value(1);
+    OLDER.with(|cell| *cell.borrow_mut() = Some(older));
+    state.push(lua_fn!(fail_older));
+    state.set_global("fail_older");
+    let res = state.do_string(r#"local ok, err = pcall(fail_older) assert(err == "(error object is a table value)")"#);
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+thread_local! {
+    static OLDER: RefCell<Option<LuaError>> = const { RefCell::new(None) };
+}
+
+fn fail_older(_: &mut State) -> Result<c_int, LuaError> {
+    Err(OLDER.with(|cell| cell.borrow_mut().take().unwrap()))
+}
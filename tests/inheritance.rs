@@ -0,0 +1,162 @@
+#[macro_use] extern crate lua_ffi;
+
+use lua_ffi::{LuaField, LuaObject, LuaParent, LuaUserData, State, ThreadStatus, c_int, ffi};
+
+struct Entity {
+    hp: i32,
+}
+
+impl LuaObject for Entity {
+    fn name() -> *const i8 {
+        c_str!("Entity")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!(lua_method!("damage", Entity, Entity::damage))
+    }
+
+    fn lua_fields() -> Vec<LuaField<Entity>> {
+        vec!(LuaField::new("hp").getter(|e: &Entity| e.hp).setter(|e: &mut Entity, hp| e.hp = hp))
+    }
+}
+
+impl Entity {
+    fn damage(&mut self, state: &mut State) -> c_int {
+        self.hp -= state.to_int(2).unwrap();
+
+        0
+    }
+}
+
+struct Monster {
+    entity: Entity,
+    rage: i32,
+}
+
+impl LuaObject for Monster {
+    fn name() -> *const i8 {
+        c_str!("Monster")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!(lua_method!("roar", Monster, Monster::roar))
+    }
+
+    fn lua_fields() -> Vec<LuaField<Monster>> {
+        vec!(LuaField::new("rage").getter(|m: &Monster| m.rage))
+    }
+
+    fn lua_parent() -> Option<LuaParent<Monster>> {
+        Some(LuaParent::new(|m: &Monster| &m.entity, |m: &mut Monster| &mut m.entity))
+    }
+}
+
+impl Monster {
+    fn roar(&mut self, _: &mut State) -> c_int {
+        self.rage += 1;
+
+        0
+    }
+}
+
+struct Boss {
+    monster: Monster,
+}
+
+impl LuaObject for Boss {
+    fn name() -> *const i8 {
+        c_str!("Boss")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!()
+    }
+
+    fn lua_parent() -> Option<LuaParent<Boss>> {
+        Some(LuaParent::new(|b: &Boss| &b.monster, |b: &mut Boss| &mut b.monster))
+    }
+}
+
+fn monster() -> Monster {
+    Monster {
+        entity: Entity { hp: 10 },
+        rage: 0,
+    }
+}
+
+// Reads the hp of any Entity through `check_userdata`.
+fn entity_hp(state: &mut State) -> c_int {
+    let hp = unsafe { (*state.check_userdata::<Entity>(1).unwrap()).hp };
+    state.push(hp);
+
+    1
+}
+
+fn error_of(state: &mut State, code: &str) -> String {
+    state.do_string(&format!("ok, err = pcall(function() {} end)", code));
+    state.get_global("err");
+    let err = state.to_str(-1).unwrap_or_default().to_owned();
+    state.pop(1);
+    err
+}
+
+#[test]
+fn parent_methods_and_fields() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(monster());
+    state.set_global("m");
+
+    let res = state.do_string(
+        "m:damage(3)
+        m:roar()
+        assert(m.hp == 7 and m.rage == 1)
+        m.hp = 20
+        assert(m.hp == 20)"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+
+    assert!(error_of(&mut state, "return m.z").ends_with("no field 'z' on Entity"));
+    assert!(error_of(&mut state, "m.rage = 2").ends_with("field 'rage' on Monster is read-only"));
+}
+
+#[test]
+fn accepted_as_parent() {
+    let mut state = State::new();
+    state.open_libs();
+    state.register("entity_hp", lua_fn!(entity_hp).unwrap());
+    state.push(Entity { hp: 1 });
+    state.set_global("e");
+    state.push(monster());
+    state.set_global("m");
+    state.push(Boss { monster: monster() });
+    state.set_global("b");
+
+    let res = state.do_string(
+        "assert(entity_hp(e) == 1)
+        assert(entity_hp(m) == 10)
+        b:damage(4)
+        b:roar()
+        assert(entity_hp(b) == 6 and b.rage == 1)"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+
+    // Parents are not accepted in place of their children
+    assert!(error_of(&mut state, "m.roar(e)").contains("Monster expected, got Entity"));
+}
+
+#[test]
+fn borrows_are_shared() {
+    let mut state = State::new();
+    state.push(monster());
+
+    let m = state.to::<LuaUserData<Monster>>(-1).unwrap();
+    let e = state.to::<LuaUserData<Entity>>(-1).unwrap();
+
+    let guard = m.borrow_mut().unwrap();
+    assert!(e.borrow().is_err());
+    drop(guard);
+
+    e.borrow_mut().unwrap().hp = 3;
+    assert_eq!(m.borrow().unwrap().entity.hp, 3);
+}
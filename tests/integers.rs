@@ -0,0 +1,110 @@
+extern crate lua_ffi;
+
+use lua_ffi::{LuaError, LuaNumber, State, ThreadStatus};
+
+#[test]
+pub fn test_full_range() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.push(i64::MAX);
+    state.set_global("max");
+    state.push(i64::MIN);
+    state.set_global("min");
+    let res = state.do_string("assert(max == math.maxinteger and min == math.mininteger)");
+    assert_eq!(res, ThreadStatus::Ok);
+
+    state.do_string("return math.maxinteger, math.mininteger");
+    assert_eq!(state.to::<i64>(-2), Ok(i64::MAX));
+    assert_eq!(state.to::<i64>(-1), Ok(i64::MIN));
+    assert_eq!(state.to::<u64>(-2), Ok(i64::MAX as u64));
+    assert_eq!(state.to::<i128>(-1), Ok(i64::MIN as i128));
+}
+
+#[test]
+pub fn test_checked_reads() {
+    let mut state = State::new();
+
+    state.do_string("return 3.0, 3.5, 2^31, -1, 2^63, '7'");
+    assert_eq!(state.to::<i32>(1), Ok(3));
+    assert_eq!(
+        state.to::<i32>(2),
+        Err(LuaError::Conversion { path: String::new(), message: "number has no integer representation".to_owned() })
+    );
+    assert_eq!(state.to_int(2), None);
+    assert_eq!(state.to::<i64>(3), Ok(1 << 31));
+    assert_eq!(state.to::<i32>(3).unwrap_err().to_string(), "integer 2147483648 is out of range for i32");
+    assert_eq!(state.to_int(3), None);
+    assert_eq!(state.to::<u8>(4).unwrap_err().to_string(), "integer -1 is out of range for u8");
+    assert_eq!(state.to_uint(4), None);
+    assert!(state.to::<i64>(5).is_err());
+    assert_eq!(state.to_long(5), None);
+    assert_eq!(state.to::<u16>(6), Ok(7));
+    assert!(state.to::<i32>(7).is_err());
+}
+
+#[test]
+pub fn test_subtype() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.do_string("return 3, 3.0, '3'");
+    assert!(state.is_integer(1));
+    assert!(!state.is_integer(2));
+    assert!(!state.is_integer(3));
+    assert_eq!(state.to_number(1), Some(LuaNumber::Integer(3)));
+    assert_eq!(state.to_number(2), Some(LuaNumber::Float(3.0)));
+    assert_eq!(state.to_number(3), None);
+    assert!(state.to::<LuaNumber>(3).is_err());
+
+    let a = state.to::<LuaNumber>(1).unwrap();
+    let b = state.to::<LuaNumber>(2).unwrap();
+    state.settop(0);
+    state.push(a);
+    state.set_global("a");
+    state.push(b);
+    state.set_global("b");
+    let res = state.do_string(r#"assert(math.type(a) == "integer" and math.type(b) == "float")"#);
+    assert_eq!(res, ThreadStatus::Ok);
+
+    assert_eq!(LuaNumber::Float(4.0).to_i64(), Some(4));
+    assert_eq!(LuaNumber::Float(4.5).to_i64(), None);
+    assert_eq!(LuaNumber::Float(9.3e18).to_i64(), None);
+    assert_eq!(LuaNumber::from(2).to_f64(), 2.0);
+}
+
+#[test]
+pub fn test_checked_pushes() {
+    let mut state = State::new();
+    state.open_libs();
+
+    assert!(state.push_integer(i64::MAX as u64).is_ok());
+    state.set_global("big");
+    let res = state.do_string("assert(big == math.maxinteger)");
+    assert_eq!(res, ThreadStatus::Ok);
+
+    assert_eq!(
+        state.push_integer(u64::MAX).unwrap_err().to_string(),
+        "integer 18446744073709551615 does not fit in a Lua integer"
+    );
+    assert!(state.push_integer(i128::MIN).is_err());
+    assert_eq!(unsafe { lua_ffi::ffi::lua_gettop(state.as_ptr()) }, 0);
+
+    state.push(usize::MAX >> 1);
+    assert_eq!(state.to::<usize>(-1), Ok(usize::MAX >> 1));
+}
+
+#[test]
+pub fn test_push_overflow() {
+    let mut state = State::new();
+
+    assert_eq!(
+        state.try_push(u64::MAX).unwrap_err().to_string(),
+        "u64 18446744073709551615 does not fit in a Lua integer"
+    );
+    assert_eq!(
+        state.try_push(vec![Some(1), None, Some(u128::MAX)]).unwrap_err().to_string(),
+        "[3]: u128 340282366920938463463374607431768211455 does not fit in a Lua integer"
+    );
+    assert_eq!(state.gettop(), 0);
+}
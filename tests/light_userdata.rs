@@ -0,0 +1,65 @@
+extern crate lua_ffi;
+
+use lua_ffi::{ffi, LightUserData, LuaError, State, ThreadStatus};
+
+fn pointer_to<T>(val: &mut T) -> LightUserData {
+    LightUserData(val as *mut T as *mut _)
+}
+
+#[test]
+fn push_and_read() {
+    let mut state = State::new();
+    let mut host = 1u32;
+    let key = pointer_to(&mut host);
+
+    state.push(key);
+    assert!(state.is_userdata(-1));
+    assert!(state.is_light_userdata(-1));
+    assert_eq!(state.to::<LightUserData>(-1), Ok(key));
+    assert_eq!(state.to_raw_userdata(-1), None);
+
+    state.new_raw_userdata(4);
+    assert!(!state.is_light_userdata(-1));
+    assert!(state.to_raw_userdata(-1).is_some());
+    assert_eq!(state.to::<LightUserData>(-1), Err(LuaError::TypeMismatch {
+        expected: "light userdata".to_owned(),
+        got: "userdata".to_owned(),
+    }));
+}
+
+#[test]
+fn equality() {
+    let mut state = State::new();
+    state.open_libs();
+    let (mut a, mut b) = (0u8, 0u8);
+
+    state.push(pointer_to(&mut a));
+    state.set_global("a");
+    state.push(pointer_to(&mut a));
+    state.set_global("a2");
+    state.push(pointer_to(&mut b));
+    state.set_global("b");
+
+    let res = state.do_string("assert(a == a2) assert(a ~= b) assert(type(a) == 'userdata')");
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+fn registry_key() {
+    let mut state = State::new();
+    state.open_libs();
+    let mut host = 0u64;
+    let key = pointer_to(&mut host);
+
+    state.push(key);
+    state.push("cached");
+    unsafe {
+        ffi::lua_rawset(state.as_ptr(), ffi::LUA_REGISTRYINDEX);
+    }
+
+    state.push(key);
+    unsafe {
+        ffi::lua_rawget(state.as_ptr(), ffi::LUA_REGISTRYINDEX);
+    }
+    assert_eq!(state.to_str(-1), Some("cached"));
+}
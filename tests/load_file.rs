@@ -1,7 +1,7 @@
-#[macro_use] extern crate luajit;
+#[macro_use] extern crate lua_ffi;
 
-use luajit::{State, LuaObject};
-use luajit::ffi::luaL_Reg;
+use lua_ffi::{State, LuaObject};
+use lua_ffi::ffi::luaL_Reg;
 use std::path::Path;
 
 struct Point2D {
@@ -25,6 +25,7 @@ impl LuaObject for Point2D {
 }
 
 impl Point2D {
+    #[allow(clippy::new_ret_no_self)]
     fn new(state: &mut State) -> i32 {
         state.push(Point2D {
             x: 0,
@@ -0,0 +1,30 @@
+-- Point2D is a struct defined in rust
+describe("Point2D", function()
+    it("starts at the origin", function()
+        local point = Point2D:new()
+        assert_eq(point:add(), 0)
+    end)
+
+    it("adds its coordinates", function()
+        local point = Point2D:new()
+        point:setX(2)
+        point:setY(4)
+        assert_eq(point:add(), 6)
+    end)
+
+    describe("in tables", function()
+        it("compares by contents", function()
+            local point = Point2D:new()
+            point:setX(1)
+            assert_eq({ sum = point:add(), tags = { "a", "b" } }, { sum = 1, tags = { "a", "b" } })
+        end)
+    end)
+end)
+
+it("runs every case in a fresh state", function()
+    leaked = true
+end)
+
+it("does not see globals of other cases", function()
+    assert_eq(leaked, nil)
+end)
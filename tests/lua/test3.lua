@@ -0,0 +1 @@
+error({ code = 3 })
@@ -0,0 +1,5 @@
+it("is never run", function() end)
+
+describe("broken", function()
+    local x = nil + 1
+end)
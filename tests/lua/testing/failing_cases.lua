@@ -0,0 +1,19 @@
+describe("failing cases", function()
+    it("compares numbers", function()
+        assert_eq(1 + 1, 3)
+    end)
+
+    it("compares nested tables", function()
+        local t = { 1, { x = 2 } }
+        t.self = t
+        assert_eq(t, { 1, { x = 3 }, self = t }, "nested")
+    end)
+
+    it("raises errors", function()
+        error("unexpected")
+    end)
+end)
+
+it("passes", function()
+    assert_eq({}, {})
+end)
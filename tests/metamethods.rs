@@ -0,0 +1,233 @@
+#[macro_use] extern crate lua_ffi;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lua_ffi::{State, LuaObject, LuaMetaMethod, Operand, ThreadStatus, c_int, ffi};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Vec2 {
+    x: f64,
+    y: f64,
+}
+
+impl LuaObject for Vec2 {
+    fn name() -> *const i8 {
+        c_str!("Vec2")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!(lua_func!("new", Vec2::new))
+    }
+
+    fn lua_metamethods() -> Vec<LuaMetaMethod<Vec2>> {
+        vec!(
+            LuaMetaMethod::add(|a: Operand<Vec2, f64>, b| {
+                match (a, b) {
+                    (Operand::Object(a), Operand::Object(b)) => Vec2 { x: a.x + b.x, y: a.y + b.y },
+                    (Operand::Object(v), Operand::Value(k)) |
+                    (Operand::Value(k), Operand::Object(v)) => Vec2 { x: v.x + k, y: v.y + k },
+                    _ => unreachable!(),
+                }
+            }),
+            LuaMetaMethod::sub(|a: Operand<Vec2, f64>, b| {
+                match (a, b) {
+                    (Operand::Object(a), Operand::Object(b)) => Vec2 { x: a.x - b.x, y: a.y - b.y },
+                    (Operand::Object(v), Operand::Value(k)) => Vec2 { x: v.x - k, y: v.y - k },
+                    (Operand::Value(k), Operand::Object(v)) => Vec2 { x: k - v.x, y: k - v.y },
+                    _ => unreachable!(),
+                }
+            }),
+            LuaMetaMethod::unm(|v: &Vec2| Vec2 { x: -v.x, y: -v.y }),
+            LuaMetaMethod::len(|v: &Vec2| (v.x * v.x + v.y * v.y).sqrt()),
+            LuaMetaMethod::eq(|a: &Vec2, b: &Vec2| a == b),
+            LuaMetaMethod::lt(|a: Operand<Vec2, f64>, b| {
+                let len = |op: Operand<Vec2, f64>| match op {
+                    Operand::Object(v) => (v.x * v.x + v.y * v.y).sqrt(),
+                    Operand::Value(k) => k,
+                };
+                len(a) < len(b)
+            }),
+            LuaMetaMethod::concat(|a: Operand<Vec2, String>, b| {
+                let s = |op: Operand<Vec2, String>| match op {
+                    Operand::Object(v) => format!("({}, {})", v.x, v.y),
+                    Operand::Value(s) => s,
+                };
+                s(a) + &s(b)
+            }),
+            LuaMetaMethod::tostring(|v: &Vec2| format!("Vec2({}, {})", v.x, v.y)),
+            LuaMetaMethod::call(|v: &mut Vec2, state: &mut State| {
+                v.x = state.to_double(2).unwrap_or(v.x);
+                v.y = state.to_double(3).unwrap_or(v.y);
+            }),
+        )
+    }
+}
+
+impl Vec2 {
+    #[allow(clippy::new_ret_no_self)]
+    fn new(state: &mut State) -> c_int {
+        let x = state.to_double(1).unwrap_or(0.0);
+        let y = state.to_double(2).unwrap_or(0.0);
+        state.push(Vec2 { x, y });
+
+        1
+    }
+}
+
+fn new_state() -> State {
+    let mut state = State::new();
+    state.open_libs();
+    state.register_struct::<Vec2>();
+    state.pop(1);
+    state
+}
+
+#[test]
+fn arithmetic() {
+    let mut state = new_state();
+
+    let res = state.do_string(
+        "local a, b = Vec2.new(1, 2), Vec2.new(3, 4)
+        assert(a + b == Vec2.new(4, 6))
+        assert(a + 1 == Vec2.new(2, 3))
+        assert(1 + a == Vec2.new(2, 3))
+        assert(a - 1 == Vec2.new(0, 1))
+        assert(1 - a == Vec2.new(0, -1))
+        assert(-a == Vec2.new(-1, -2))
+        assert(#b == 5)"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+fn comparison() {
+    let mut state = new_state();
+
+    let res = state.do_string(
+        "local a, b = Vec2.new(1, 0), Vec2.new(3, 4)
+        assert(a == Vec2.new(1, 0))
+        assert(a ~= b)
+        assert(a < b)
+        assert(b > a)
+        assert(a < 2)
+        assert(2 > a)
+        assert(not (b < 2))
+        assert(a ~= io.stdout)"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+fn strings_and_calls() {
+    let mut state = new_state();
+
+    let res = state.do_string(
+        r#"local a = Vec2.new(1, 2)
+        assert(tostring(a) == "Vec2(1, 2)")
+        assert("a = " .. a == "a = (1, 2)")
+        assert(a .. "!" == "(1, 2)!")
+        a(5, 6)
+        assert(a == Vec2.new(5, 6))"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+fn bad_operand() {
+    let mut state = new_state();
+
+    state.do_string(r#"ok, err = pcall(function() return Vec2.new() + {} end)"#);
+    state.get_global("err");
+    assert_eq!(state.to_str(-1), Some("bad operand #2 to '__add' on Vec2 (number expected, got table)"));
+}
+
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+struct Guard;
+
+impl LuaObject for Guard {
+    fn name() -> *const i8 {
+        c_str!("Guard")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!()
+    }
+
+    fn lua_metamethods() -> Vec<LuaMetaMethod<Guard>> {
+        vec!(LuaMetaMethod::close(|_: &mut Guard| {}))
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        DROPPED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn gc_drops_value() {
+    {
+        let mut state = State::new();
+        state.open_libs();
+        state.push(Guard);
+        state.push(Guard);
+        state.set_global("guard");
+        state.pop(1);
+
+        let res = state.do_string(
+            "assert(type(getmetatable(guard).__close) == 'function')
+            collectgarbage()"
+        );
+        assert_eq!(res, ThreadStatus::Ok);
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+    }
+
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 2);
+}
+
+struct Counter {
+    calls: i64,
+}
+
+impl LuaObject for Counter {
+    fn name() -> *const i8 {
+        c_str!("Counter")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!()
+    }
+
+    fn lua_metamethods() -> Vec<LuaMetaMethod<Counter>> {
+        vec!(
+            // Calls the function at argument 2 while the counter is borrowed
+            LuaMetaMethod::call(|c: &mut Counter, state: &mut State| {
+                c.calls += 1;
+                state.push_value(2);
+                state.call(0, 0);
+                c.calls
+            }),
+            LuaMetaMethod::pairs(|_: &mut Counter, state: &mut State| {
+                state.error("no pairs");
+            }),
+        )
+    }
+}
+
+#[test]
+fn errors_release_borrow() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(Counter { calls: 0 });
+    state.set_global("counter");
+
+    let res = state.do_string(
+        r#"local ok, err = pcall(counter, function() error({ code = 7 }) end)
+        assert(not ok and err.code == 7)
+        ok, err = pcall(pairs, counter)
+        assert(not ok and err:find("no pairs$"))
+        assert(counter(function() end) == 2)"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
@@ -0,0 +1,106 @@
+extern crate lua_ffi;
+
+use std::env;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use lua_ffi::reload::Reloader;
+use lua_ffi::{LuaError, State};
+
+/// Writes a new version of the script at `path`, with a later modification
+/// time even on file systems with a coarse clock.
+fn edit(path: &PathBuf, code: &str) {
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).unwrap();
+    fs::write(path, code).unwrap();
+    File::options().write(true).open(path).unwrap().set_modified(modified + Duration::from_secs(1)).unwrap();
+}
+
+/// Writes the script `name`, returning a path to it which is not canonical.
+fn script(name: &str, code: &str) -> PathBuf {
+    let path = env::temp_dir().join(".").join(format!("lua-ffi-reload-{}-{}.lua", process::id(), name));
+    fs::write(&path, code).unwrap();
+
+    path
+}
+
+#[test]
+pub fn test_reload_preserves_tables() {
+    let path = script("game", "
+        Game = { score = 0 }
+        function Game.points() return 1 end
+    ");
+
+    let mut state = State::new();
+    state.open_libs();
+    let reloader = Reloader::new().preserve("Game");
+    state.do_file(&path).unwrap();
+    state.do_string("held = Game; Game.score = 10");
+
+    assert!(reloader.poll(&mut state).is_empty());
+
+    edit(&path, "
+        Game = { score = 0, lives = 3 }
+        function Game.points() return 2 end
+        function on_reload() reloaded_score = Game.score end
+    ");
+    let reloads = reloader.poll(&mut state);
+    assert_eq!(reloads.len(), 1);
+    assert_eq!(reloads[0].path, path.canonicalize().unwrap());
+    assert_eq!(reloads[0].result, Ok(()));
+    assert!(reloader.poll(&mut state).is_empty());
+
+    state.do_chunk(b"return held == Game, Game.score, Game.lives, Game.points(), reloaded_score", "=check").unwrap();
+    assert_eq!(state.to::<(bool, i32, i32, i32, i32)>(1), Ok((true, 10, 3, 2, 10)));
+    assert_eq!(state.gettop(), 5);
+}
+
+#[test]
+pub fn test_reload_keeps_new_on_reload() {
+    let game = script("callback-game", "function on_reload() game_reloads = (game_reloads or 0) + 1 end");
+    let ui = script("callback-ui", "ui_version = 1");
+
+    let mut state = State::new();
+    state.open_libs();
+    let reloader = Reloader::new();
+    state.do_file(&game).unwrap();
+    state.do_file(&ui).unwrap();
+
+    edit(&game, "function on_reload() game_reloads = (game_reloads or 0) + 10 end");
+    assert_eq!(reloader.poll(&mut state)[0].result, Ok(()));
+    edit(&ui, "ui_version = 2");
+    assert_eq!(reloader.poll(&mut state)[0].result, Ok(()));
+
+    // The callback defined by the new version of the game script is kept,
+    // and reloading the other script did not run it
+    state.do_chunk(b"on_reload() return game_reloads, ui_version", "=check").unwrap();
+    assert_eq!(state.to::<(i32, i32)>(1), Ok((20, 2)));
+}
+
+#[test]
+pub fn test_reload_errors_keep_previous_version() {
+    let path = script("errors", "function version() return 1 end");
+
+    let mut state = State::new();
+    state.open_libs();
+    let reloader = Reloader::new();
+    state.do_file(&path).unwrap();
+
+    edit(&path, "function version() return 2 end\nfunction broken(");
+    let reloads = reloader.poll(&mut state);
+    match reloads[0].result {
+        Err(LuaError::Syntax(ref err)) => assert_eq!((err.line, err.incomplete_input), (2, true)),
+        ref res => panic!("unexpected {:?}", res),
+    }
+    assert!(reloader.poll(&mut state).is_empty());
+    state.do_chunk(b"assert(version() == 1)", "=check").unwrap();
+
+    edit(&path, "function version() return 3 end\nerror('half done')");
+    match reloader.poll(&mut state)[0].result {
+        Err(LuaError::Runtime { ref message, .. }) => assert_eq!(*message, format!("{}:2: half done", path.display())),
+        ref res => panic!("unexpected {:?}", res),
+    }
+    state.do_chunk(b"assert(version() == 3)", "=check").unwrap();
+    assert_eq!(state.gettop(), 0);
+}
@@ -0,0 +1,111 @@
+#![cfg(feature = "repl")]
+
+#[macro_use] extern crate lua_ffi;
+
+use std::io::Cursor;
+
+use lua_ffi::repl::{pretty, Eval, Repl};
+use lua_ffi::{c_int, ffi, LuaError, LuaObject, State};
+
+fn values(eval: Eval) -> Vec<String> {
+    match eval {
+        Eval::Values(values) => values,
+        eval => panic!("expected values, got {:?}", eval),
+    }
+}
+
+fn repl() -> Repl {
+    let mut state = State::new();
+    state.open_libs();
+
+    Repl::new(state)
+}
+
+#[test]
+pub fn test_expressions_and_statements() {
+    let mut repl = repl();
+
+    assert_eq!(values(repl.eval("1 + 1, 'two', nil")), vec!["2", "\"two\"", "nil"]);
+    assert_eq!(values(repl.eval("x = 10")), Vec::<String>::new());
+    assert_eq!(values(repl.eval("x / 4")), vec!["2.5"]);
+    assert_eq!(repl.state().gettop(), 0);
+
+    match repl.eval("x = = 1") {
+        Eval::Error(LuaError::Syntax(err)) => assert_eq!(err.to_string(), "stdin:1: unexpected symbol near '='"),
+        eval => panic!("expected a syntax error, got {:?}", eval),
+    }
+    match repl.eval("error('boom')") {
+        Eval::Error(LuaError::Runtime { message, .. }) => assert_eq!(message, "stdin:1: boom"),
+        eval => panic!("expected a runtime error, got {:?}", eval),
+    }
+}
+
+#[test]
+pub fn test_continuation() {
+    let mut repl = repl();
+
+    assert!(matches!(repl.eval("function double(n)"), Eval::Incomplete));
+    assert!(repl.is_pending());
+    assert!(matches!(repl.eval("  return n * 2"), Eval::Incomplete));
+    assert_eq!(values(repl.eval("end")), Vec::<String>::new());
+    assert!(!repl.is_pending());
+    assert_eq!(values(repl.eval("double(4)")), vec!["8"]);
+
+    assert!(matches!(repl.eval("s = [[one"), Eval::Incomplete));
+    assert_eq!(values(repl.eval("two]] return s")), vec!["\"one\\ntwo\""]);
+}
+
+struct Probe;
+
+impl LuaObject for Probe {
+    fn name() -> *const i8 {
+        c_str!("Probe")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!()
+    }
+}
+
+#[test]
+pub fn test_pretty() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.do_string(
+        "local t = { 'a', { 1, 2 }, count = 3, ['two words'] = true, [1.5] = 'f' }
+        t.self = t
+        return t, {}, setmetatable({}, { __tostring = function() error('bad') end })"
+    );
+    assert_eq!(
+        pretty(&mut state, 1),
+        r#"{ "a", { 1, 2 }, ["two words"] = true, [1.5] = "f", count = 3, self = <cycle> }"#
+    );
+    assert_eq!(pretty(&mut state, 2), "{}");
+    assert!(pretty(&mut state, 3).starts_with("<__tostring failed: "));
+
+    state.push(Probe);
+    assert!(pretty(&mut state, -1).starts_with("Probe: 0x"));
+    assert_eq!(state.gettop(), 4);
+}
+
+unsafe extern "C" fn open_game(l: *mut ffi::lua_State) -> c_int {
+    let mut state = State::from_ptr(l);
+    state.new_table();
+    state.push(60);
+    state.set_field(-2, "fps");
+
+    1
+}
+
+#[test]
+pub fn test_run() {
+    let mut repl = repl();
+    repl.preload("game", open_game);
+
+    let input = Cursor::new("game = require('game')\ngame.fps\nfor i = 1, 2 do\nprint(i) end\n");
+    let mut output = Vec::new();
+    repl.run(input, &mut output).unwrap();
+
+    assert_eq!(String::from_utf8(output).unwrap(), "> > 60\n> >> > \n");
+}
@@ -0,0 +1,79 @@
+#![cfg(feature = "run")]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command, Output};
+
+fn script(name: &str, code: &[u8]) -> PathBuf {
+    let path = env::temp_dir().join(format!("lua-ffi-run-{}-{}.lua", process::id(), name));
+    fs::write(&path, code).unwrap();
+
+    path
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_lua-ffi-run")).args(args).output().unwrap()
+}
+
+fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+#[test]
+pub fn test_arguments() {
+    let path = script("args", b"#!/usr/bin/env lua-ffi-run\nprint(arg[-1], arg[0]:find('args') ~= nil, arg[1], arg[2], select('#', ...))");
+    let path = path.to_str().unwrap();
+    let out = run(&["--no-binary", path, "--", "a", "b c"]);
+
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "--no-binary\ttrue\ta\tb c\t2\n");
+}
+
+#[test]
+pub fn test_exit_codes() {
+    let error = script("error", b"local function fail() error('boom') end\nfail()");
+    let out = run(&[error.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stderr(&out).contains("boom\nstack traceback:"), "{}", stderr(&out));
+    assert!(stderr(&out).contains("in local 'fail'"), "{}", stderr(&out));
+
+    let syntax = script("syntax", b"x = = 1");
+    let out = run(&[syntax.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(3));
+    assert!(stderr(&out).contains(":1: unexpected symbol near '='"), "{}", stderr(&out));
+
+    let hog = script("hog", b"local t = {} for i = 1, 1e7 do t[i] = { i } end");
+    let out = run(&["--memory-limit", "1M", hog.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(4), "{}", stderr(&out));
+
+    let spin = script("spin", b"while true do pcall(function() while true do end end) end");
+    let out = run(&["--instruction-limit", "100000", spin.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(5), "{}", stderr(&out));
+    assert!(stderr(&out).contains("instruction limit exceeded"), "{}", stderr(&out));
+
+    let out = run(&["missing-script.lua"]);
+    assert_eq!(out.status.code(), Some(6));
+
+    let out = run(&["--libs", "base,sockets", "x.lua"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(stderr(&out).contains("unknown library 'sockets'"), "{}", stderr(&out));
+}
+
+#[test]
+pub fn test_libraries_and_binary_chunks() {
+    let libs = script("libs", b"assert(math and string and not io and not os)");
+    let out = run(&["--libs", "base,math,string", libs.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+
+    let dump = script("dump", b"io.write(string.dump(function() print('compiled') end))");
+    let out = run(&[dump.to_str().unwrap()]);
+    let binary = script("binary", &out.stdout);
+
+    let out = run(&[binary.to_str().unwrap()]);
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "compiled\n");
+
+    let out = run(&["--no-binary", binary.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(3));
+    assert!(stderr(&out).contains("attempt to load a binary chunk"), "{}", stderr(&out));
+}
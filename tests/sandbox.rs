@@ -0,0 +1,91 @@
+extern crate lua_ffi;
+
+use lua_ffi::{LuaError, State};
+
+#[test]
+pub fn test_open_libs_set_globals() {
+    let mut state = State::new();
+    state.open_base();
+    state.open_math();
+    state.open_string();
+    state.open_utf8();
+
+    assert_eq!(state.gettop(), 0);
+    state.do_chunk(b"assert(math.floor(2.5) == 2 and string.rep('a', 2) == 'aa' and utf8.char(65) == 'A')", "=libs").unwrap();
+    state.do_chunk(b"assert(table == nil and io == nil and coroutine == nil)", "=libs").unwrap();
+}
+
+#[test]
+pub fn test_memory_limit() {
+    let mut state = State::new();
+    state.open_base();
+    state.open_string();
+    let used = state.memory_used();
+    state.set_memory_limit(Some(used + 256 * 1024));
+
+    let hog = b"local t = {} for i = 1, 1e7 do t[i] = { i } end";
+    assert_eq!(state.do_chunk(hog, "=hog"), Err(LuaError::OutOfMemory));
+    assert_eq!(state.gettop(), 0);
+
+    // The garbage of the failed chunk is collected, and small scripts still
+    // run within the limit.
+    state.do_chunk(b"local t = {} for i = 1, 100 do t[i] = { i } end", "=small").unwrap();
+
+    let msg = b"local ok, err = pcall(function() local t = {} for i = 1, 1e7 do t[i] = i end end) assert(err == 'not enough memory')";
+    state.do_chunk(msg, "=caught").unwrap();
+
+    state.set_memory_limit(None);
+    state.do_chunk(b"local s = ('x'):rep(1 << 20)", "=lifted").unwrap();
+}
+
+#[test]
+pub fn test_instruction_limit() {
+    let mut state = State::new();
+    state.open_base();
+    state.set_instruction_limit(Some(100_000));
+
+    match state.do_chunk(b"local function spin() while true do end end\nspin()", "=spin") {
+        Err(LuaError::InstructionLimit { traceback: Some(traceback) }) => {
+            assert!(traceback.contains("spin:1: in local 'spin'"), "{}", traceback);
+        }
+        res => panic!("unexpected {:?}", res),
+    }
+
+    // A new limit lets code run again, and catching the error with pcall
+    // does not let a script go on past it.
+    state.set_instruction_limit(Some(100_000));
+    state.do_chunk(b"for i = 1, 1000 do end", "=short").unwrap();
+    match state.do_chunk(b"while true do pcall(function() while true do end end) end", "=catch") {
+        Err(err @ LuaError::InstructionLimit { .. }) => assert!(err.to_string().starts_with("instruction limit exceeded\n")),
+        res => panic!("unexpected {:?}", res),
+    }
+
+    state.set_instruction_limit(None);
+    state.do_chunk(b"for i = 1, 1e6 do end", "=unlimited").unwrap();
+}
+
+#[test]
+pub fn test_refuse_binary_chunks() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.do_chunk(b"return string.dump(function() return 42 end)", "=dump").unwrap();
+    let binary = state.to_bytes(-1).unwrap().to_owned();
+    state.pop(1);
+
+    state.load_chunk(&binary, "=binary").unwrap();
+    state.pop(1);
+
+    state.refuse_binary_chunks();
+    match state.load_chunk(&binary, "=binary") {
+        Err(LuaError::Syntax(err)) => assert_eq!(err.message, "attempt to load a binary chunk (mode is 't')"),
+        res => panic!("unexpected {:?}", res),
+    }
+
+    state.do_chunk(b"
+        local binary = string.dump(function() return 42 end)
+        local f, err = load(binary, 'binary', 'b')
+        assert(f == nil and err:find('binary chunk'))
+        assert(load('return ...', 'text', 'bt', {})(7) == 7)
+    ", "=load").unwrap();
+}
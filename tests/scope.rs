@@ -0,0 +1,108 @@
+#[macro_use] extern crate lua_ffi;
+
+use std::panic::{self, AssertUnwindSafe};
+
+use lua_ffi::{LuaField, LuaObject, State, ThreadStatus, c_int, ffi};
+
+struct Player {
+    hp: i32,
+    dropped: bool,
+}
+
+impl LuaObject for Player {
+    fn name() -> *const i8 {
+        c_str!("Player")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!(lua_method!("heal", Player, Player::heal))
+    }
+
+    fn lua_fields() -> Vec<LuaField<Player>> {
+        vec!(LuaField::new("hp").getter(|p: &Player| p.hp).setter(|p: &mut Player, hp| p.hp = hp))
+    }
+}
+
+impl Player {
+    fn heal(&mut self, state: &mut State) -> c_int {
+        self.hp += state.to_int(2).unwrap();
+
+        0
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        assert!(!self.dropped);
+        self.dropped = true;
+    }
+}
+
+fn error_of(state: &mut State, code: &str) -> String {
+    state.do_string(&format!("ok, err = pcall(function() {} end)", code));
+    state.get_global("err");
+    let err = state.to_str(-1).unwrap_or_default().to_owned();
+    state.pop(1);
+    err
+}
+
+#[test]
+fn push_ref() {
+    let mut state = State::new();
+    state.open_libs();
+    let mut player = Player { hp: 10, dropped: false };
+
+    let hp = state.scope(|s| {
+        s.push_ref(&mut player);
+        s.set_global("player");
+
+        let res = s.do_string("player.hp = player.hp - 3 player:heal(1)");
+        assert_eq!(res, ThreadStatus::Ok);
+
+        s.do_string("return player.hp");
+        s.to_int(-1)
+    });
+
+    assert_eq!(hp, Some(8));
+    assert_eq!(player.hp, 8);
+
+    state.do_string("collectgarbage()");
+    assert!(!player.dropped);
+}
+
+#[test]
+fn expired_after_scope() {
+    let mut state = State::new();
+    state.open_libs();
+    let mut player = Player { hp: 10, dropped: false };
+
+    state.scope(|s| {
+        s.push_ref(&mut player);
+        s.set_global("player");
+    });
+    player.hp = 0;
+
+    assert!(error_of(&mut state, "return player.hp").ends_with("Player is no longer accessible, its scope has ended"));
+    assert!(error_of(&mut state, "player.hp = 1").ends_with("Player is no longer accessible, its scope has ended"));
+    assert!(error_of(&mut state, "player:heal(1)")
+        .ends_with("calling 'heal' on bad self (Player is no longer accessible, its scope has ended)"));
+    assert_eq!(player.hp, 0);
+}
+
+#[test]
+fn expired_after_panic() {
+    let mut state = State::new();
+    state.open_libs();
+    let mut player = Player { hp: 10, dropped: false };
+
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        state.scope(|s| {
+            s.push_ref(&mut player);
+            s.set_global("player");
+            panic!("host error");
+        })
+    }));
+    assert!(res.is_err());
+
+    assert!(error_of(&mut state, "return player.hp").ends_with("Player is no longer accessible, its scope has ended"));
+}
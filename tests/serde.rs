@@ -0,0 +1,203 @@
+#![cfg(feature = "serde")]
+
+#[macro_use] extern crate serde;
+extern crate lua_ffi;
+
+use std::collections::HashMap;
+
+use lua_ffi::{LuaError, State, ThreadStatus};
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+enum Behaviour {
+    Idle,
+    Patrol(Vec<(i32, i32)>),
+    Chase { target: String, speed: f64 },
+    Guard(String),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Enemy {
+    name: String,
+    hp: i32,
+    loot: Option<String>,
+    behaviour: Behaviour,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Level {
+    title: String,
+    enemies: Vec<Enemy>,
+    flags: HashMap<String, bool>,
+    spawn: (u8, u8),
+}
+
+fn level() -> Level {
+    let mut flags = HashMap::new();
+    flags.insert("dark".to_owned(), true);
+
+    Level {
+        title: "Cave".to_owned(),
+        enemies: vec![
+            Enemy { name: "bat".to_owned(), hp: 3, loot: None, behaviour: Behaviour::Idle },
+            Enemy {
+                name: "slime".to_owned(),
+                hp: 5,
+                loot: Some("goo".to_owned()),
+                behaviour: Behaviour::Patrol(vec![(0, 0), (4, 2)]),
+            },
+            Enemy {
+                name: "wolf".to_owned(),
+                hp: 12,
+                loot: None,
+                behaviour: Behaviour::Chase { target: "player".to_owned(), speed: 1.5 },
+            },
+            Enemy { name: "knight".to_owned(), hp: 20, loot: None, behaviour: Behaviour::Guard("gate".to_owned()) },
+        ],
+        flags,
+        spawn: (2, 3),
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.to_lua(&level()).unwrap();
+    assert_eq!(state.from_lua::<Level>(-1), Ok(level()));
+    state.set_global("level");
+
+    let res = state.do_string(
+        r#"assert(level.title == "Cave")
+        assert(#level.enemies == 4)
+        assert(level.enemies[1].loot == nil)
+        assert(level.enemies[1].behaviour == "Idle")
+        assert(level.enemies[2].behaviour.Patrol[2][1] == 4)
+        assert(level.enemies[3].behaviour.Chase.speed == 1.5)
+        assert(level.enemies[4].behaviour.Guard == "gate")
+        assert(level.flags.dark == true)
+        assert(level.spawn[1] == 2 and level.spawn[2] == 3)
+        assert(math.type(level.enemies[1].hp) == "integer")"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+fn test_from_script() {
+    let mut state = State::new();
+    state.do_string(
+        r#"return {
+            title = "Forest",
+            enemies = {
+                { name = "boar", hp = 7.0, behaviour = { Guard = "den" } },
+            },
+            flags = {},
+            spawn = { 1, 1 },
+            ignored = "extra fields are skipped",
+        }"#
+    );
+
+    let level: Level = state.from_lua(-1).unwrap();
+    assert_eq!(level.enemies[0].hp, 7);
+    assert_eq!(level.enemies[0].behaviour, Behaviour::Guard("den".to_owned()));
+    assert!(level.flags.is_empty());
+    assert_eq!(state.to_str(-1), None);
+}
+
+fn error_of<T>(state: &mut State, code: &str) -> String where T: serde::de::DeserializeOwned + std::fmt::Debug {
+    state.do_string(code);
+    let top = unsafe { lua_ffi::ffi::lua_gettop(state.as_ptr()) };
+    let err = state.from_lua::<T>(-1).unwrap_err();
+    assert_eq!(unsafe { lua_ffi::ffi::lua_gettop(state.as_ptr()) }, top);
+    state.pop(1);
+
+    err.to_string()
+}
+
+#[test]
+fn test_error_paths() {
+    let mut state = State::new();
+
+    let err = error_of::<Level>(
+        &mut state,
+        r#"local bat = { name = "bat", hp = 1, behaviour = "Idle" }
+        return {
+            title = "Cave", flags = {}, spawn = { 0, 0 },
+            enemies = { bat, bat, { name = "bat", hp = "lots", behaviour = "Idle" } },
+        }"#,
+    );
+    assert_eq!(err, "enemies[3].hp: expected integer, got string");
+
+    let err = error_of::<Level>(
+        &mut state,
+        r#"return { title = "Cave", flags = { dark = 1 }, spawn = { 0, 0 }, enemies = {} }"#,
+    );
+    assert_eq!(err, "flags.dark: expected boolean, got number");
+
+    let err = error_of::<Enemy>(&mut state, r#"return { name = "wolf", hp = 1, behaviour = { Chase = { target = 3 } } }"#);
+    assert_eq!(err, "behaviour.Chase.target: expected string, got number");
+
+    let err = error_of::<Enemy>(&mut state, r#"return { name = "wolf", behaviour = "Idle" }"#);
+    assert_eq!(err, "missing field `hp`");
+
+    let err = error_of::<Vec<i32>>(&mut state, "return { 1, 2.5 }");
+    assert_eq!(err, "[2]: expected integer, got number");
+
+    let err = error_of::<Behaviour>(&mut state, "return { Guard = 'a', Idle = true }");
+    assert!(err.starts_with("expected only the variant name as a key"), "{}", err);
+
+    match state.from_lua::<i32>(1) {
+        Err(LuaError::Conversion { ref path, ref message }) => {
+            assert_eq!(path, "");
+            assert_eq!(message, "expected integer, got no value");
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[derive(Serialize)]
+struct Huge {
+    id: u64,
+}
+
+#[test]
+fn test_unrepresentable() {
+    let mut state = State::new();
+
+    let res = state.to_lua(&vec![Huge { id: 1 }, Huge { id: u64::MAX }]);
+    assert_eq!(
+        res.unwrap_err().to_string(),
+        format!("[2].id: integer {} does not fit in a Lua integer", u64::MAX)
+    );
+    assert_eq!(unsafe { lua_ffi::ffi::lua_gettop(state.as_ptr()) }, 0);
+
+    let mut map = HashMap::new();
+    map.insert(None::<i32>, 1);
+    assert!(state.to_lua(&map).is_err());
+    assert_eq!(unsafe { lua_ffi::ffi::lua_gettop(state.as_ptr()) }, 0);
+}
+
+#[derive(Deserialize, Debug)]
+struct Node {
+    #[allow(dead_code)]
+    next: Option<Box<Node>>,
+}
+
+#[test]
+fn test_cycles() {
+    let mut state = State::new();
+
+    let err = error_of::<Node>(&mut state, "local t = {} t.next = t return t");
+    assert!(err.ends_with(": tables are nested more than 128 levels deep"), "{}", err);
+    assert!(err.starts_with("next.next."), "{}", err);
+
+    let err = error_of::<Tree>(&mut state, "local t = {} t[1] = t return t");
+    assert!(err.ends_with("tables are nested more than 128 levels deep"), "{}", err);
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Tree {
+    #[allow(dead_code)]
+    Branch(Vec<Tree>),
+}
@@ -0,0 +1,179 @@
+#[macro_use] extern crate lua_ffi;
+
+use std::collections::HashMap;
+
+use lua_ffi::{c_int, LuaError, State, ThreadStatus};
+
+fn values(state: &mut State) -> Vec<i32> {
+    let top = state.gettop();
+    (1..=top).map(|i| state.to(i).unwrap()).collect()
+}
+
+#[test]
+pub fn test_stack_helpers() {
+    let mut state = State::new();
+    state.do_string("return 1, 2, 3, 4");
+    assert_eq!(state.gettop(), 4);
+    assert_eq!(state.absindex(-1), 4);
+    assert_eq!(state.absindex(2), 2);
+
+    state.push(0);
+    state.insert(1);
+    assert_eq!(values(&mut state), vec![0, 1, 2, 3, 4]);
+
+    state.remove(-2);
+    assert_eq!(values(&mut state), vec![0, 1, 2, 4]);
+
+    state.push(9);
+    state.replace(1);
+    assert_eq!(values(&mut state), vec![9, 1, 2, 4]);
+
+    state.rotate(2, -1);
+    assert_eq!(values(&mut state), vec![9, 2, 4, 1]);
+
+    state.copy(1, -1);
+    assert_eq!(values(&mut state), vec![9, 2, 4, 9]);
+}
+
+#[test]
+#[should_panic(expected = "invalid stack index 3 (stack has 2 elements)")]
+pub fn test_invalid_index() {
+    let mut state = State::new();
+    state.push(1);
+    state.push(2);
+    state.remove(3);
+}
+
+#[test]
+pub fn test_guard_restores_top() {
+    let mut state = State::new();
+    state.push(1);
+
+    {
+        let mut guard = state.guard();
+        assert_eq!(guard.top(), 1);
+        guard.push(2);
+        guard.pop(1);
+    }
+    assert_eq!(state.gettop(), 1);
+
+    {
+        let mut guard = state.guard();
+        guard.do_string("return 5, 6");
+        guard.keep(2);
+    }
+    assert_eq!(values(&mut state), vec![1, 5, 6]);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "stack guard: 2 value(s) leaked")]
+pub fn test_guard_detects_leak() {
+    let mut state = State::new();
+    let mut guard = state.guard();
+    guard.push(1);
+    guard.push(2);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "stack guard: 1 value(s) popped past the guarded top")]
+pub fn test_guard_detects_over_pop() {
+    let mut state = State::new();
+    state.push(1);
+    let mut guard = state.guard();
+    guard.pop(1);
+}
+
+#[test]
+pub fn test_push_many() {
+    let mut state = State::new();
+    for i in 0..100_000 {
+        state.push(i);
+    }
+
+    assert_eq!(state.gettop(), 100_000);
+    assert_eq!(state.to::<i32>(1), Ok(0));
+    assert_eq!(state.to::<i32>(-1), Ok(99_999));
+}
+
+fn many(state: &mut State) -> c_int {
+    for i in 0..100_000 {
+        state.push(i);
+    }
+
+    100_000
+}
+
+fn flood(state: &mut State) -> Result<c_int, LuaError> {
+    loop {
+        state.try_push("more")?;
+    }
+}
+
+#[test]
+pub fn test_callback_overflow() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(lua_fn!(many));
+    state.set_global("many");
+    state.push(lua_fn!(flood));
+    state.set_global("flood");
+
+    let res = state.do_string(
+        "assert(select('#', many()) == 100000)
+        local ok, err = pcall(flood)
+        assert(not ok and err == 'stack overflow')"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+#[should_panic(expected = "stack overflow")]
+pub fn test_push_on_full_stack() {
+    let mut state = State::new();
+    while state.try_push_nil().is_ok() {}
+
+    state.push(1);
+}
+
+#[test]
+pub fn test_try_push() {
+    let mut state = State::new();
+
+    let mut pushed = 0;
+    let err = loop {
+        match state.try_push(pushed) {
+            Ok(()) => pushed += 1,
+            Err(err) => break err,
+        }
+    };
+
+    assert_eq!(err, LuaError::StackOverflow);
+    assert_eq!(state.gettop(), pushed);
+    assert!(!state.checkstack(usize::MAX));
+}
+
+#[test]
+pub fn test_try_helpers_on_full_stack() {
+    let mut state = State::new();
+    state.push(7);
+    state.set_global("seven");
+    state.push(vec![vec![1], vec![2]]);
+    while state.try_push_nil().is_ok() {}
+
+    let top = state.gettop();
+    assert_eq!(state.try_push_bytes(b"bytes"), Err(LuaError::StackOverflow));
+    assert_eq!(state.push_integer(1), Err(LuaError::StackOverflow));
+    assert_eq!(state.try_get_global("seven"), Err(LuaError::StackOverflow));
+    assert_eq!(state.try_get_field(1, "x"), Err(LuaError::StackOverflow));
+    assert_eq!(state.try_new_table(), Err(LuaError::StackOverflow));
+    assert_eq!(state.load_chunk(b"return 1", "=chunk"), Err(LuaError::StackOverflow));
+    assert_eq!(state.to::<Vec<Vec<i32>>>(1), Err(LuaError::StackOverflow));
+    assert_eq!(state.to::<HashMap<i32, Vec<i32>>>(1), Err(LuaError::StackOverflow));
+    assert_eq!(state.gettop(), top);
+
+    state.pop(1);
+    state.try_get_global("seven").unwrap();
+    assert_eq!(state.to::<i32>(-1), Ok(7));
+}
@@ -1,6 +1,6 @@
-extern crate luajit;
+extern crate lua_ffi;
 
-use luajit::{State, ThreadStatus};
+use lua_ffi::{State, ThreadStatus};
 
 #[test]
 fn do_valid_string() {
@@ -0,0 +1,80 @@
+#[macro_use] extern crate lua_ffi;
+
+use lua_ffi::{c_int, LuaString, State, ThreadStatus};
+
+#[test]
+pub fn test_embedded_nul() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.push("a\0b");
+    state.set_global("s");
+    let res = state.do_string(r#"assert(#s == 3 and s:byte(2) == 0)"#);
+    assert_eq!(res, ThreadStatus::Ok);
+
+    state.do_string(r#"return "x\0y""#);
+    assert_eq!(state.to_str(-1), Some("x\0y"));
+    assert_eq!(state.to::<String>(-1), Ok("x\0y".to_owned()));
+}
+
+#[test]
+pub fn test_bytes() {
+    let mut state = State::new();
+    state.open_libs();
+
+    let blob: Vec<u8> = (0..=255).collect();
+    state.push_bytes(&blob);
+    state.set_global("blob");
+    let res = state.do_string("assert(#blob == 256 and blob:byte(1) == 0 and blob:byte(256) == 255)");
+    assert_eq!(res, ThreadStatus::Ok);
+
+    state.get_global("blob");
+    assert_eq!(state.to_bytes(-1), Some(&blob[..]));
+    assert_eq!(state.to_str(-1), None);
+    assert!(state.to::<String>(-1).is_err());
+    assert_eq!(state.to::<LuaString>(-1).map(LuaString::into_bytes), Ok(blob));
+
+    state.push(12);
+    assert_eq!(state.to_bytes(-1), Some(&b"12"[..]));
+    state.push(true);
+    assert_eq!(state.to_bytes(-1), None);
+    assert!(state.to::<LuaString>(-1).is_err());
+}
+
+fn checksum(state: &mut State) -> c_int {
+    let sum = state.to_bytes(1).map(|b| b.iter().map(|&x| x as i32).sum::<i32>()).unwrap_or(-1);
+    state.push(sum);
+
+    1
+}
+
+fn reversed(state: &mut State) -> c_int {
+    let mut bytes = state.to::<LuaString>(1).unwrap().into_bytes();
+    bytes.reverse();
+    state.push(LuaString::from(bytes));
+
+    1
+}
+
+#[test]
+pub fn test_lua_string() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(lua_fn!(checksum));
+    state.set_global("checksum");
+    state.push(lua_fn!(reversed));
+    state.set_global("reversed");
+
+    let res = state.do_string(
+        r#"assert(checksum("\0\1\255") == 256)
+        assert(reversed("\255\0a") == "a\0\255")"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+
+    let borrowed = LuaString::from("tiles");
+    assert_eq!(borrowed.to_str(), Some("tiles"));
+    assert_eq!(&*borrowed, b"tiles");
+    let invalid = LuaString::from(vec![b'o', 0xff]);
+    assert_eq!(invalid.to_string_lossy(), "o\u{fffd}");
+    assert_eq!(invalid.clone().into_owned(), invalid);
+}
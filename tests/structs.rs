@@ -1,7 +1,10 @@
-#[macro_use] extern crate luajit;
+#[macro_use] extern crate lua_ffi;
 
-use luajit::types::LuaObject;
-use luajit::{State, ffi, c_int, ThreadStatus};
+use std::any::TypeId;
+use std::ptr;
+
+use lua_ffi::types::{LuaConstant, LuaField, LuaObject};
+use lua_ffi::{State, ffi, c_int, LuaError, LuaUserData, ThreadStatus};
 
 struct Point2D {
     pub x: i32,
@@ -25,6 +28,7 @@ impl LuaObject for Point2D {
 }
 
 impl Point2D {
+    #[allow(clippy::new_ret_no_self)]
     fn new(state: &mut State) -> c_int {
         state.push(Point2D {
             x: 0,
@@ -46,18 +50,16 @@ impl Point2D {
         1
     }
 
-    fn set_x(&mut self, state: &mut State) -> c_int {
-        let new_x = state.to_int(2).unwrap();
-        self.x = new_x;
+    fn set_x(&mut self, state: &mut State) -> Result<c_int, LuaError> {
+        self.x = state.check_arg(2)?;
 
-        0
+        Ok(0)
     }
 
-    fn set_y(&mut self, state: &mut State) -> c_int {
-        let new_y = state.to_int(2).unwrap();
-        self.y = new_y;
+    fn set_y(&mut self, state: &mut State) -> Result<c_int, LuaError> {
+        self.y = state.check_arg(2)?;
 
-        0
+        Ok(0)
     }
 }
 
@@ -89,6 +91,13 @@ pub fn test_new_struct() {
         if foo:add() ~= 6 then error() end"
     );
     assert_eq!(res, ThreadStatus::Ok);
+
+    let res = state.do_string(
+        r#"local ok, err = pcall(function() test:setX("four") end)
+        assert(err == "bad argument #2 to 'setX' (number expected, got string)", err)
+        assert(not pcall(test.setY, test))"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
 }
 
 struct B {
@@ -137,4 +146,346 @@ pub fn test_double_sentinel() {
 
     let res = state.do_string("if test:add(4) ~= 9 then error() end");
     assert_eq!(res, ThreadStatus::Ok);
-}
\ No newline at end of file
+}
+struct Entity {
+    pub hp: i32,
+    pub name: String,
+    pub secret: i32,
+}
+
+impl LuaObject for Entity {
+    fn name() -> *const i8 {
+        c_str!("Entity")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!(
+            lua_method!("damage", Entity, Entity::damage),
+            lua_method!("visit", Entity, Entity::visit),
+        )
+    }
+
+    fn lua_fields() -> Vec<LuaField<Entity>> {
+        vec!(
+            LuaField::new("hp")
+                .getter(|e: &Entity| e.hp)
+                .setter(|e: &mut Entity, hp| e.hp = hp),
+            LuaField::new("name").getter(|e: &Entity| e.name.clone()),
+            LuaField::new("secret").setter(|e: &mut Entity, v| e.secret = v),
+            LuaField::new("serial").getter(|_: &Entity| u64::MAX),
+        )
+    }
+}
+
+impl Entity {
+    fn damage(&mut self, state: &mut State) -> c_int {
+        self.hp -= state.to_int(2).unwrap();
+
+        0
+    }
+
+    // Calls the function at argument 2 while `self` is borrowed, returning
+    // its error message if it failed.
+    fn visit(&mut self, state: &mut State) -> c_int {
+        state.push_value(2);
+        match state.pcall(0, 0, 0) {
+            Ok(()) => 0,
+            Err((_, msg)) => {
+                state.push(msg);
+                1
+            }
+        }
+    }
+}
+
+fn error_of(state: &mut State, code: &str) -> String {
+    state.do_string(&format!("ok, err = pcall(function() {} end)", code));
+    state.get_global("err");
+    let err = state.to_str(-1).unwrap_or_default().to_owned();
+    state.pop(1);
+    err
+}
+
+#[test]
+pub fn test_fields() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.push(Entity {
+        hp: 10,
+        name: "slime".to_owned(),
+        secret: 0,
+    });
+    state.set_global("e");
+
+    let res = state.do_string(
+        r#"e.hp = 20
+        e:damage(5)
+        if e.hp ~= 15 then error() end
+        if e.name ~= "slime" then error() end
+        e.secret = 42"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+
+    assert!(error_of(&mut state, "return e.z").ends_with("no field 'z' on Entity"));
+    assert!(error_of(&mut state, "e.z = 1").ends_with("no field 'z' on Entity"));
+    assert!(error_of(&mut state, "e.name = 'bat'").ends_with("field 'name' on Entity is read-only"));
+    assert!(error_of(&mut state, "return e.secret").ends_with("field 'secret' on Entity is write-only"));
+    assert!(error_of(&mut state, "e.hp = 'lots'")
+        .ends_with("bad value for field 'hp' on Entity (number expected, got string)"));
+    let err = error_of(&mut state, "return e.serial");
+    assert!(err.ends_with(&format!("u64 {} does not fit in a Lua integer", u64::MAX)), "{}", err);
+
+    // The failed read left `e` unborrowed
+    assert_eq!(state.do_string("e.hp = 1"), ThreadStatus::Ok);
+}
+
+#[allow(dead_code)]
+struct Impostor {
+    pub val: i64,
+}
+
+impl LuaObject for Impostor {
+    fn name() -> *const i8 {
+        c_str!("Impostor")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!()
+    }
+}
+
+#[test]
+pub fn test_forged_metatable() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.push(B { val: 1 });
+    state.set_global("b");
+    state.push(Impostor { val: 1 << 40 });
+    state.set_global("impostor");
+
+    let err = error_of(&mut state, "debug.setmetatable(impostor, getmetatable(b)) impostor:add(1)");
+    assert!(err.ends_with("B expected, got foreign userdata)"), "{}", err);
+
+    let res = state.do_string("if b:add(1) ~= 2 then error() end");
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+pub fn test_foreign_userdata() {
+    let mut state = State::new();
+    state.register_struct::<B>();
+
+    // A userdata made elsewhere is not read, even if it happens to start
+    // with the same bytes.
+    unsafe {
+        let udata = state.new_raw_userdata(256);
+        ptr::write_bytes(udata as *mut u8, 0, 256);
+        ptr::write(udata as *mut TypeId, TypeId::of::<B>());
+    }
+    assert_eq!(
+        state.to::<LuaUserData<B>>(-1).err(),
+        Some(LuaError::TypeMismatch { expected: "B".to_owned(), got: "userdata".to_owned() })
+    );
+}
+
+#[test]
+pub fn test_check_userdata_returns_error() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(B { val: 1 });
+    state.set_global("b");
+
+    state.push(1);
+    assert_eq!(
+        state.check_userdata::<B>(1).err().map(|err| err.to_string()),
+        Some("bad argument #1 to '?' (B expected, got number)".to_owned())
+    );
+    state.pop(1);
+
+    let err = error_of(&mut state, "b.add(5)");
+    assert!(err.ends_with("bad argument #1 to 'add' (B expected, got number)"), "{}", err);
+}
+
+#[test]
+pub fn test_handle_keeps_userdata_alive() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.push(B { val: 3 });
+    let b: LuaUserData<B> = state.to(-1).unwrap();
+    state.pop(1);
+    assert_eq!(state.do_string("collectgarbage() collectgarbage()"), ThreadStatus::Ok);
+    assert_eq!(b.borrow().unwrap().val, 3);
+
+    // Calling `__gc` directly leaves a borrowed value alone
+    state.push(B { val: 4 });
+    let c: LuaUserData<B> = state.to(-1).unwrap();
+    state.set_global("c");
+    {
+        let guard = c.borrow().unwrap();
+        assert_eq!(state.do_string("getmetatable(c).__gc(c)"), ThreadStatus::Ok);
+        assert_eq!(guard.val, 4);
+    }
+
+    drop(state);
+    assert_eq!(b.borrow().err(), Some(LuaError::Closed { type_name: "B".to_owned() }));
+    assert!(b.as_ptr().is_null());
+}
+
+struct NotB;
+
+impl LuaObject for NotB {
+    fn name() -> *const i8 {
+        c_str!("B")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!()
+    }
+}
+
+#[test]
+#[should_panic(expected = "metatable B is already registered for another type")]
+pub fn test_duplicate_name() {
+    let mut state = State::new();
+    state.register_struct::<B>();
+    state.register_struct::<NotB>();
+}
+
+#[test]
+pub fn test_borrow_conflict() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.push(Entity {
+        hp: 10,
+        name: "slime".to_owned(),
+        secret: 0,
+    });
+    state.set_global("e");
+
+    let res = state.do_string(
+        r#"local err = e:visit(function() return e.hp end)
+        if not err:find("Entity is already mutably borrowed") then error(err) end
+        err = e:visit(function() e:damage(1) end)
+        if not err:find("bad argument #1 to 'damage' %(Entity is already mutably borrowed%)") then error(err) end
+        if e:visit(function() end) ~= nil then error() end
+        e:damage(1)
+        if e.hp ~= 9 then error() end"#
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+struct Scripted;
+
+impl LuaObject for Scripted {
+    fn name() -> *const i8 {
+        c_str!("Scripted")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!()
+    }
+
+    fn user_values() -> c_int {
+        2
+    }
+}
+
+#[test]
+pub fn test_user_values() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.push(Scripted);
+    state.do_string("data = setmetatable({}, { __gc = function() collected = true end })");
+    state.get_global("data");
+    assert!(state.set_user_value(-2, 1));
+    state.do_string("return function() return 42 end");
+    assert!(state.set_user_value(-2, 2));
+    state.push(0);
+    assert!(!state.set_user_value(-2, 3));
+    state.set_global("obj");
+
+    let res = state.do_string("data = nil collectgarbage() assert(not collected)");
+    assert_eq!(res, ThreadStatus::Ok);
+
+    state.get_global("obj");
+    assert!(state.get_user_value(-1, 2));
+    state.call(0, 1);
+    assert_eq!(state.to_int(-1), Some(42));
+    state.pop(1);
+    assert!(!state.get_user_value(-1, 3));
+    assert!(!state.get_user_value(-2, 0));
+    state.pop(3);
+
+    let res = state.do_string("obj = nil collectgarbage() collectgarbage() assert(collected)");
+    assert_eq!(res, ThreadStatus::Ok);
+
+    state.push(Point2D { x: 0, y: 0 });
+    assert!(!state.get_user_value(-1, 1));
+}
+
+#[allow(dead_code)]
+struct Color(u8, u8, u8);
+
+impl LuaObject for Color {
+    fn name() -> *const i8 {
+        c_str!("Color")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!(lua_method!("red", Color, Color::red))
+    }
+
+    fn lua_statics() -> Vec<ffi::luaL_Reg> {
+        vec!(lua_func!("new", Color::new))
+    }
+
+    fn lua_constants() -> Vec<LuaConstant> {
+        vec!(
+            LuaConstant::new("BLACK", Color(0, 0, 0)),
+            LuaConstant::new("CHANNELS", 3),
+        )
+    }
+}
+
+impl Color {
+    #[allow(clippy::new_ret_no_self)]
+    fn new(state: &mut State) -> c_int {
+        let r = state.to_int(1).unwrap_or(0) as u8;
+        state.push(Color(r, 0, 0));
+
+        1
+    }
+
+    fn red(&mut self, state: &mut State) -> c_int {
+        state.push(self.0 as i32);
+
+        1
+    }
+}
+
+#[test]
+pub fn test_class_table() {
+    let mut state = State::new();
+    state.open_libs();
+    state.register_struct::<Color>();
+    state.register_struct::<B>();
+    state.pop(2);
+
+    let res = state.do_string(
+        "assert(Color.new(5):red() == 5)
+        assert(Color(7):red() == 7)
+        assert(Color.BLACK:red() == 0)
+        assert(Color.CHANNELS == 3)
+        assert(Color.red(Color(1)) == 1)"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+
+    assert!(error_of(&mut state, "return Color(1).new").ends_with("no field 'new' on Color"));
+    assert!(error_of(&mut state, "return Color(1).CHANNELS").ends_with("no field 'CHANNELS' on Color"));
+    assert!(error_of(&mut state, "return B()").ends_with("B has no 'new' function to call"));
+}
@@ -0,0 +1,112 @@
+#[macro_use] extern crate lua_ffi;
+
+use std::path::{Path, PathBuf};
+
+use lua_ffi::ffi::luaL_Reg;
+use lua_ffi::testing::Harness;
+use lua_ffi::{LuaError, LuaObject, State};
+
+struct Point2D {
+    x: i32,
+    y: i32,
+}
+
+impl LuaObject for Point2D {
+    fn name() -> *const i8 {
+        c_str!("Point2D")
+    }
+
+    fn lua_fns() -> Vec<luaL_Reg> {
+        vec!(
+            lua_func!("new", Point2D::new),
+            lua_method!("add", Point2D, Point2D::add),
+            lua_method!("setX", Point2D, Point2D::set_x),
+            lua_method!("setY", Point2D, Point2D::set_y),
+        )
+    }
+}
+
+impl Point2D {
+    #[allow(clippy::new_ret_no_self)]
+    fn new(state: &mut State) -> i32 {
+        state.push(Point2D { x: 0, y: 0 });
+
+        1
+    }
+
+    fn add(&mut self, state: &mut State) -> i32 {
+        state.push(self.x + self.y);
+
+        1
+    }
+
+    fn set_x(&mut self, state: &mut State) -> Result<i32, LuaError> {
+        self.x = state.check_arg(2)?;
+
+        Ok(0)
+    }
+
+    fn set_y(&mut self, state: &mut State) -> Result<i32, LuaError> {
+        self.y = state.check_arg(2)?;
+
+        Ok(0)
+    }
+}
+
+#[test]
+fn test_discover_and_run() {
+    let harness = Harness::discover("tests/lua").unwrap().setup(|state| {
+        state.open_libs();
+        state.register_struct::<Point2D>();
+    });
+    assert_eq!(harness.files(), &[PathBuf::from("tests/lua/point_test.lua")]);
+
+    let report = harness.run();
+    report.assert_success();
+
+    let names: Vec<_> = report.cases.iter().map(|case| (case.name.as_str(), case.line)).collect();
+    assert_eq!(names, vec![
+        ("Point2D starts at the origin", 3),
+        ("Point2D adds its coordinates", 8),
+        ("Point2D in tables compares by contents", 16),
+        ("runs every case in a fresh state", 24),
+        ("does not see globals of other cases", 28),
+    ]);
+}
+
+#[test]
+fn test_failures() {
+    let report = Harness::new().file("tests/lua/testing/failing_cases.lua").run();
+    assert_eq!((report.passed(), report.failed()), (1, 3));
+
+    let messages: Vec<_> = report.cases.iter().map(|case| match case.error {
+        Some(LuaError::Runtime { ref message, .. }) => message.as_str(),
+        None => "",
+        ref err => panic!("unexpected {:?}", err),
+    }).collect();
+    assert_eq!(messages, vec![
+        "tests/lua/testing/failing_cases.lua:3: expected 3, got 2",
+        "tests/lua/testing/failing_cases.lua:9: nested: expected { 1, { x = 3 }, self = { 1, { x = 2 }, self = <cycle> } }, got { 1, { x = 2 }, self = <cycle> }",
+        "tests/lua/testing/failing_cases.lua:13: unexpected",
+        "",
+    ]);
+
+    let output = report.to_string();
+    assert!(output.contains("FAIL tests/lua/testing/failing_cases.lua:2: failing cases compares numbers\n"), "{}", output);
+    assert!(output.contains("ok   tests/lua/testing/failing_cases.lua:17: passes\n"), "{}", output);
+    assert!(output.ends_with("1 passed, 3 failed\n"), "{}", output);
+}
+
+#[test]
+fn test_file_errors() {
+    let broken = Path::new("tests/lua/testing/broken_cases.lua");
+    let report = Harness::new().file(broken).file("tests/lua/testing/missing_test.lua").run();
+
+    assert_eq!(report.cases.len(), 2);
+    assert_eq!(report.cases[0].name, "");
+    let error = report.cases[0].error.as_ref().unwrap().to_string();
+    assert!(error.starts_with("tests/lua/testing/broken_cases.lua:4: attempt to perform arithmetic on a nil value"), "{}", error);
+
+    let error = report.cases[1].error.as_ref().unwrap().to_string();
+    assert!(error.starts_with("cannot read tests/lua/testing/missing_test.lua"), "{}", error);
+}
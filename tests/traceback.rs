@@ -0,0 +1,142 @@
+#[macro_use] extern crate lua_ffi;
+
+use std::path::Path;
+
+use lua_ffi::{c_int, LuaError, State, ThreadStatus, Value};
+
+fn runtime_error(res: Result<(), LuaError>) -> (String, String) {
+    match res {
+        Err(LuaError::Runtime { message, traceback: Some(traceback) }) => (message, traceback),
+        res => panic!("expected a runtime error with a traceback, got {:?}", res),
+    }
+}
+
+#[test]
+pub fn test_traceback() {
+    let mut state = State::new();
+    state.open_libs();
+    state.do_string(
+        "function inner() error('deep failure') end
+        function outer(n) inner() return n end"
+    );
+
+    state.push(1);
+    state.get_global("outer");
+    state.push(2);
+    let (message, traceback) = runtime_error(state.pcall_with_traceback(1, 1));
+    assert!(message.ends_with("deep failure"), "{}", message);
+    assert!(traceback.starts_with("stack traceback:"), "{}", traceback);
+    assert!(traceback.contains("in function 'inner'"), "{}", traceback);
+    assert!(traceback.contains("in function 'outer'"), "{}", traceback);
+    assert_eq!(state.gettop(), 1);
+
+    state.do_string("function inner() end");
+    state.get_global("outer");
+    state.push(2);
+    assert_eq!(state.pcall_with_traceback(1, 1), Ok(()));
+    assert_eq!(state.to::<(i32, i32)>(1), Ok((1, 2)));
+}
+
+fn reject(state: &mut State) -> Result<c_int, LuaError> {
+    let name = state.check_arg::<String>(1)?;
+
+    Err(LuaError::Conversion { path: String::new(), message: format!("{} is not allowed", name) })
+}
+
+#[test]
+pub fn test_rust_errors() {
+    let mut state = State::new();
+    state.open_libs();
+    state.register("reject", lua_fn!(reject).unwrap());
+    state.do_string("function spawn(name) reject(name) end");
+
+    state.get_global("spawn");
+    state.push("dragon");
+    let (message, traceback) = runtime_error(state.pcall_with_traceback(1, 0));
+    assert_eq!(message, "dragon is not allowed");
+    assert!(traceback.contains("in function 'reject'"), "{}", traceback);
+    assert!(traceback.contains("in function 'spawn'"), "{}", traceback);
+    assert_eq!(state.gettop(), 0);
+}
+
+#[test]
+pub fn test_default_message_handler() {
+    let mut state = State::new();
+    state.open_libs();
+    state.set_default_message_handler(|state, err| {
+        let code = match err {
+            Value::Table(ref t) => {
+                state.push(t);
+                state.get_field(-1, "code");
+                let code = state.to::<i64>(-1).ok();
+                state.pop(2);
+                code
+            }
+            _ => None,
+        };
+
+        match code {
+            Some(code) => Value::from(format!("error code {}", code)),
+            None => err,
+        }
+    });
+
+    let res = state.do_string("error({ code = 42 })");
+    assert_eq!(res, ThreadStatus::RuntimeError);
+    assert_eq!(state.to_str(-1), Some("error code 42"));
+    state.settop(0);
+
+    state.do_string("function fail() error({ code = 7 }) end");
+    state.get_global("fail");
+    let (message, _) = runtime_error(state.pcall_with_traceback(0, 0));
+    assert_eq!(message, "error code 7");
+
+    let (message, _) = runtime_error(state.do_file(Path::new("./tests/lua/test3.lua")));
+    assert_eq!(message, "error code 3");
+    assert_eq!(state.gettop(), 0);
+
+    assert_eq!(state.do_string("return 1, 2"), ThreadStatus::Ok);
+    assert_eq!(state.to::<(i32, i32)>(-2), Ok((1, 2)));
+}
+
+#[test]
+pub fn test_non_string_errors() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.do_string("function fail() error(setmetatable({}, {})) end");
+    state.get_global("fail");
+    let (message, _) = runtime_error(state.pcall_with_traceback(0, 0));
+    assert_eq!(message, "(error object is a table value)");
+}
+
+#[test]
+pub fn test_handler_protected_calls() {
+    let mut state = State::new();
+    state.open_libs();
+    state.set_default_message_handler(|state, err| {
+        if let Err(LuaError::Runtime { traceback, .. }) = state.do_chunk(b"local function log() error('log') end log()", "=log") {
+            assert!(traceback.unwrap().contains("in local 'log'"));
+        }
+        err
+    });
+
+    state.do_string("function fail() error('outer') end");
+    state.get_global("fail");
+    let (message, traceback) = runtime_error(state.pcall_with_traceback(0, 0));
+    assert!(message.ends_with("outer"), "{}", message);
+    assert!(traceback.contains("in function 'fail'"), "{}", traceback);
+    assert!(!traceback.contains("log"), "{}", traceback);
+}
+
+#[test]
+pub fn test_panicking_handler() {
+    let mut state = State::new();
+    state.open_libs();
+    state.set_default_message_handler(|_, _| panic!("handler bug"));
+
+    let (message, traceback) = runtime_error(state.do_chunk(b"error('boom')", "=boom"));
+    assert_eq!(message, "message handler panicked: handler bug");
+    assert!(traceback.starts_with("stack traceback:"), "{}", traceback);
+    assert_eq!(state.gettop(), 0);
+}
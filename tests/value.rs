@@ -0,0 +1,155 @@
+#[macro_use] extern crate lua_ffi;
+
+use std::cell::RefCell;
+
+use lua_ffi::{c_int, LightUserData, LuaObject, LuaType, State, ThreadStatus, Value};
+use lua_ffi::ffi;
+
+struct Token;
+
+impl LuaObject for Token {
+    fn name() -> *const i8 {
+        c_str!("Token")
+    }
+
+    fn lua_fns() -> Vec<ffi::luaL_Reg> {
+        vec!()
+    }
+}
+
+#[test]
+pub fn test_type_of() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.do_string("return nil, true, 1, 'a', {}, print, coroutine.create(print)");
+    state.push(LightUserData(std::ptr::null_mut()));
+    state.push(Token);
+
+    let types: Vec<LuaType> = (1..=10).map(|i| state.type_of(i)).collect();
+    assert_eq!(types, vec![
+        LuaType::Nil, LuaType::Boolean, LuaType::Number, LuaType::String, LuaType::Table,
+        LuaType::Function, LuaType::Thread, LuaType::LightUserData, LuaType::UserData, LuaType::None,
+    ]);
+    assert_eq!(LuaType::LightUserData.name(), "userdata");
+    assert_eq!(LuaType::None.to_string(), "no value");
+
+    let values: Vec<Value> = (1..=10).map(|i| state.to(i).unwrap()).collect();
+    let value_types: Vec<LuaType> = values.iter().map(Value::type_of).collect();
+    assert_eq!(&value_types[..9], &types[..9]);
+    assert_eq!(values[9], Value::Nil);
+}
+
+#[test]
+pub fn test_round_trip() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.do_string("t = {} f = function() end co = coroutine.create(f) return t, f, co, 2^53, 7, 'a\\0b'");
+    let values: Vec<Value> = (1..=6).map(|i| state.to(i).unwrap()).collect();
+    state.settop(0);
+
+    assert_eq!(values[3], Value::Number(2f64.powi(53)));
+    assert_eq!(values[4], Value::Integer(7));
+    assert_eq!(values[5], Value::from("a\0b"));
+    assert_eq!(values[0], values[0].clone());
+    assert!(values[0] != values[1]);
+
+    for (i, value) in values.into_iter().enumerate() {
+        state.push(value);
+        state.set_global(&format!("v{}", i + 1));
+    }
+    let res = state.do_string(
+        "assert(v1 == t and v2 == f and v3 == co)
+        assert(math.type(v4) == 'float' and math.type(v5) == 'integer')
+        assert(v6 == 'a\\0b')"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+pub fn test_refs_are_released() {
+    let mut state = State::new();
+    state.open_libs();
+
+    state.do_string("return setmetatable({}, { __gc = function() collected = true end })");
+    let table: Value = state.to(-1).unwrap();
+    state.settop(0);
+
+    let res = state.do_string("collectgarbage() collectgarbage() assert(not collected)");
+    assert_eq!(res, ThreadStatus::Ok);
+
+    drop(table);
+    let res = state.do_string("collectgarbage() collectgarbage() assert(collected)");
+    assert_eq!(res, ThreadStatus::Ok);
+}
+
+#[test]
+pub fn test_refs_outlive_state() {
+    let (table, copy) = {
+        let mut state = State::new();
+        state.do_string("return {}");
+        let table: Value = state.to(-1).unwrap();
+        let copy = table.clone();
+        (table, copy)
+    };
+
+    assert_eq!(table.type_of(), LuaType::Table);
+    match table {
+        Value::Table(ref r) => assert_eq!(r.type_of(), LuaType::None),
+        ref val => panic!("unexpected {:?}", val),
+    }
+    assert_eq!(table.clone(), table);
+    assert_ne!(table, copy);
+    drop(table);
+    drop(copy);
+}
+
+thread_local! {
+    static QUEUE: RefCell<Vec<(String, Vec<Value>)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Queues an event with whatever arguments Lua passed, to be delivered later.
+fn emit(state: &mut State) -> c_int {
+    let name = state.to::<String>(1).unwrap();
+    let top = unsafe { ffi::lua_gettop(state.as_ptr()) };
+    let args = (2..=top).map(|i| state.to::<Value>(i).unwrap()).collect();
+    QUEUE.with(|q| q.borrow_mut().push((name, args)));
+
+    0
+}
+
+#[test]
+pub fn test_event_bus() {
+    let mut state = State::new();
+    state.open_libs();
+    state.push(lua_fn!(emit));
+    state.set_global("emit");
+
+    let res = state.do_string(
+        "player = { hp = 3 }
+        emit('hit', player, 2, nil, 'sword')
+        received = {}
+        function on_hit(target, damage, _, weapon)
+            target.hp = target.hp - damage
+            received.weapon = weapon
+        end"
+    );
+    assert_eq!(res, ThreadStatus::Ok);
+
+    let events = QUEUE.with(|q| q.borrow_mut().split_off(0));
+    assert_eq!(events.len(), 1);
+    let (name, args) = events.into_iter().next().unwrap();
+    assert_eq!(args.len(), 4);
+    assert!(args[2].is_nil());
+
+    state.get_global(&format!("on_{}", name));
+    let nargs = args.len() as c_int;
+    for arg in args {
+        state.push(arg);
+    }
+    state.call(nargs, 0);
+
+    let res = state.do_string("assert(player.hp == 1 and received.weapon == 'sword')");
+    assert_eq!(res, ThreadStatus::Ok);
+}